@@ -0,0 +1,84 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sbis_build_status::activity::{ActivityTracker, ProcessInfo, ScriptedSource};
+
+const SIZES: [u32; 3] = [100, 1_000, 10_000];
+
+fn process(pid: u32, parent: u32, name: &str, cmd: &[&str]) -> ProcessInfo {
+	ProcessInfo {
+		pid: sysinfo::Pid::from_u32(pid),
+		start_time: u64::from(pid),
+		parent: Some(sysinfo::Pid::from_u32(parent)),
+		name: name.to_owned(),
+		cmd: cmd.iter().map(|s| s.to_string()).collect(),
+		cwd: None,
+		cpu_usage: 1.0,
+		memory: 1 << 20,
+		disk_io: 0,
+		cpu_time: None,
+	}
+}
+
+/// A build with many compilers, the way it looks on a build server
+fn build_server(compilers: u32) -> Vec<ProcessInfo> {
+	let mut processes = vec![
+		process(1, 0, "init", &["init"]),
+		process(
+			10,
+			1,
+			"qtcreator_ctrlc_stub",
+			&["stub", "--build", "/home/user/build"],
+		),
+		process(11, 10, "make", &["make", "-j64"]),
+	];
+	for i in 0..compilers {
+		processes.push(process(
+			100 + i,
+			11,
+			"cc1plus",
+			&["cc1plus", "-O2", "/home/user/repo/src/file.cpp"],
+		));
+	}
+	processes
+}
+
+/// Scans of the real system: a check used to create a new System for each of its three scans,
+/// now it refreshes one System once
+fn bench_system(c: &mut Criterion) {
+	let mut group = c.benchmark_group("check");
+	group.sample_size(20);
+	group.bench_function("fresh_system_x3", |b| {
+		b.iter(|| {
+			for _ in 0..3 {
+				ActivityTracker::new().refresh();
+			}
+		})
+	});
+	let mut tracker = ActivityTracker::new();
+	group.bench_function("persistent_system", |b| b.iter(|| tracker.refresh()));
+	group.finish();
+}
+
+/// The CPU times of the fake processes are not read from the system
+fn scripted_tracker() -> ActivityTracker {
+	ActivityTracker::with_source(Box::new(ScriptedSource::default()))
+}
+
+fn bench_update(c: &mut Criterion) {
+	let mut group = c.benchmark_group("update");
+	for size in SIZES {
+		let processes = build_server(size);
+		let mut tracker = scripted_tracker();
+		group.bench_with_input(
+			BenchmarkId::new("known", size),
+			&processes,
+			|b, processes| b.iter(|| tracker.update(processes)),
+		);
+		group.bench_with_input(BenchmarkId::new("new", size), &processes, |b, processes| {
+			b.iter(|| scripted_tracker().update(processes))
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(benches, bench_system, bench_update);
+criterion_main!(benches);
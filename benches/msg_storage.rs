@@ -0,0 +1,88 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use sbis_build_status::msg_storage::{MessageCategory, MessageStorage};
+use sbis_build_status::storage::FileStorage;
+
+const SIZES: [i32; 3] = [100, 1_000, 10_000];
+
+fn filled_storage(dir: &std::path::Path, size: i32) -> MessageStorage<(i64, i32)> {
+	let mut storage = MessageStorage::new(std::sync::Arc::new(FileStorage::new(dir.to_path_buf())));
+	for i in 0..size {
+		storage.add_message((i64::from(i % 10), i), MessageCategory::Reply);
+	}
+	storage
+}
+
+fn bench_add(c: &mut Criterion) {
+	let mut group = c.benchmark_group("add_message");
+	for size in SIZES {
+		let dir = tempfile::tempdir().unwrap();
+		group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+			b.iter_batched_ref(
+				|| filled_storage(dir.path(), size),
+				|storage| {
+					storage.add_message((0, size), MessageCategory::Reply);
+					// Duplicates are skipped
+					storage.add_message((0, size / 2), MessageCategory::Reply);
+				},
+				BatchSize::LargeInput,
+			)
+		});
+	}
+	group.finish();
+}
+
+fn bench_old_messages(c: &mut Criterion) {
+	let mut group = c.benchmark_group("get_old_messages");
+	for size in SIZES {
+		let dir = tempfile::tempdir().unwrap();
+		let storage = filled_storage(dir.path(), size);
+		group.bench_with_input(BenchmarkId::from_parameter(size), &storage, |b, storage| {
+			b.iter(|| storage.get_old_messages(&std::time::Duration::from_secs(60)))
+		});
+	}
+	group.finish();
+}
+
+fn bench_remove(c: &mut Criterion) {
+	let mut group = c.benchmark_group("remove_messages");
+	for size in SIZES {
+		let dir = tempfile::tempdir().unwrap();
+		group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+			b.iter_batched_ref(
+				|| filled_storage(dir.path(), size),
+				|storage| storage.remove_messages((0..10).map(|i| (i64::from(i), i)).collect()),
+				BatchSize::LargeInput,
+			)
+		});
+	}
+	group.finish();
+}
+
+fn bench_flush(c: &mut Criterion) {
+	let mut group = c.benchmark_group("flush");
+	group.sample_size(20);
+	for size in SIZES {
+		group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+			b.iter_batched_ref(
+				|| {
+					// A new directory every time, otherwise the messages would be loaded from the previous file
+					let dir = tempfile::tempdir().unwrap();
+					let storage = filled_storage(dir.path(), size);
+					(dir, storage)
+				},
+				|(_, storage)| storage.flush(),
+				BatchSize::LargeInput,
+			)
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(
+	benches,
+	bench_add,
+	bench_old_messages,
+	bench_remove,
+	bench_flush
+);
+criterion_main!(benches);
@@ -1,6 +1,13 @@
-use sysinfo::{ProcessRefreshKind, RefreshKind, System};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+use sysinfo::{Pid, ProcessRefreshKind, System};
+
+use crate::cmdline::{file_name, is_root, normalize_path, option_value, strip_components, unquote};
+
+#[derive(Debug, Eq, PartialEq, Hash, Clone, serde::Serialize, serde::Deserialize)]
 pub enum ActivityKind {
 	Build,
 	Deploy,
@@ -8,6 +15,36 @@ pub enum ActivityKind {
 	UpdateModuleManager,
 }
 
+impl ActivityKind {
+	pub const ALL: [ActivityKind; 4] = [
+		ActivityKind::Build,
+		ActivityKind::Deploy,
+		ActivityKind::UpdateToRevision,
+		ActivityKind::UpdateModuleManager,
+	];
+
+	/// The name used in the config
+	pub fn name(&self) -> &'static str {
+		match self {
+			ActivityKind::Build => "build",
+			ActivityKind::Deploy => "deploy",
+			ActivityKind::UpdateToRevision => "update_to_revision",
+			ActivityKind::UpdateModuleManager => "update_module_manager",
+		}
+	}
+}
+
+impl std::str::FromStr for ActivityKind {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		ActivityKind::ALL
+			.into_iter()
+			.find(|k| k.name() == s)
+			.ok_or(())
+	}
+}
+
 impl std::fmt::Display for ActivityKind {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match *self {
@@ -19,108 +56,225 @@ impl std::fmt::Display for ActivityKind {
 	}
 }
 
+/// Resources used by the process tree since the activity has been detected, sampled on every scan
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ResourceUsage {
+	/// The largest total memory of the tree in bytes
+	pub peak_memory: u64,
+	/// CPU time of the tree. It is measured by the CPU time of the processes on Linux. Elsewhere it is estimated
+	/// by the CPU usage of every scan multiplied by the time since the previous one
+	pub cpu_time: Duration,
+	/// False if `cpu_time` is estimated, the processes living between two scans are missed then
+	#[serde(default)]
+	pub cpu_time_measured: bool,
+	/// Time between the first and the last scan of the activity
+	pub elapsed: Duration,
+}
+
+impl ResourceUsage {
+	/// Average CPU usage, percent of one core
+	pub fn average_cpu(&self) -> f32 {
+		if self.elapsed.is_zero() {
+			return 0.0;
+		}
+		(self.cpu_time.as_secs_f64() / self.elapsed.as_secs_f64() * 100.0) as f32
+	}
+
+	/// The CPU usage estimates the CPU time if the measured one is unknown
+	fn sample(
+		&mut self,
+		cpu_usage: f32,
+		memory: u64,
+		interval: Duration,
+		cpu_time: Option<Duration>,
+	) {
+		self.peak_memory = self.peak_memory.max(memory);
+		match cpu_time {
+			// The time of a process is lost if it exits unnoticed by the tree, the total never decreases
+			Some(cpu_time) => self.cpu_time = self.cpu_time.max(cpu_time),
+			None => self.cpu_time += interval.mul_f64(f64::from(cpu_usage.max(0.0)) / 100.0),
+		}
+		self.cpu_time_measured = cpu_time.is_some();
+		self.elapsed += interval;
+	}
+}
+
 pub trait ProcessDescription {
-	fn pid(&self) -> &sysinfo::Pid;
+	/// The root process of the activity, it stays the same after the root process exits
+	fn id(&self) -> &ProcessId;
 	fn activity_kind(&self) -> &ActivityKind;
 	fn description(&self) -> Option<&str>;
+	/// Number of the alive processes of the tree besides the root
+	fn children(&self) -> usize;
+	/// Total CPU usage of the tree, percent of one core
+	fn cpu_usage(&self) -> f32;
+	/// Total memory of the tree in bytes
+	fn memory(&self) -> u64;
+	/// Time since the tree has last used CPU, done I/O or started or lost a process
+	fn idle(&self) -> Duration;
+	fn usage(&self) -> &ResourceUsage;
 }
 
+/// The agents send the activities to the bot as they are, except the host
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct ProcessDescriptionWithPid {
-	pid: sysinfo::Pid,
+	id: ProcessId,
 	description: ProcessDescriptionData,
+	children: usize,
+	cpu_usage: f32,
+	memory: u64,
+	idle: Duration,
+	usage: ResourceUsage,
+	/// Empty for the activities of this machine
+	#[serde(skip)]
+	host: String,
 }
 
+impl ProcessDescriptionWithPid {
+	/// The label of the agent which has found the activity, empty for this machine
+	pub fn host(&self) -> &str {
+		&self.host
+	}
+
+	pub fn with_host(self, host: &str) -> Self {
+		Self {
+			host: host.to_owned(),
+			..self
+		}
+	}
+
+	pub fn activity_id(&self) -> ActivityId {
+		ActivityId {
+			host: self.host.clone(),
+			root: self.id,
+		}
+	}
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 struct ProcessDescriptionData {
 	activity: ActivityKind,
 	description_text: Option<String>,
 }
 
 impl ProcessDescription for ProcessDescriptionWithPid {
-	fn pid(&self) -> &sysinfo::Pid {
-		&self.pid
+	fn id(&self) -> &ProcessId {
+		&self.id
 	}
 	fn activity_kind(&self) -> &ActivityKind {
 		&self.description.activity
 	}
 	fn description(&self) -> Option<&str> {
-		if let Some(x) = &self.description.description_text {
-			Some(&*x)
-		} else {
-			None
-		}
+		self.description.description_text.as_deref()
+	}
+	fn children(&self) -> usize {
+		self.children
+	}
+	fn cpu_usage(&self) -> f32 {
+		self.cpu_usage
+	}
+	fn memory(&self) -> u64 {
+		self.memory
+	}
+	fn idle(&self) -> Duration {
+		self.idle
+	}
+	fn usage(&self) -> &ResourceUsage {
+		&self.usage
 	}
 }
 
-fn get_process_description(proc: &sysinfo::Process) -> Option<ProcessDescriptionData> {
-	let name = proc.name();
-	let cmd = proc.cmd();
-	if name.contains("qtcreator_ctrlc_stub") {
-		let build_path;
-		if let Some(pos) = cmd.iter().position(|r| r == "--build") {
-			if cmd.len() > pos + 1 {
-				build_path = Some(cmd[pos + 1].clone());
-			} else {
-				build_path = None;
-			}
-			return Some(ProcessDescriptionData {
-				activity: ActivityKind::Build,
-				description_text: build_path,
-			});
-		} else {
-			return None;
-		};
+/// Parts of the names of the processes which may be activities, the rest are never activities
+const ACTIVITY_NAMES: [&str; 4] = [
+	"qtcreator_ctrlc_stub",
+	"python",
+	"jinnee-utility",
+	"module-manager",
+];
+
+/// Whether the process may be an activity, its arguments tell it for sure
+fn is_activity_name(name: &str) -> bool {
+	ACTIVITY_NAMES.iter().any(|part| name.contains(part))
+}
+
+/// Detects the activity by the process name, arguments and working directory.
+/// The paths in the description are normalized, relative ones are resolved against `cwd`
+fn get_process_description(
+	name: &str,
+	cmd: &[String],
+	cwd: Option<&Path>,
+) -> Option<ProcessDescriptionData> {
+	if !is_activity_name(name) {
+		return None;
 	}
+	let path = |arg: &str| normalize_path(arg, cwd);
 
-	let arg_item = cmd
-		.iter()
-		.find(|a| a.contains("update_to_revisions.py"))
-		.map(|x| x.strip_suffix("online-inside\\update_to_revisions.py"));
-	if name.contains("python") && arg_item.is_some() {
-		let opt_path = arg_item.unwrap();
-		let path;
-		if let Some(x) = opt_path {
-			path = Some(x.to_string());
-		} else {
-			path = None;
+	if name.contains("qtcreator_ctrlc_stub") {
+		if !cmd.iter().any(|arg| unquote(arg) == "--build") {
+			return None;
 		}
 		return Some(ProcessDescriptionData {
-			activity: ActivityKind::UpdateToRevision,
-			description_text: path,
+			activity: ActivityKind::Build,
+			description_text: option_value(cmd, "--build").map(path),
 		});
 	}
 
-	if name.contains("jinnee-utility") && cmd.contains(&"--deploy_stand".to_owned()) {
+	if name.contains("python") {
+		let script = cmd
+			.iter()
+			.map(|arg| path(arg))
+			.find(|arg| file_name(arg) == "update_to_revisions.py");
+		if let Some(script) = script {
+			return Some(ProcessDescriptionData {
+				activity: ActivityKind::UpdateToRevision,
+				description_text: strip_components(&script, "online-inside/update_to_revisions.py")
+					.map(|x| x.to_owned()),
+			});
+		}
+	}
+
+	if name.contains("jinnee-utility") && cmd.iter().any(|arg| unquote(arg) == "--deploy_stand") {
 		return Some(ProcessDescriptionData {
 			activity: ActivityKind::Deploy,
-			description_text: get_deploy_path(cmd),
+			description_text: get_deploy_path(cmd, cwd),
 		});
 	}
 
 	if name.contains("module-manager") {
-		// Find the "store" argument
-		let descr = {
-			if let Some(store_arg) = cmd.iter().enumerate().find(|(_, arg)| {
-				return *arg == "--store";
-			}) {
-				if store_arg.0 + 1 < cmd.len() {
-					Some(cmd[store_arg.0 + 1].clone())
-				} else {
-					None
-				}
-			} else {
-				None
-			}
-		};
-
 		return Some(ProcessDescriptionData {
 			activity: ActivityKind::UpdateModuleManager,
-			description_text: descr,
+			description_text: option_value(cmd, "--store").map(path),
 		});
 	}
 
 	None
 }
 
+/// Whether the running process is the root of an activity. It reads the name and the arguments
+/// from /proc the way the scan does, so a single process is checked without the full scan.
+/// The arguments are read only if the name fits, most of the processes are rejected by one short read
+#[cfg(target_os = "linux")]
+pub fn is_activity_process(pid: u32) -> bool {
+	let dir = Path::new("/proc").join(pid.to_string());
+	// The process has exited already
+	let Ok(name) = std::fs::read_to_string(dir.join("comm")) else {
+		return false;
+	};
+	let name = name.trim_end();
+	if !is_activity_name(name) {
+		return false;
+	}
+	let cmdline = std::fs::read(dir.join("cmdline")).unwrap_or_default();
+	let cmd: Vec<String> = cmdline
+		.strip_suffix(&[0])
+		.unwrap_or(&cmdline)
+		.split(|b| *b == 0)
+		.map(|arg| String::from_utf8_lossy(arg).into_owned())
+		.collect();
+	// The working directory affects the description only
+	get_process_description(name, &cmd, None).is_some()
+}
+
 // The longest common prefix of two strings
 fn lcp<'a>(a: &'a str, b: &'a str) -> &'a str {
 	for ((i, c1), c2) in a.char_indices().zip(b.chars()) {
@@ -141,94 +295,520 @@ fn pop_char(a: &str) -> &str {
 	c.as_str()
 }
 
+/// The longest common directory of two normalized paths
+fn common_dir<'a>(a: &'a str, b: &'a str) -> &'a str {
+	let prefix = lcp(a, b);
+	let is_boundary = |s: &str| s.len() == prefix.len() || s[prefix.len()..].starts_with('/');
+	if is_boundary(a) && is_boundary(b) {
+		return prefix;
+	}
+	// The last component is matched partially, e.g. "deploy2" and "deploy22"
+	match prefix.rfind('/') {
+		Some(pos) if is_root(&prefix[..=pos]) => &prefix[..=pos],
+		Some(pos) => pop_char(&prefix[..=pos]),
+		None => "",
+	}
+}
+
 // There is no deploy path in the arguments list. So I need some actions to get it
-// The most precise way to get it is to find the longest common prefix of two strings:
+// The most precise way to get it is to find the longest common directory of two paths:
 // --deploy_stand C:/Saby/deployed_projects/deploy2\config\test.s3deploy
 // --logs_dir C:/Saby/deployed_projects/deploy2\logs
-// The common prefix is C:/Saby/deployed_projects/deploy2, so it is the deploy path
-// It's also possible to take a prefix to any of these strings, but it may be broken if these paths change in the future
-fn get_deploy_path(cmd: &[String]) -> Option<String> {
-	let mut deploy_stand_idx = None;
-	let mut logs_dir_idx = None;
-	for arg in cmd.iter().enumerate() {
-		if arg.1 == "--deploy_stand" {
-			deploy_stand_idx = Some(arg.0);
-		} else if arg.1 == "--logs_dir" {
-			logs_dir_idx = Some(arg.0);
-		}
-	}
-
-	let deploy_stand_path = deploy_stand_idx
-		.map(|x| {
-			if x + 1 < cmd.len() {
-				Some(cmd[x + 1].clone())
-			} else {
-				None
-			}
-		})
-		.unwrap_or(None);
-
-	let logs_dir_path = logs_dir_idx
-		.map(|x| {
-			if x + 1 < cmd.len() {
-				Some(cmd[x + 1].clone())
-			} else {
-				None
-			}
-		})
-		.unwrap_or(None);
+// The common directory is C:/Saby/deployed_projects/deploy2, so it is the deploy path
+// It's also possible to take a prefix to any of these paths, but it may be broken if these paths change in the future
+fn get_deploy_path(cmd: &[String], cwd: Option<&Path>) -> Option<String> {
+	let deploy_stand_path = option_value(cmd, "--deploy_stand").map(|arg| normalize_path(arg, cwd));
+	let logs_dir_path = option_value(cmd, "--logs_dir").map(|arg| normalize_path(arg, cwd));
 
 	let r = match (&deploy_stand_path, &logs_dir_path) {
-		(Some(a1), Some(a2)) => Some(lcp(&a1, &a2)),
+		(Some(a1), Some(a2)) => Some(common_dir(a1, a2)).filter(|dir| !dir.is_empty()),
 
-		(None, Some(logs_dir_path)) => logs_dir_path.strip_suffix("logs"),
+		(None, Some(logs_dir_path)) => strip_components(logs_dir_path, "logs"),
 
 		(Some(deploy_stand_path), None) => {
-			let mut res = deploy_stand_path.strip_suffix("config\\test.s3deploy");
-
-			if res.is_none() {
-				res = deploy_stand_path.strip_suffix("config/test.s3deploy");
-			}
-
-			res
+			strip_components(deploy_stand_path, "config/test.s3deploy")
 		}
 
 		(None, None) => None,
 	};
 
-	if let Some(res) = r {
-		Some(
-			if res.ends_with("\\") || res.ends_with("/") {
-				pop_char(res)
-			} else {
-				res
-			}
-			.to_owned(),
-		)
-	} else {
-		None
+	r.map(|res| res.to_owned())
+}
+
+/// Identifies a process. The PID of an exited process may be given to another one, the start time tells them apart
+#[derive(
+	Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+pub struct ProcessId {
+	#[serde(with = "pid_number")]
+	pub pid: Pid,
+	/// Seconds since the Unix epoch
+	pub start_time: u64,
+}
+
+impl std::fmt::Display for ProcessId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.pid)
+	}
+}
+
+/// sysinfo can't deserialize a PID, it is written as a number
+mod pid_number {
+	use serde::{Deserialize, Deserializer, Serializer};
+	use sysinfo::Pid;
+
+	pub fn serialize<S: Serializer>(pid: &Pid, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_u32(pid.as_u32())
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pid, D::Error> {
+		u32::deserialize(deserializer).map(Pid::from_u32)
+	}
+}
+
+/// Identifies an activity among the machines: the root process and the host label of the agent.
+/// The host is empty for the activities of this machine
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ActivityId {
+	pub host: String,
+	pub root: ProcessId,
+}
+
+impl ActivityId {
+	pub fn local(root: ProcessId) -> Self {
+		Self {
+			host: String::new(),
+			root,
+		}
+	}
+
+	pub fn is_local(&self) -> bool {
+		self.host.is_empty()
+	}
+}
+
+impl std::fmt::Display for ActivityId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if self.is_local() {
+			write!(f, "{}", self.root)
+		} else {
+			write!(f, "{}@{}", self.root, self.host)
+		}
 	}
 }
 
-pub fn get_activity_list() -> Vec<impl ProcessDescription> {
-	let sys = System::new_with_specifics(
-		RefreshKind::new()
-			.with_processes(ProcessRefreshKind::new().with_cmd(sysinfo::UpdateKind::OnlyIfNotSet)),
-	);
+/// A process from the snapshot of the system
+#[derive(Clone, Debug)]
+pub struct ProcessInfo {
+	pub pid: Pid,
+	pub start_time: u64,
+	pub parent: Option<Pid>,
+	pub name: String,
+	pub cmd: Vec<String>,
+	pub cwd: Option<PathBuf>,
+	/// Percent of one CPU core
+	pub cpu_usage: f32,
+	/// Resident memory in bytes
+	pub memory: u64,
+	/// Bytes read and written since the start
+	pub disk_io: u64,
+	/// CPU time of the process and its exited children it has waited for, since the start.
+	/// None if unknown or not read by the scan, see `ProcessSource::cpu_time`
+	pub cpu_time: Option<Duration>,
+}
+
+/// Units of the times in /proc/<pid>/stat, None if unknown
+#[cfg(target_os = "linux")]
+fn clock_ticks() -> Option<f64> {
+	static CLOCK_TICKS: std::sync::OnceLock<libc::c_long> = std::sync::OnceLock::new();
+	let ticks = *CLOCK_TICKS.get_or_init(|| unsafe { libc::sysconf(libc::_SC_CLK_TCK) });
+	(ticks > 0).then_some(ticks as f64)
+}
 
-	sys.processes()
+/// utime, stime, cutime and cstime from /proc/<pid>/stat
+#[cfg(target_os = "linux")]
+fn read_cpu_time(pid: Pid) -> Option<Duration> {
+	let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+	// The name in the parentheses may contain the spaces, the state is the first field after it
+	let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split(' ').collect();
+	let ticks: u64 = fields
+		.get(11..15)?
 		.iter()
-		.filter_map(|(_, proc)| {
-			if let Some(data) = get_process_description(proc) {
-				Some(ProcessDescriptionWithPid {
-					pid: proc.pid(),
-					description: data,
-				})
-			} else {
-				None
+		.map(|f| f.parse::<u64>().ok())
+		.sum::<Option<u64>>()?;
+	Some(Duration::from_secs_f64(ticks as f64 / clock_ticks()?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_cpu_time(_pid: Pid) -> Option<Duration> {
+	None
+}
+
+impl ProcessInfo {
+	fn from_sysinfo(proc: &sysinfo::Process) -> Self {
+		Self {
+			pid: proc.pid(),
+			start_time: proc.start_time(),
+			parent: proc.parent(),
+			name: proc.name().to_owned(),
+			cmd: proc.cmd().to_vec(),
+			cwd: proc.cwd().map(|p| p.to_path_buf()),
+			cpu_usage: proc.cpu_usage(),
+			memory: proc.memory(),
+			disk_io: proc.disk_usage().total_read_bytes + proc.disk_usage().total_written_bytes,
+			// Read for the tracked processes only, the scan of every process would be too slow
+			cpu_time: None,
+		}
+	}
+
+	pub fn id(&self) -> ProcessId {
+		ProcessId {
+			pid: self.pid,
+			start_time: self.start_time,
+		}
+	}
+}
+
+/// Where the processes come from
+pub trait ProcessSource: Send {
+	fn processes(&mut self) -> Vec<ProcessInfo>;
+	/// Asks the process to terminate. False if it has exited or can't be stopped
+	fn kill(&mut self, id: ProcessId) -> bool;
+	/// CPU time of the process from the snapshot, asked for the processes of the activities only
+	fn cpu_time(&mut self, process: &ProcessInfo) -> Option<Duration> {
+		process.cpu_time
+	}
+}
+
+/// The processes of the system
+pub struct SysinfoSource {
+	system: System,
+}
+
+impl Default for SysinfoSource {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl SysinfoSource {
+	pub fn new() -> Self {
+		Self {
+			system: System::new(),
+		}
+	}
+}
+
+impl ProcessSource for SysinfoSource {
+	fn processes(&mut self) -> Vec<ProcessInfo> {
+		// The same System is refreshed every time, otherwise the CPU usage is unknown
+		self.system.refresh_processes_specifics(
+			ProcessRefreshKind::new()
+				.with_cpu()
+				.with_memory()
+				.with_disk_usage()
+				.with_cmd(sysinfo::UpdateKind::OnlyIfNotSet)
+				.with_cwd(sysinfo::UpdateKind::OnlyIfNotSet),
+		);
+		self.system
+			.processes()
+			.values()
+			.map(ProcessInfo::from_sysinfo)
+			.collect()
+	}
+
+	fn kill(&mut self, id: ProcessId) -> bool {
+		match self.system.process(id.pid) {
+			// The PID may belong to another process already
+			Some(proc) if proc.start_time() == id.start_time => proc
+				.kill_with(sysinfo::Signal::Term)
+				.unwrap_or_else(|| proc.kill()),
+			_ => false,
+		}
+	}
+
+	fn cpu_time(&mut self, process: &ProcessInfo) -> Option<Duration> {
+		read_cpu_time(process.pid)
+	}
+}
+
+/// A fake for the tests: the clones share the processes, so the test changes them while the tracker owns the source
+#[derive(Clone, Default)]
+pub struct ScriptedSource {
+	processes: Arc<Mutex<Vec<ProcessInfo>>>,
+}
+
+impl ScriptedSource {
+	pub fn set(&self, processes: Vec<ProcessInfo>) {
+		*self.processes.lock().unwrap() = processes;
+	}
+}
+
+impl ProcessSource for ScriptedSource {
+	fn processes(&mut self) -> Vec<ProcessInfo> {
+		self.processes.lock().unwrap().clone()
+	}
+
+	/// The process disappears at once
+	fn kill(&mut self, id: ProcessId) -> bool {
+		let mut processes = self.processes.lock().unwrap();
+		let len = processes.len();
+		processes.retain(|p| p.id() != id);
+		processes.len() != len
+	}
+}
+
+/// An activity seen earlier
+struct TrackedActivity {
+	description: ProcessDescriptionData,
+	/// The alive processes of the tree, the root included while it is alive
+	tree: HashSet<ProcessId>,
+	/// Total I/O of the tree at the last scan
+	disk_io: u64,
+	/// The last scan which has seen the tree working
+	last_progress: Instant,
+	last_scan: Instant,
+	usage: ResourceUsage,
+	/// The parent and the CPU time of the processes of the tree at the last scan
+	cpu_times: HashMap<ProcessId, (Option<ProcessId>, Duration)>,
+	/// CPU time of the exited processes which no process of the tree has waited for
+	exited_cpu_time: Duration,
+	/// CPU time of the tree when it has been measured first, the usage is counted from it
+	base_cpu_time: Option<Duration>,
+}
+
+/// The activity using less CPU (percent of one core) and doing no I/O is idle.
+/// A hung process may still wake up by the timers now and then
+const IDLE_CPU_USAGE: f32 = 1.0;
+
+/// Groups the processes into trees by the parent PID and attributes them to the activity detected at the root.
+/// The activity lasts while any process of its tree is alive: the wrapper processes often exit before the real work is done
+pub struct ActivityTracker {
+	source: Box<dyn ProcessSource>,
+	/// Activities by the root process
+	activities: HashMap<ProcessId, TrackedActivity>,
+	/// The detection results of the alive processes. The command line of a process is not re-read,
+	/// so it is checked once instead of every scan
+	detected: HashMap<ProcessId, Option<ProcessDescriptionData>>,
+}
+
+impl Default for ActivityTracker {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl ActivityTracker {
+	/// Tracks the processes of the system
+	pub fn new() -> Self {
+		Self::with_source(Box::new(SysinfoSource::new()))
+	}
+
+	pub fn with_source(source: Box<dyn ProcessSource>) -> Self {
+		Self {
+			source,
+			activities: HashMap::new(),
+			detected: HashMap::new(),
+		}
+	}
+
+	/// Scans the processes and returns the current activities
+	#[tracing::instrument(name = "detect", level = "debug", skip_all)]
+	pub fn refresh(&mut self) -> Vec<ProcessDescriptionWithPid> {
+		let processes = self.source.processes();
+		let res = self.update(&processes);
+		for a in res.iter() {
+			tracing::trace!(pid = %a.id, kind = %a.activity_kind(), description = ?a.description(), children = a.children, "Activity detected");
+		}
+		tracing::debug!(
+			processes = processes.len(),
+			activities = res.len(),
+			"Detection completed"
+		);
+		res
+	}
+
+	/// The alive processes of the activity, nothing if the activity has completed
+	pub fn tree(&self, root: &ProcessId) -> impl Iterator<Item = &ProcessId> {
+		self.activities
+			.get(root)
+			.into_iter()
+			.flat_map(|act| act.tree.iter())
+	}
+
+	/// Asks all the processes of the activity to terminate. Returns how many of them have been asked
+	pub fn stop(&mut self, root: &ProcessId) -> usize {
+		let tree: Vec<ProcessId> = self.tree(root).copied().collect();
+		tree.into_iter().filter(|id| self.source.kill(*id)).count()
+	}
+
+	/// Updates the process trees of the activities by the snapshot of the processes
+	pub fn update(&mut self, processes: &[ProcessInfo]) -> Vec<ProcessDescriptionWithPid> {
+		self.update_at(processes, Instant::now())
+	}
+
+	/// The same as `update` for the snapshot made at the moment
+	pub fn update_at(
+		&mut self,
+		processes: &[ProcessInfo],
+		now: Instant,
+	) -> Vec<ProcessDescriptionWithPid> {
+		let by_pid: HashMap<Pid, &ProcessInfo> = processes.iter().map(|p| (p.pid, p)).collect();
+		// A parent younger than the child is another process which has got the PID of the exited parent
+		let parent_of = |p: &ProcessInfo| {
+			p.parent
+				.and_then(|pid| by_pid.get(&pid))
+				.copied()
+				.filter(|parent| parent.start_time <= p.start_time)
+		};
+		let mut children: HashMap<ProcessId, Vec<ProcessId>> = HashMap::new();
+		for p in processes {
+			if let Some(parent) = parent_of(p) {
+				children.entry(parent.id()).or_default().push(p.id());
+			}
+		}
+
+		let alive: HashSet<ProcessId> = processes.iter().map(ProcessInfo::id).collect();
+		self.detected.retain(|id, _| alive.contains(id));
+		for p in processes {
+			self.detected
+				.entry(p.id())
+				.or_insert_with(|| get_process_description(&p.name, &p.cmd, p.cwd.as_deref()));
+		}
+		// The activities whose process trees have changed are working
+		let mut changed: HashSet<ProcessId> = HashSet::new();
+		for (root, act) in self.activities.iter_mut() {
+			let len = act.tree.len();
+			act.tree.retain(|id| alive.contains(id));
+			if act.tree.len() != len {
+				changed.insert(*root);
+			}
+			// The CPU time of an exited process is added to its parent if the parent of the tree waits for it
+			let tree = &act.tree;
+			let exited = &mut act.exited_cpu_time;
+			act.cpu_times.retain(|id, (parent, cpu_time)| {
+				if tree.contains(id) {
+					return true;
+				}
+				if !parent.is_some_and(|parent| tree.contains(&parent)) {
+					*exited += *cpu_time;
+				}
+				false
+			});
+		}
+		self.activities.retain(|_, act| !act.tree.is_empty());
+
+		let mut owner: HashMap<ProcessId, ProcessId> = HashMap::new();
+		for (root, act) in self.activities.iter() {
+			for id in act.tree.iter() {
+				owner.insert(*id, *root);
+			}
+		}
+
+		// New activities. The topmost matching process of a tree is the root, the matching children belong to it
+		let detected = &self.detected;
+		let is_activity = |p: &ProcessInfo| detected.get(&p.id()).is_some_and(Option::is_some);
+		for p in processes {
+			let id = p.id();
+			if owner.contains_key(&id) {
+				continue;
 			}
-		})
-		.collect()
+			let Some(Some(description)) = detected.get(&id) else {
+				continue;
+			};
+			let mut ancestors = HashSet::from([id]);
+			let mut parent = parent_of(p);
+			let mut is_root = true;
+			// The set protects against the loops in the parent links
+			while let Some(ancestor) = parent.filter(|a| ancestors.insert(a.id())) {
+				if owner.contains_key(&ancestor.id()) || is_activity(ancestor) {
+					is_root = false;
+					break;
+				}
+				parent = parent_of(ancestor);
+			}
+			if is_root {
+				owner.insert(id, id);
+				self.activities.insert(
+					id,
+					TrackedActivity {
+						description: description.clone(),
+						tree: HashSet::from([id]),
+						disk_io: 0,
+						last_progress: now,
+						last_scan: now,
+						usage: ResourceUsage::default(),
+						cpu_times: HashMap::new(),
+						exited_cpu_time: Duration::ZERO,
+						base_cpu_time: None,
+					},
+				);
+			}
+		}
+
+		// The new children of the known processes join their trees
+		for (root, act) in self.activities.iter_mut() {
+			let mut queue: Vec<ProcessId> = act.tree.iter().copied().collect();
+			while let Some(id) = queue.pop() {
+				for child in children.get(&id).into_iter().flatten() {
+					if !owner.contains_key(child) {
+						owner.insert(*child, *root);
+						act.tree.insert(*child);
+						changed.insert(*root);
+						queue.push(*child);
+					}
+				}
+			}
+		}
+
+		let mut res: Vec<_> = self
+			.activities
+			.iter_mut()
+			.map(|(root, act)| {
+				let tree = act.tree.iter().filter_map(|id| by_pid.get(&id.pid));
+				let cpu_usage = tree.clone().map(|p| p.cpu_usage).sum();
+				let disk_io = tree.clone().map(|p| p.disk_io).sum();
+				if changed.contains(root) || disk_io != act.disk_io || cpu_usage >= IDLE_CPU_USAGE {
+					act.last_progress = now;
+				}
+				act.disk_io = disk_io;
+				let memory = tree.clone().map(|p| p.memory).sum();
+				// Unknown if the time of any process is unknown
+				let mut cpu_time = Some(act.exited_cpu_time);
+				for p in tree {
+					let process_time = self.source.cpu_time(p);
+					if let Some(process_time) = process_time {
+						let parent = parent_of(p).map(ProcessInfo::id);
+						act.cpu_times.insert(p.id(), (parent, process_time));
+					}
+					cpu_time = cpu_time.zip(process_time).map(|(sum, time)| sum + time);
+				}
+				let cpu_time = cpu_time.map(|cpu_time| {
+					cpu_time.saturating_sub(*act.base_cpu_time.get_or_insert(cpu_time))
+				});
+				// The CPU usage is measured since the previous refresh of the processes
+				act.usage.sample(
+					cpu_usage,
+					memory,
+					now.saturating_duration_since(act.last_scan),
+					cpu_time,
+				);
+				act.last_scan = now;
+				ProcessDescriptionWithPid {
+					id: *root,
+					description: act.description.clone(),
+					children: act.tree.len() - usize::from(act.tree.contains(root)),
+					cpu_usage,
+					memory,
+					idle: now.saturating_duration_since(act.last_progress),
+					usage: act.usage,
+					host: String::new(),
+				}
+			})
+			.collect();
+		res.sort_by_key(|a| a.id);
+		res
+	}
 }
 
 #[cfg(test)]
@@ -253,4 +833,453 @@ mod test {
 		assert_eq!(pop_char("a"), "");
 		assert_eq!(pop_char(""), "");
 	}
+
+	#[test]
+	fn test_common_dir() {
+		assert_eq!(
+			common_dir("C:/a/deploy2/config/x", "C:/a/deploy2/logs"),
+			"C:/a/deploy2"
+		);
+		assert_eq!(common_dir("/a/deploy2/logs", "/a/deploy22/logs"), "/a");
+		assert_eq!(common_dir("/a/b", "/a/b/logs"), "/a/b");
+		assert_eq!(common_dir("/a", "/b"), "/");
+		assert_eq!(common_dir("C:/a", "D:/a"), "");
+	}
+
+	#[test]
+	fn test_process_description() {
+		// Process name, arguments, working directory and the detected activity
+		type Case<'a> = (
+			&'a str,
+			&'a [&'a str],
+			Option<&'a str>,
+			Option<(ActivityKind, Option<&'a str>)>,
+		);
+		let cases: [Case; 15] = [
+			// Windows
+			(
+				"qtcreator_ctrlc_stub.exe",
+				&["stub", "--build", r#""C:\work\build-debug""#],
+				None,
+				Some((ActivityKind::Build, Some("C:/work/build-debug"))),
+			),
+			(
+				"python.exe",
+				&[
+					"python",
+					r"C:\repo\online-inside\update_to_revisions.py",
+					"-r",
+					"24.1",
+				],
+				None,
+				Some((ActivityKind::UpdateToRevision, Some("C:/repo"))),
+			),
+			(
+				"python.exe",
+				&["python", r"online-inside\update_to_revisions.py"],
+				Some(r"D:\sources\repo"),
+				Some((ActivityKind::UpdateToRevision, Some("D:/sources/repo"))),
+			),
+			(
+				"jinnee-utility.exe",
+				&[
+					"jinnee-utility",
+					"--deploy_stand",
+					r"C:/Saby/deployed_projects/deploy2\config\test.s3deploy",
+					"--logs_dir",
+					r"C:/Saby/deployed_projects/deploy2\logs",
+				],
+				None,
+				Some((
+					ActivityKind::Deploy,
+					Some("C:/Saby/deployed_projects/deploy2"),
+				)),
+			),
+			(
+				"jinnee-utility.exe",
+				&["jinnee-utility", "--deploy_stand", r"config\test.s3deploy"],
+				Some(r"C:\Saby\deploy3"),
+				Some((ActivityKind::Deploy, Some("C:/Saby/deploy3"))),
+			),
+			(
+				"module-manager.exe",
+				&["module-manager", r#"--store="C:\Saby\store""#],
+				None,
+				Some((ActivityKind::UpdateModuleManager, Some("C:/Saby/store"))),
+			),
+			// Linux
+			(
+				"qtcreator_ctrlc_stub",
+				&["stub", "--build", "/home/user/build-debug/"],
+				None,
+				Some((ActivityKind::Build, Some("/home/user/build-debug"))),
+			),
+			(
+				"python3",
+				&["python3", "./online-inside/update_to_revisions.py"],
+				Some("/home/user/repo"),
+				Some((ActivityKind::UpdateToRevision, Some("/home/user/repo"))),
+			),
+			(
+				"python3",
+				&["python3", "/opt/update_to_revisions.py"],
+				None,
+				Some((ActivityKind::UpdateToRevision, None)),
+			),
+			(
+				"jinnee-utility",
+				&[
+					"jinnee-utility",
+					"--deploy_stand",
+					"/srv/deploy/config/test.s3deploy",
+					"--logs_dir=../logs",
+				],
+				Some("/srv/deploy/config"),
+				Some((ActivityKind::Deploy, Some("/srv/deploy"))),
+			),
+			(
+				"jinnee-utility",
+				&["jinnee-utility", "--logs_dir", "/srv/deploy/logs"],
+				None,
+				None,
+			),
+			(
+				"module-manager",
+				&["module-manager", "--store", "store"],
+				Some("/srv/saby"),
+				Some((ActivityKind::UpdateModuleManager, Some("/srv/saby/store"))),
+			),
+			// Not activities
+			("qtcreator_ctrlc_stub", &["stub", "--run"], None, None),
+			("python3", &["python3", "manage.py"], None, None),
+			(
+				"make",
+				&["make", "--build", "/b", "--deploy_stand"],
+				None,
+				None,
+			),
+		];
+		for (name, cmd, cwd, expected) in cases {
+			let cmd: Vec<String> = cmd.iter().map(|s| s.to_string()).collect();
+			let res = get_process_description(name, &cmd, cwd.map(Path::new))
+				.map(|d| (d.activity, d.description_text));
+			let expected = expected.map(|(kind, descr)| (kind, descr.map(|s| s.to_owned())));
+			assert_eq!(res, expected, "{} {:?}", name, cmd);
+		}
+	}
+
+	/// The CPU times of the fake processes are not read from the system
+	fn scripted_tracker() -> ActivityTracker {
+		ActivityTracker::with_source(Box::new(ScriptedSource::default()))
+	}
+
+	fn process(pid: usize, parent: usize, name: &str, cmd: &[&str]) -> ProcessInfo {
+		ProcessInfo {
+			pid: Pid::from(pid),
+			start_time: 1,
+			parent: Some(Pid::from(parent)),
+			name: name.to_owned(),
+			cmd: cmd.iter().map(|s| s.to_string()).collect(),
+			cwd: None,
+			cpu_usage: 10.0,
+			memory: 1000,
+			disk_io: 0,
+			cpu_time: None,
+		}
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_is_activity_process() {
+		assert!(!is_activity_process(std::process::id()));
+
+		// The name of the process is the name of the link it is started by
+		let dir = tempfile::tempdir().unwrap();
+		let link = dir.path().join("module-manager");
+		std::os::unix::fs::symlink("/bin/sleep", &link).unwrap();
+		let mut child = std::process::Command::new(&link).arg("10").spawn().unwrap();
+		assert!(is_activity_process(child.id()));
+		child.kill().unwrap();
+		child.wait().unwrap();
+		assert!(!is_activity_process(child.id()));
+	}
+
+	fn build_tree() -> Vec<ProcessInfo> {
+		vec![
+			process(1, 0, "init", &[]),
+			process(10, 1, "qtcreator_ctrlc_stub", &["stub", "--build", "/b"]),
+			process(11, 10, "make", &["make", "-j8"]),
+			process(12, 11, "cc1plus", &[]),
+			process(13, 11, "cc1plus", &[]),
+			process(20, 1, "bash", &[]),
+		]
+	}
+
+	#[test]
+	fn test_tracker_tree() {
+		let mut tracker = scripted_tracker();
+		let res = tracker.update(&build_tree());
+		assert_eq!(res.len(), 1);
+		assert_eq!(res[0].id().pid, Pid::from(10));
+		assert_eq!(res[0].activity_kind(), &ActivityKind::Build);
+		assert_eq!(res[0].children(), 3);
+		assert_eq!(res[0].cpu_usage(), 40.0);
+		assert_eq!(res[0].memory(), 4000);
+
+		// The wrapper exits, the compilers are reparented to init and still work
+		let mut processes = build_tree();
+		processes.retain(|p| p.pid != Pid::from(10));
+		processes[1].parent = Some(Pid::from(1));
+		processes.push(process(14, 11, "cc1plus", &[]));
+		let res = tracker.update(&processes);
+		assert_eq!(res.len(), 1);
+		assert_eq!(res[0].id().pid, Pid::from(10));
+		assert_eq!(res[0].children(), 4);
+
+		// The last compiler is gone
+		processes.retain(|p| p.pid == Pid::from(1) || p.pid == Pid::from(20));
+		processes.push(process(15, 20, "ls", &[]));
+		assert!(tracker.update(&processes).is_empty());
+	}
+
+	#[test]
+	fn test_tracker_nested_activities() {
+		let mut tracker = scripted_tracker();
+		let mut processes = build_tree();
+		// The deploy started by the build belongs to the build
+		processes.push(process(
+			16,
+			11,
+			"jinnee-utility",
+			&[
+				"jinnee-utility",
+				"--deploy_stand",
+				"/d/config/test.s3deploy",
+			],
+		));
+		// The separate deploy is a separate activity
+		processes.push(process(
+			30,
+			20,
+			"jinnee-utility",
+			&[
+				"jinnee-utility",
+				"--deploy_stand",
+				"/e/config/test.s3deploy",
+			],
+		));
+		let res = tracker.update(&processes);
+		let kinds: Vec<_> = res
+			.iter()
+			.map(|a| {
+				(
+					usize::from(a.id().pid),
+					a.activity_kind().clone(),
+					a.children(),
+				)
+			})
+			.collect();
+		assert_eq!(
+			kinds,
+			[(10, ActivityKind::Build, 4), (30, ActivityKind::Deploy, 0)]
+		);
+	}
+
+	fn check(tracker: &mut ActivityTracker) -> Vec<(usize, u64, usize)> {
+		tracker
+			.refresh()
+			.iter()
+			.map(|a| (usize::from(a.id().pid), a.id().start_time, a.children()))
+			.collect()
+	}
+
+	fn started(mut p: ProcessInfo, start_time: u64) -> ProcessInfo {
+		p.start_time = start_time;
+		p
+	}
+
+	#[test]
+	fn test_pid_reuse() {
+		let stub = |start_time| {
+			started(
+				process(10, 1, "qtcreator_ctrlc_stub", &["stub", "--build", "/b"]),
+				start_time,
+			)
+		};
+		let init = process(1, 0, "init", &[]);
+		let source = ScriptedSource::default();
+		let mut tracker = ActivityTracker::with_source(Box::new(source.clone()));
+
+		source.set(vec![
+			init.clone(),
+			stub(100),
+			started(process(11, 10, "make", &[]), 100),
+		]);
+		assert_eq!(check(&mut tracker), [(10, 100, 1)]);
+
+		// Both the build and make have exited, another build has got the same PID.
+		// The subscribers of the first build see it completed
+		source.set(vec![init.clone(), stub(200)]);
+		assert_eq!(check(&mut tracker), [(10, 200, 0)]);
+
+		// Another process has got the PID of the exited make and a child,
+		// the child is not attributed to the build
+		source.set(vec![
+			init.clone(),
+			stub(200),
+			started(process(11, 1, "bash", &[]), 300),
+			started(process(12, 11, "cc1plus", &[]), 310),
+		]);
+		assert_eq!(check(&mut tracker), [(10, 200, 0)]);
+	}
+
+	#[test]
+	fn test_reused_parent_pid() {
+		let mut tracker = scripted_tracker();
+		let stub = started(
+			process(10, 1, "qtcreator_ctrlc_stub", &["stub", "--build", "/b"]),
+			100,
+		);
+		// The PID of the exited parent has been given to the build, the orphan is not its child
+		let orphan = started(process(11, 10, "cc1plus", &[]), 50);
+		let res = tracker.update(&[stub, orphan]);
+		assert_eq!(res[0].children(), 0);
+	}
+
+	#[test]
+	fn test_idle() {
+		let mut tracker = scripted_tracker();
+		let start = Instant::now();
+		let minutes = |m: u64| start + Duration::from_secs(60 * m);
+		let mut processes = build_tree();
+		for p in processes.iter_mut() {
+			p.cpu_usage = 0.0;
+		}
+		let idle = |res: Vec<ProcessDescriptionWithPid>| res[0].idle().as_secs() / 60;
+		assert_eq!(idle(tracker.update_at(&processes, start)), 0);
+		assert_eq!(idle(tracker.update_at(&processes, minutes(5))), 5);
+
+		// I/O is progress
+		processes[3].disk_io += 1;
+		assert_eq!(idle(tracker.update_at(&processes, minutes(6))), 0);
+		assert_eq!(idle(tracker.update_at(&processes, minutes(8))), 2);
+
+		// So is CPU
+		processes[3].cpu_usage = 50.0;
+		assert_eq!(idle(tracker.update_at(&processes, minutes(9))), 0);
+		processes[3].cpu_usage = 0.5;
+		assert_eq!(idle(tracker.update_at(&processes, minutes(10))), 1);
+
+		// And an exited process
+		processes.remove(4);
+		assert_eq!(idle(tracker.update_at(&processes, minutes(11))), 0);
+	}
+
+	#[test]
+	fn test_usage() {
+		let mut tracker = scripted_tracker();
+		let start = Instant::now();
+		let minutes = |m: u64| start + Duration::from_secs(60 * m);
+		let mut processes = build_tree();
+		let set = |processes: &mut Vec<ProcessInfo>, cpu_usage: f32, memory: u64| {
+			for p in processes.iter_mut() {
+				p.cpu_usage = 0.0;
+				p.memory = 0;
+			}
+			processes[3].cpu_usage = cpu_usage;
+			processes[3].memory = memory;
+		};
+		set(&mut processes, 100.0, 300);
+		let usage = tracker.update_at(&processes, start)[0].usage;
+		assert_eq!(
+			usage,
+			ResourceUsage {
+				peak_memory: 300,
+				..Default::default()
+			}
+		);
+		assert_eq!(usage.average_cpu(), 0.0);
+
+		set(&mut processes, 200.0, 500);
+		tracker.update_at(&processes, minutes(10));
+		set(&mut processes, 0.0, 100);
+		let usage = tracker.update_at(&processes, minutes(20))[0].usage;
+		assert_eq!(usage.peak_memory, 500);
+		assert_eq!(usage.cpu_time, Duration::from_secs(20 * 60));
+		assert_eq!(usage.elapsed, Duration::from_secs(20 * 60));
+		assert_eq!(usage.average_cpu(), 100.0);
+		assert!(!usage.cpu_time_measured);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_read_cpu_time() {
+		let pid = Pid::from_u32(std::process::id());
+		let before = read_cpu_time(pid).unwrap();
+		let busy = Instant::now();
+		while busy.elapsed() < Duration::from_millis(100) {
+			std::hint::black_box(busy.elapsed());
+		}
+		assert!(read_cpu_time(pid).unwrap() > before);
+	}
+
+	#[test]
+	fn test_measured_cpu_time() {
+		let mut tracker = scripted_tracker();
+		let start = Instant::now();
+		let minutes = |m: u64| start + Duration::from_secs(60 * m);
+		let mut processes = build_tree();
+		let set = |processes: &mut Vec<ProcessInfo>, pid: usize, secs: u64| {
+			let p = processes.iter_mut().find(|p| p.pid == Pid::from(pid));
+			p.unwrap().cpu_time = Some(Duration::from_secs(secs));
+		};
+		let cpu_time = |usage: ResourceUsage| {
+			assert!(usage.cpu_time_measured);
+			usage.cpu_time.as_secs()
+		};
+		// Counted since the activity is detected
+		for (pid, secs) in [(10, 1), (11, 1), (12, 2), (13, 0)] {
+			set(&mut processes, pid, secs);
+		}
+		assert_eq!(cpu_time(tracker.update_at(&processes, start)[0].usage), 0);
+
+		// The compiler has used 3s more before the exit and make has waited for it
+		processes.retain(|p| p.pid != Pid::from(12));
+		set(&mut processes, 11, 6);
+		set(&mut processes, 13, 3);
+		let usage = tracker.update_at(&processes, minutes(1))[0].usage;
+		assert_eq!(cpu_time(usage), 6);
+
+		// Nobody in the tree waits for the orphan and the root, their last time is kept
+		let orphan = processes.iter_mut().find(|p| p.pid == Pid::from(13));
+		orphan.unwrap().parent = Some(Pid::from(1));
+		tracker.update_at(&processes, minutes(2));
+		processes.retain(|p| p.pid != Pid::from(13) && p.pid != Pid::from(10));
+		let usage = tracker.update_at(&processes, minutes(3))[0].usage;
+		assert_eq!(cpu_time(usage), 6);
+		set(&mut processes, 11, 12);
+		let usage = tracker.update_at(&processes, minutes(4))[0].usage;
+		assert_eq!(cpu_time(usage), 12);
+		assert_eq!(usage.elapsed, Duration::from_secs(240));
+		assert_eq!(usage.average_cpu(), 5.0);
+	}
+
+	#[test]
+	fn test_stop() {
+		let source = ScriptedSource::default();
+		let mut tracker = ActivityTracker::with_source(Box::new(source.clone()));
+		source.set(build_tree());
+		tracker.refresh();
+		assert_eq!(tracker.stop(&build_tree()[1].id()), 4);
+		// The unrelated processes are left
+		let left: Vec<_> = source
+			.clone()
+			.processes()
+			.iter()
+			.map(|p| usize::from(p.pid))
+			.collect();
+		assert_eq!(left, [1, 20]);
+		assert!(tracker.refresh().is_empty());
+		assert_eq!(tracker.stop(&build_tree()[1].id()), 0);
+	}
 }
@@ -0,0 +1,703 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{pin_mut, select, FutureExt};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{debug, info, warn, Instrument};
+
+use crate::activity::{ActivityTracker, ProcessDescriptionWithPid, ProcessId};
+use crate::config::{AgentConfig, AgentsConfig, MAX_HOST_LEN};
+use crate::monitor::{Monitor, MonitorHandle, Snapshot};
+
+/// Changed when the messages change incompatibly
+const PROTOCOL_VERSION: u32 = 1;
+/// Longest message, a report of hundreds of activities fits
+const MAX_MESSAGE_LEN: u64 = 1024 * 1024;
+/// The agent has to introduce itself and the bot has to answer in this time after connecting
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause before connecting again after the connection is lost or refused
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// The agent has to answer the stop request in this time, the bot doesn't handle the other events meanwhile
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the agent sends to the bot. The messages are JSON lines
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AgentMessage {
+	/// The first message, the bot closes the connection if the token is wrong
+	Hello {
+		version: u32,
+		host: String,
+		token: String,
+	},
+	/// The activities found by a check
+	Report {
+		activities: Vec<ProcessDescriptionWithPid>,
+	},
+	/// How many processes have been asked to terminate by the request
+	Stopped { request: u64, count: usize },
+}
+
+/// What the bot sends to the agent
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+	Welcome,
+	Rejected {
+		reason: String,
+	},
+	/// Terminate the processes of the activity
+	Stop {
+		request: u64,
+		root: ProcessId,
+	},
+	/// The subscribed activities, the agent watches the exits of their processes
+	Watch {
+		roots: Vec<ProcessId>,
+	},
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+	writer: &mut W,
+	message: &impl serde::Serialize,
+) -> io::Result<()> {
+	let mut data = serde_json::to_vec(message)?;
+	data.push(b'\n');
+	writer.write_all(&data).await
+}
+
+/// None if the connection is closed
+async fn read_message<M: DeserializeOwned>(
+	reader: &mut BufReader<OwnedReadHalf>,
+) -> io::Result<Option<M>> {
+	let mut line = Vec::new();
+	(&mut *reader)
+		.take(MAX_MESSAGE_LEN)
+		.read_until(b'\n', &mut line)
+		.await?;
+	if line.is_empty() {
+		return Ok(None);
+	}
+	if !line.ends_with(b"\n") {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"the message is too long or cut",
+		));
+	}
+	serde_json::from_slice(&line)
+		.map(Some)
+		.map_err(io::Error::from)
+}
+
+/// The messages are read by a separate task: a read interrupted by another event would lose the data.
+/// The channel is closed when the connection is
+fn spawn_reader<M: DeserializeOwned + Send + 'static>(
+	mut reader: BufReader<OwnedReadHalf>,
+) -> (mpsc::UnboundedReceiver<M>, tokio::task::JoinHandle<()>) {
+	let (tx, rx) = mpsc::unbounded_channel();
+	let task = tokio::spawn(
+		async move {
+			loop {
+				match read_message(&mut reader).await {
+					Ok(Some(message)) => {
+						if tx.send(message).is_err() {
+							break;
+						}
+					}
+					Ok(None) => break,
+					Err(e) => {
+						warn!(error = %e, "Cannot read the message");
+						break;
+					}
+				}
+			}
+		}
+		.in_current_span(),
+	);
+	(rx, task)
+}
+
+/// Compares the tokens in the time which doesn't depend on the position of the first difference
+fn tokens_match(a: &str, b: &str) -> bool {
+	a.len() == b.len()
+		&& a.bytes()
+			.zip(b.bytes())
+			.fold(0, |diff, (x, y)| diff | (x ^ y))
+			== 0
+}
+
+/// What the bot learns from the agents
+pub enum AgentEvent {
+	Connected {
+		host: String,
+	},
+	/// The activities found by the last check of the agent
+	Report {
+		host: String,
+		activities: Vec<ProcessDescriptionWithPid>,
+	},
+	/// The activities of the host are unknown until the agent connects again
+	Disconnected {
+		host: String,
+	},
+}
+
+/// What the bot asks the agent for
+enum Command {
+	Stop(ProcessId, oneshot::Sender<usize>),
+	Watch(Vec<ProcessId>),
+}
+
+struct Connection {
+	/// Tells a connection from the one which has replaced it
+	id: u64,
+	commands: mpsc::UnboundedSender<Command>,
+}
+
+#[derive(Default)]
+struct Agents {
+	connected: HashMap<String, Connection>,
+	/// The watched roots by the host, they are sent again when the agent reconnects
+	watched: HashMap<String, HashSet<ProcessId>>,
+	last_id: u64,
+}
+
+/// Talks to the connected agents
+#[derive(Clone, Default)]
+pub struct AgentsHandle {
+	agents: Arc<Mutex<Agents>>,
+}
+
+impl AgentsHandle {
+	/// Asks the agent to terminate the processes of the activity. Returns how many of them have been asked,
+	/// None if the agent is not connected or doesn't answer
+	pub async fn stop(&self, host: &str, root: ProcessId) -> Option<usize> {
+		let (tx, rx) = oneshot::channel();
+		self.agents
+			.lock()
+			.unwrap()
+			.connected
+			.get(host)?
+			.commands
+			.send(Command::Stop(root, tx))
+			.ok()?;
+		match tokio::time::timeout(STOP_TIMEOUT, rx).await {
+			Ok(count) => count.ok(),
+			Err(_) => {
+				warn!(host, %root, "The agent has not answered the stop request");
+				None
+			}
+		}
+	}
+
+	/// Sets the subscribed activities of every host, the agents watch the exits of their processes
+	pub fn watch(&self, roots: HashMap<String, HashSet<ProcessId>>) {
+		let mut agents = self.agents.lock().unwrap();
+		for (host, connection) in agents.connected.iter() {
+			if roots.get(host) != agents.watched.get(host) {
+				let roots = roots.get(host).into_iter().flatten().copied().collect();
+				connection.commands.send(Command::Watch(roots)).ok();
+			}
+		}
+		agents.watched = roots;
+	}
+
+	/// The new connection of the agent replaces the older one
+	fn register(&self, host: &str, commands: mpsc::UnboundedSender<Command>) -> u64 {
+		let mut agents = self.agents.lock().unwrap();
+		agents.last_id += 1;
+		let id = agents.last_id;
+		if let Some(roots) = agents.watched.get(host) {
+			let roots = roots.iter().copied().collect();
+			commands.send(Command::Watch(roots)).ok();
+		}
+		agents
+			.connected
+			.insert(host.to_owned(), Connection { id, commands });
+		id
+	}
+
+	/// Returns false if the connection has already been replaced
+	fn unregister(&self, host: &str, id: u64) -> bool {
+		let mut agents = self.agents.lock().unwrap();
+		if agents.connected.get(host).is_some_and(|c| c.id == id) {
+			agents.connected.remove(host);
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// Accepts the connections of the agents
+pub struct AgentServer {
+	listener: TcpListener,
+	token: Arc<str>,
+	agents: AgentsHandle,
+	events: mpsc::UnboundedSender<AgentEvent>,
+}
+
+/// Why the connection task has woken up
+enum Wake<M, C> {
+	Message(Option<M>),
+	Command(Option<C>),
+}
+
+impl AgentServer {
+	pub async fn bind(
+		config: &AgentsConfig,
+		events: mpsc::UnboundedSender<AgentEvent>,
+	) -> io::Result<(Self, AgentsHandle)> {
+		let listener = TcpListener::bind(config.listen).await?;
+		let agents = AgentsHandle::default();
+		let server = Self {
+			listener,
+			token: config.token.as_str().into(),
+			agents: agents.clone(),
+			events,
+		};
+		Ok((server, agents))
+	}
+
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.listener.local_addr()
+	}
+
+	/// Stops when the bot drops the event channel
+	pub async fn run(self) {
+		loop {
+			let accepted = {
+				let accept = self.listener.accept().fuse();
+				let closed = self.events.closed().fuse();
+				pin_mut!(accept, closed);
+				select! {
+					accepted = accept => accepted,
+					_ = closed => break,
+				}
+			};
+			match accepted {
+				Ok((stream, addr)) => {
+					let span = tracing::info_span!("agent", %addr);
+					tokio::spawn(
+						serve(
+							stream,
+							self.token.clone(),
+							self.agents.clone(),
+							self.events.clone(),
+						)
+						.instrument(span),
+					);
+				}
+				Err(e) => {
+					warn!(error = %e, "Cannot accept the agent connection");
+					// The descriptors may have run out, the error repeats at once
+					tokio::time::sleep(Duration::from_millis(100)).await;
+				}
+			}
+		}
+		debug!("The agent server has stopped");
+	}
+}
+
+/// Checks the greeting of the agent and returns its host label
+async fn authenticate(
+	reader: &mut BufReader<OwnedReadHalf>,
+	writer: &mut OwnedWriteHalf,
+	expected_token: &str,
+) -> Option<String> {
+	let hello = tokio::time::timeout(HELLO_TIMEOUT, read_message(reader)).await;
+	let (version, host, token) = match hello {
+		Ok(Ok(Some(AgentMessage::Hello {
+			version,
+			host,
+			token,
+		}))) => (version, host, token),
+		Ok(Ok(Some(_))) => {
+			warn!("The agent hasn't introduced itself");
+			return None;
+		}
+		Ok(Ok(None)) => return None,
+		Ok(Err(e)) => {
+			warn!(error = %e, "Cannot read the greeting of the agent");
+			return None;
+		}
+		Err(_) => {
+			warn!("The agent hasn't introduced itself in time");
+			return None;
+		}
+	};
+	let reason = if version != PROTOCOL_VERSION {
+		Some(format!("unsupported protocol version {}", version))
+	} else if !tokens_match(&token, expected_token) {
+		Some("wrong token".to_owned())
+	} else if host.is_empty() || host.len() > MAX_HOST_LEN {
+		Some(format!("invalid host label \"{}\"", host))
+	} else {
+		None
+	};
+	if let Some(reason) = reason {
+		warn!(host, reason, "The agent is rejected");
+		write_message(writer, &ServerMessage::Rejected { reason })
+			.await
+			.ok();
+		return None;
+	}
+	write_message(writer, &ServerMessage::Welcome).await.ok()?;
+	Some(host)
+}
+
+/// Passes the reports of the agent to the bot and the commands of the bot to the agent
+async fn serve(
+	stream: TcpStream,
+	token: Arc<str>,
+	agents: AgentsHandle,
+	events: mpsc::UnboundedSender<AgentEvent>,
+) {
+	let (reader, mut writer) = stream.into_split();
+	let mut reader = BufReader::new(reader);
+	let Some(host) = authenticate(&mut reader, &mut writer, &token).await else {
+		return;
+	};
+	let (commands_tx, mut commands) = mpsc::unbounded_channel();
+	let id = agents.register(&host, commands_tx);
+	info!(host, "Agent connected");
+	events
+		.send(AgentEvent::Connected { host: host.clone() })
+		.ok();
+
+	let (mut messages, reader_task) = spawn_reader(reader);
+	let mut pending: HashMap<u64, oneshot::Sender<usize>> = HashMap::new();
+	let mut last_request = 0;
+	loop {
+		let wake = {
+			let message = messages.recv().fuse();
+			let command = commands.recv().fuse();
+			pin_mut!(message, command);
+			select! {
+				message = message => Wake::Message(message),
+				command = command => Wake::Command(command),
+			}
+		};
+		// The requests given up by the bot are not answered
+		pending.retain(|_, reply| !reply.is_closed());
+		let res = match wake {
+			Wake::Message(Some(AgentMessage::Report { activities })) => {
+				debug!(host, activities = activities.len(), "Agent report");
+				let report = AgentEvent::Report {
+					host: host.clone(),
+					activities,
+				};
+				if events.send(report).is_err() {
+					break;
+				}
+				Ok(())
+			}
+			Wake::Message(Some(AgentMessage::Stopped { request, count })) => {
+				if let Some(reply) = pending.remove(&request) {
+					reply.send(count).ok();
+				}
+				Ok(())
+			}
+			Wake::Message(Some(AgentMessage::Hello { .. })) => {
+				warn!(host, "The agent has introduced itself again");
+				Ok(())
+			}
+			Wake::Message(None) => break,
+			Wake::Command(Some(Command::Stop(root, reply))) => {
+				last_request += 1;
+				pending.insert(last_request, reply);
+				let stop = ServerMessage::Stop {
+					request: last_request,
+					root,
+				};
+				write_message(&mut writer, &stop).await
+			}
+			Wake::Command(Some(Command::Watch(roots))) => {
+				write_message(&mut writer, &ServerMessage::Watch { roots }).await
+			}
+			// Another connection of the agent has replaced this one
+			Wake::Command(None) => break,
+		};
+		if let Err(e) = res {
+			warn!(host, error = %e, "Cannot write to the agent");
+			break;
+		}
+	}
+	reader_task.abort();
+	if agents.unregister(&host, id) {
+		info!(host, "Agent disconnected");
+		events.send(AgentEvent::Disconnected { host }).ok();
+	}
+}
+
+/// Reports the activities of this machine to the bot and stops them on request.
+/// Connects again when the connection is lost
+pub struct Agent {
+	config: AgentConfig,
+	monitor: MonitorHandle,
+	snapshots: mpsc::UnboundedReceiver<Snapshot>,
+	/// The monitor stops when the settings are dropped
+	_settings: watch::Sender<AgentConfig>,
+	/// The number of the last reported snapshot, the older ones are outdated
+	last_seq: u64,
+}
+
+impl Agent {
+	/// Starts the monitor of the processes, must be called inside the runtime
+	pub fn start(config: AgentConfig, tracker: ActivityTracker) -> Self {
+		let (settings, settings_rx) = watch::channel(config.clone());
+		let (snapshots_tx, snapshots) = mpsc::unbounded_channel();
+		let (monitor, handle) = Monitor::new(tracker, settings_rx, snapshots_tx);
+		tokio::spawn(monitor.run());
+		Self {
+			config,
+			monitor: handle,
+			snapshots,
+			_settings: settings,
+			last_seq: 0,
+		}
+	}
+
+	pub async fn run(mut self) {
+		loop {
+			match self.connect().await {
+				Ok(()) => info!("The bot has closed the connection"),
+				Err(e) => warn!(server = self.config.server, error = %e, "Agent connection failed"),
+			}
+			tokio::time::sleep(RECONNECT_DELAY).await;
+		}
+	}
+
+	async fn connect(&mut self) -> io::Result<()> {
+		let stream = TcpStream::connect(&self.config.server).await?;
+		let (reader, mut writer) = stream.into_split();
+		let mut reader = BufReader::new(reader);
+		let hello = AgentMessage::Hello {
+			version: PROTOCOL_VERSION,
+			host: self.config.host.clone(),
+			token: self.config.token.clone(),
+		};
+		write_message(&mut writer, &hello).await?;
+		match tokio::time::timeout(HELLO_TIMEOUT, read_message(&mut reader)).await {
+			Ok(Ok(Some(ServerMessage::Welcome))) => {}
+			Ok(Ok(Some(ServerMessage::Rejected { reason }))) => {
+				return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+			}
+			Ok(Ok(Some(_))) => {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					"unexpected greeting",
+				))
+			}
+			Ok(Ok(None)) => return Err(io::ErrorKind::UnexpectedEof.into()),
+			Ok(Err(e)) => return Err(e),
+			Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+		}
+		info!(server = self.config.server, "Connected to the bot");
+
+		// The bot learns the current activities at once, the checks made while disconnected are outdated
+		while self.snapshots.try_recv().is_ok() {}
+		let snapshot = self.monitor.scan().await.ok_or_else(monitor_stopped)?;
+		self.report(&mut writer, snapshot).await?;
+
+		let (messages, reader_task) = spawn_reader(reader);
+		let res = self.exchange(&mut writer, messages).await;
+		reader_task.abort();
+		res
+	}
+
+	async fn exchange(
+		&mut self,
+		writer: &mut OwnedWriteHalf,
+		mut messages: mpsc::UnboundedReceiver<ServerMessage>,
+	) -> io::Result<()> {
+		loop {
+			let wake = {
+				let message = messages.recv().fuse();
+				let snapshot = self.snapshots.recv().fuse();
+				pin_mut!(message, snapshot);
+				select! {
+					message = message => Wake::Message(message),
+					snapshot = snapshot => Wake::Command(snapshot),
+				}
+			};
+			match wake {
+				Wake::Command(Some(snapshot)) => self.report(writer, snapshot).await?,
+				Wake::Command(None) => return Err(monitor_stopped()),
+				Wake::Message(Some(ServerMessage::Stop { request, root })) => {
+					let count = self.monitor.stop(root).await.unwrap_or(0);
+					info!(%root, count, "Activity stopped by the bot");
+					write_message(writer, &AgentMessage::Stopped { request, count }).await?;
+				}
+				Wake::Message(Some(ServerMessage::Watch { roots })) => {
+					self.monitor.watch(roots.into_iter().collect());
+				}
+				Wake::Message(Some(_)) => warn!("Unexpected message from the bot"),
+				Wake::Message(None) => return Ok(()),
+			}
+		}
+	}
+
+	async fn report(&mut self, writer: &mut OwnedWriteHalf, snapshot: Snapshot) -> io::Result<()> {
+		if snapshot.seq <= self.last_seq {
+			return Ok(());
+		}
+		self.last_seq = snapshot.seq;
+		let report = AgentMessage::Report {
+			activities: snapshot.activities.to_vec(),
+		};
+		write_message(writer, &report).await
+	}
+}
+
+fn monitor_stopped() -> io::Error {
+	io::Error::other("the monitor has stopped")
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::activity::{ProcessDescription, ProcessInfo, ScriptedSource};
+	use crate::logging::LogConfig;
+
+	#[test]
+	fn test_tokens_match() {
+		assert!(tokens_match("secret", "secret"));
+		assert!(!tokens_match("secret", "secreT"));
+		assert!(!tokens_match("secret", "secret2"));
+		assert!(tokens_match("", ""));
+	}
+
+	fn runtime() -> tokio::runtime::Runtime {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+	}
+
+	#[test]
+	fn test_stop_timeout() {
+		runtime().block_on(async {
+			tokio::time::pause();
+			let agents = AgentsHandle::default();
+			let (commands_tx, mut commands) = mpsc::unbounded_channel();
+			agents.register("build1", commands_tx);
+			let root = ProcessId {
+				pid: sysinfo::Pid::from_u32(100),
+				start_time: 10,
+			};
+			assert_eq!(agents.stop("build1", root).await, None);
+			// The connection forgets the request then
+			match commands.recv().await {
+				Some(Command::Stop(stopped, reply)) => {
+					assert_eq!(stopped, root);
+					assert!(reply.is_closed());
+				}
+				_ => panic!("No stop request"),
+			}
+			assert_eq!(agents.stop("build2", root).await, None);
+		});
+	}
+
+	async fn start_server() -> (
+		SocketAddr,
+		AgentsHandle,
+		mpsc::UnboundedReceiver<AgentEvent>,
+	) {
+		let config = AgentsConfig {
+			listen: "127.0.0.1:0".parse().unwrap(),
+			token: "secret".to_owned(),
+			host: "main".to_owned(),
+		};
+		let (events_tx, events) = mpsc::unbounded_channel();
+		let (server, agents) = AgentServer::bind(&config, events_tx).await.unwrap();
+		let addr = server.local_addr().unwrap();
+		tokio::spawn(server.run());
+		(addr, agents, events)
+	}
+
+	async fn next_event(events: &mut mpsc::UnboundedReceiver<AgentEvent>) -> AgentEvent {
+		tokio::time::timeout(Duration::from_secs(5), events.recv())
+			.await
+			.unwrap()
+			.unwrap()
+	}
+
+	#[test]
+	fn test_agent() {
+		runtime().block_on(async {
+			let (addr, agents, mut events) = start_server().await;
+			let source = ScriptedSource::default();
+			let build = ProcessInfo {
+				pid: sysinfo::Pid::from_u32(100),
+				start_time: 10,
+				parent: None,
+				name: "qtcreator_ctrlc_stub".to_owned(),
+				cmd: ["stub", "--build", "/b"].map(String::from).to_vec(),
+				cwd: None,
+				cpu_usage: 0.0,
+				memory: 0,
+				disk_io: 0,
+				cpu_time: None,
+			};
+			source.set(vec![build.clone()]);
+			let config = AgentConfig {
+				server: addr.to_string(),
+				token: "secret".to_owned(),
+				host: "build1".to_owned(),
+				check_interval: Duration::from_secs(3600),
+				watch_processes: false,
+				log: LogConfig::default(),
+			};
+			let agent = Agent::start(
+				config,
+				ActivityTracker::with_source(Box::new(source.clone())),
+			);
+			tokio::spawn(agent.run());
+
+			assert!(matches!(
+				next_event(&mut events).await,
+				AgentEvent::Connected { host } if host == "build1"
+			));
+			let AgentEvent::Report { host, activities } = next_event(&mut events).await else {
+				panic!("no report");
+			};
+			assert_eq!(host, "build1");
+			assert_eq!(activities.len(), 1);
+			assert_eq!(activities[0].id(), &build.id());
+			assert_eq!(activities[0].description(), Some("/b"));
+
+			assert_eq!(agents.stop("build1", build.id()).await, Some(1));
+			assert_eq!(agents.stop("build1", build.id()).await, Some(0));
+			assert_eq!(agents.stop("build2", build.id()).await, None);
+		});
+	}
+
+	#[test]
+	fn test_wrong_token() {
+		runtime().block_on(async {
+			let (addr, _agents, mut events) = start_server().await;
+			let stream = TcpStream::connect(addr).await.unwrap();
+			let (reader, mut writer) = stream.into_split();
+			let hello = AgentMessage::Hello {
+				version: PROTOCOL_VERSION,
+				host: "build1".to_owned(),
+				token: "guess".to_owned(),
+			};
+			write_message(&mut writer, &hello).await.unwrap();
+			let answer = read_message(&mut BufReader::new(reader)).await.unwrap();
+			assert!(matches!(
+				answer,
+				Some(ServerMessage::Rejected { reason }) if reason == "wrong token"
+			));
+			assert!(events.try_recv().is_err());
+		});
+	}
+}
@@ -0,0 +1,1346 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use teloxide::types::{ChatId, MediaKind, Message, MessageKind, User, UserId};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, warn, Instrument};
+
+use crate::activity::{
+	self, ActivityId, ActivityKind, ProcessDescription, ProcessDescriptionWithPid, ResourceUsage,
+};
+use crate::agent::{AgentEvent, AgentsHandle};
+use crate::cleaner::CleanerRequest;
+use crate::history::{History, HistoryEntry};
+use crate::i18n::Lang;
+use crate::monitor::{MonitorHandle, Snapshot};
+use crate::msg_storage::MessageCategory;
+use crate::notifier::{Outbox, SharedMessages};
+use crate::preferences::{self, AllPreferences, Languages, Preferences, QuietHours, QuietMode};
+use crate::storage::SharedStorage;
+use crate::subscriptions::{self, AllActions};
+use crate::telegram::Button;
+use crate::{config, duration};
+
+/// Number of the completed activities kept in the history
+const HISTORY_SIZE: usize = 1000;
+/// Number of the history entries shown by the /history command
+const HISTORY_SHOWN: usize = 10;
+/// Telegram limit of the message text in characters
+const MAX_MESSAGE_LEN: usize = 4096;
+/// The longest age accepted by /clean, no tracked message is older
+const MAX_CLEAN_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+#[derive(PartialEq, Eq)]
+enum Request {
+	Help,
+	Subscribe,
+	History,
+	Status,
+	/// Delete the messages older than the age, all of them if the age is absent
+	Clean(Option<std::time::Duration>),
+	Stats,
+	/// Show, set or remove the quiet hours. The arguments keep their case for the timezone
+	Quiet(Vec<String>),
+	/// Show or set the language, "auto" follows Telegram
+	Language(Option<String>),
+	Unknown(String),
+}
+
+impl From<&str> for Request {
+	fn from(command: &str) -> Request {
+		let mut vs: Vec<_> = command
+			.split_ascii_whitespace()
+			.filter_map(|s| {
+				if s.is_empty() {
+					None
+				} else {
+					Some(s.to_ascii_lowercase())
+				}
+			})
+			.collect();
+
+		if vs.is_empty() {
+			return Request::Unknown(command.to_string());
+		}
+
+		vs[0] = vs[0].chars().skip_while(|c| *c == '/').collect();
+
+		if vs[0] == "help" {
+			return Request::Help;
+		}
+		if vs[0] == "subscribe" {
+			return Request::Subscribe;
+		}
+		if vs[0] == "history" {
+			return Request::History;
+		}
+		if vs[0] == "status" {
+			return Request::Status;
+		}
+		if vs[0] == "stats" {
+			return Request::Stats;
+		}
+		if vs[0] == "language" {
+			return Request::Language(vs.get(1).cloned());
+		}
+		if vs[0] == "quiet" {
+			let args = command.split_ascii_whitespace().skip(1);
+			return Request::Quiet(args.map(|s| s.to_owned()).collect());
+		}
+		if vs[0] == "clean" {
+			let age = vs[1..].concat();
+			if age.is_empty() {
+				return Request::Clean(None);
+			}
+			if let Some(age) = duration::parse_duration(&age).filter(|age| *age <= MAX_CLEAN_AGE) {
+				return Request::Clean(Some(age));
+			}
+		}
+		Request::Unknown(command.to_string())
+	}
+}
+
+fn get_string_help(lang: Lang) -> String {
+	lang.text("help").to_owned()
+}
+
+fn format_path(path: &Option<String>, lang: Lang) -> String {
+	match path {
+		Some(s) => lang.format("path", &[("path", s)]),
+		None => String::new(),
+	}
+}
+
+/// The language of the messages to the user
+fn user_lang(preferences: &AllPreferences, user_id: &UserId) -> Lang {
+	preferences
+		.get(user_id)
+		.map(Preferences::lang)
+		.unwrap_or_default()
+}
+
+/// "[host] " before the notifications if the bot watches several machines, this machine has the empty host
+fn host_label(agents: &Option<config::AgentsConfig>, host: &str) -> String {
+	match agents {
+		Some(agents) if host.is_empty() => format!("[{}] ", agents.host),
+		Some(_) => format!("[{}] ", host),
+		None => String::new(),
+	}
+}
+
+fn format_history_entry(entry: &HistoryEntry, label: &str, lang: Lang) -> String {
+	let duration = (entry.finished - entry.started)
+		.to_std()
+		.unwrap_or_default();
+	let mut res = format!(
+		"{}{}{}: {} - {} ({})",
+		label,
+		lang.kind(&entry.kind),
+		format_path(&entry.description, lang),
+		entry.started.format("%d.%m %H:%M"),
+		entry.finished.format("%H:%M"),
+		duration::format_duration(&duration)
+	);
+	if let Some(usage) = &entry.usage {
+		res += &format!(", {}", format_resource_usage(usage, lang));
+	}
+	res
+}
+
+/// Joins the parts into as few messages as Telegram allows, the header starts the first one
+fn join_messages(header: &str, parts: &[String], separator: &str) -> Vec<String> {
+	let mut res = Vec::new();
+	let mut current = header.to_owned();
+	for part in parts {
+		let len = current.chars().count();
+		if len > 0 && len + separator.len() + part.chars().count() > MAX_MESSAGE_LEN {
+			res.push(std::mem::take(&mut current));
+		} else if !current.is_empty() {
+			current += separator;
+		}
+		current += part;
+	}
+	if !current.is_empty() {
+		res.push(current);
+	}
+	res
+}
+
+fn format_memory(bytes: u64) -> String {
+	const MIB: u64 = 1024 * 1024;
+	if bytes >= 1024 * MIB {
+		format!("{:.1} GiB", bytes as f64 / (1024 * MIB) as f64)
+	} else {
+		format!("{} MiB", bytes / MIB)
+	}
+}
+
+/// Resources used by the whole process tree of the activity
+pub fn format_usage(action: &impl ProcessDescription, lang: Lang) -> String {
+	lang.format(
+		"usage",
+		&[
+			("children", &action.children()),
+			("cpu", &format!("{:.0}", action.cpu_usage())),
+			("memory", &format_memory(action.memory())),
+		],
+	)
+}
+
+/// Resources used by the activity over its whole run
+fn format_resource_usage(usage: &ResourceUsage, lang: Lang) -> String {
+	let mut cpu_time = duration::format_duration(&usage.cpu_time);
+	if !usage.cpu_time_measured {
+		cpu_time = lang.format("estimated", &[("value", &cpu_time)]);
+	}
+	lang.format(
+		"resource_usage",
+		&[
+			("memory", &format_memory(usage.peak_memory)),
+			("cpu", &format!("{:.0}", usage.average_cpu())),
+			("cpu_time", &cpu_time),
+		],
+	)
+}
+
+/// The answers to the alert about a hung activity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StallAction {
+	Keep,
+	Stop,
+}
+
+impl StallAction {
+	fn name(self) -> &'static str {
+		match self {
+			StallAction::Keep => "keep",
+			StallAction::Stop => "stop",
+		}
+	}
+
+	/// The data of the button is "<action>:<pid>:<start time>", followed by ":<host>" for the agents
+	fn button(self, text: &str, id: &ActivityId) -> Button {
+		let mut data = format!("{}:{}:{}", self.name(), id.root.pid, id.root.start_time);
+		if !id.is_local() {
+			data += &format!(":{}", id.host);
+		}
+		Button {
+			text: text.to_owned(),
+			data,
+		}
+	}
+
+	fn parse(data: &str) -> Option<(Self, ActivityId)> {
+		let mut parts = data.splitn(4, ':');
+		let action = match parts.next()? {
+			"keep" => StallAction::Keep,
+			"stop" => StallAction::Stop,
+			_ => return None,
+		};
+		let pid = parts.next()?.parse().ok()?;
+		let start_time = parts.next()?.parse().ok()?;
+		let host = match parts.next() {
+			Some("") => return None,
+			host => host.unwrap_or_default(),
+		};
+		let id = ActivityId {
+			host: host.to_owned(),
+			root: activity::ProcessId { pid, start_time },
+		};
+		Some((action, id))
+	}
+}
+
+/// An activity reported as hung
+struct Stall {
+	/// The idle time of the activity when the alert was sent
+	alerted: Duration,
+	/// The subscribers have chosen to keep waiting, the alert is repeated if the activity stays idle
+	rearmed: bool,
+}
+
+/// An activity seen by the bot, whoever is subscribed to it
+struct RunningActivity {
+	kind: activity::ActivityKind,
+	description: Option<String>,
+	started: chrono::DateTime<chrono::Utc>,
+	/// As of the last check
+	usage: ResourceUsage,
+}
+
+/// The notifications to a chat waiting for the end of the digest window
+struct Pending {
+	messages: Vec<String>,
+	since: std::time::Instant,
+	/// All of them are sent during the quiet hours
+	silent: bool,
+}
+
+/// The last report of an agent
+struct RemoteHost {
+	activities: Vec<ProcessDescriptionWithPid>,
+	/// The activities of a disconnected agent are considered running until it reconnects
+	connected: bool,
+}
+
+/// The channels from the bot to the other tasks
+pub struct Links {
+	pub outbox: Outbox,
+	pub monitor: MonitorHandle,
+	pub cleaner: mpsc::UnboundedSender<CleanerRequest>,
+	/// None if the bot watches this machine only
+	pub agents: Option<AgentsHandle>,
+	/// Publishes the reloaded configuration
+	pub config: watch::Sender<config::Config>,
+	/// Publishes the languages of the users changed by them
+	pub languages: watch::Sender<Languages>,
+}
+
+/// State of the bot and the handlers of the Telegram messages and the snapshots of the processes.
+/// The messages are sent, the processes are scanned and the old messages are deleted by the other tasks
+pub struct BotData {
+	pub config: config::Config,
+	config_watcher: config::ConfigWatcher,
+	links: Links,
+
+	subscribers: AllActions,
+	preferences: AllPreferences,
+	/// The notifications held during the quiet hours of the users, lost on restart
+	held: HashMap<UserId, Vec<(MessageCategory, String)>>,
+	/// The notifications to merge, used if the digest window is set
+	pending: HashMap<UserId, Pending>,
+	/// The daily digest is due if its time has come since this check
+	daily_checked: chrono::DateTime<chrono::Utc>,
+
+	storage: SharedStorage,
+	msg_storage: SharedMessages,
+	history: History,
+	/// The activities of this machine found by the last check
+	local: Arc<[ProcessDescriptionWithPid]>,
+	/// The activities of the other machines by the host label
+	remote: BTreeMap<String, RemoteHost>,
+	running: HashMap<ActivityId, RunningActivity>,
+	stalls: HashMap<ActivityId, Stall>,
+	/// Running time of the activities when they were last reported to exceed `max_duration`
+	overtime: HashMap<ActivityId, Duration>,
+	/// Number of the newest handled snapshot, the older ones are outdated
+	last_seq: u64,
+}
+
+impl BotData {
+	/// Loads the state saved before the restart
+	pub fn new(
+		config: config::Config,
+		config_watcher: config::ConfigWatcher,
+		storage: SharedStorage,
+		msg_storage: SharedMessages,
+		links: Links,
+	) -> Self {
+		let res = Self {
+			subscribers: subscriptions::load(&*storage),
+			preferences: preferences::load(&*storage),
+			held: HashMap::new(),
+			pending: HashMap::new(),
+			daily_checked: chrono::Utc::now(),
+			config,
+			config_watcher,
+			links,
+			msg_storage,
+			history: History::new(storage.clone(), HISTORY_SIZE),
+			local: Arc::new([]),
+			remote: BTreeMap::new(),
+			running: HashMap::new(),
+			stalls: HashMap::new(),
+			overtime: HashMap::new(),
+			last_seq: 0,
+			storage,
+		};
+		res.links
+			.languages
+			.send_replace(preferences::languages(&res.preferences));
+		res.watch_subscribed();
+		res
+	}
+
+	/// Remembers the message to delete it later and handles the text commands
+	pub async fn process_update_message(&mut self, message: Message) {
+		let chat_id = message.chat.id;
+		let msg_id = message.id.0;
+		let span = tracing::info_span!("poll", %chat_id, msg_id);
+
+		{
+			let mut msg_storage = self.msg_storage.lock().unwrap();
+			msg_storage.add_message((chat_id, msg_id), MessageCategory::Command);
+			if let MessageKind::Pinned(pinned) = &message.kind {
+				// The pinned message stays in the chat until it is unpinned by hand
+				let pinned_id = (chat_id, pinned.pinned.id.0);
+				if msg_storage.set_category(&pinned_id, MessageCategory::Pinned) {
+					debug!(parent: &span, pinned_id = pinned_id.1, "The message is pinned");
+				}
+			}
+		}
+		if let MessageKind::Common(msg_common) = message.kind {
+			if let MediaKind::Text(media_text) = msg_common.media_kind {
+				if let Some(user) = msg_common.from {
+					self.process_message(&media_text.text, &user, chat_id)
+						.instrument(span)
+						.await
+				}
+			}
+		}
+	}
+
+	/// Writes the changed messages to the storage
+	pub fn flush(&mut self) {
+		self.msg_storage.lock().unwrap().flush();
+	}
+
+	/// The exits of the processes of the subscribed activities are watched by the monitor and the agents
+	fn watch_subscribed(&self) {
+		let mut local = HashSet::new();
+		let mut remote: HashMap<String, HashSet<activity::ProcessId>> = HashMap::new();
+		for id in self.subscribers.values().flat_map(|actions| actions.keys()) {
+			if id.is_local() {
+				local.insert(id.root);
+			} else {
+				remote.entry(id.host.clone()).or_default().insert(id.root);
+			}
+		}
+		self.links.monitor.watch(local);
+		if let Some(agents) = &self.links.agents {
+			agents.watch(remote);
+		}
+	}
+
+	/// The activities of all the machines, the disconnected agents included
+	fn current_actions(&self) -> Vec<ProcessDescriptionWithPid> {
+		let remote = self.remote.values().flat_map(|host| host.activities.iter());
+		self.local.iter().chain(remote).cloned().collect()
+	}
+
+	/// Subscribes the chat to the current activities
+	fn subscribe(
+		&mut self,
+		chat_id: UserId,
+		act_list: &[ProcessDescriptionWithPid],
+	) -> Option<String> {
+		if let Some(elem) = act_list.first() {
+			let lang = self.lang(chat_id);
+			// There is at least one element
+			let mut msg = if act_list.len() == 1 {
+				lang.format(
+					"current_action",
+					&[
+						("label", &host_label(&self.config.agents, elem.host())),
+						("kind", &lang.kind(elem.activity_kind())),
+						("usage", &format_usage(elem, lang)),
+					],
+				)
+			} else {
+				lang.text("several_actions").to_owned()
+			};
+			msg += "\n";
+			msg += lang.text("will_notify");
+
+			let h: std::collections::HashMap<_, _> = act_list
+				.iter()
+				.map(|a| {
+					(
+						a.activity_id(),
+						(
+							a.activity_kind().clone(),
+							a.description().map(|x| x.to_owned()),
+						),
+					)
+				})
+				.collect();
+
+			// The completed actions are kept until their completion is reported
+			let actions = self.subscribers.entry(chat_id).or_default();
+			let len = actions.len();
+			actions.extend(h);
+			// The auto subscription repeats on every check, the storage is written only for the new actions
+			if actions.len() != len {
+				subscriptions::save(&*self.storage, &self.subscribers);
+				self.watch_subscribed();
+			}
+
+			Some(msg)
+		} else {
+			None
+		}
+	}
+
+	/// Handles the command of the user. The replies go to the private chat of the user,
+	/// except /clean which cleans and replies to `message_chat` where the command has been sent
+	pub async fn process_message(&mut self, msg: &str, chat: &User, message_chat: ChatId) {
+		debug!(user_id = %chat.id, text = msg, "Received a message");
+		let chat_id = ChatId(chat.id.0 as i64);
+		if !self.config.is_allowed(chat.id) {
+			warn!(user_id = %chat.id, "The user is not allowed to use the bot");
+			let lang = chat.language_code.as_deref().and_then(Lang::from_code);
+			self.links.outbox.send(
+				chat_id,
+				lang.unwrap_or_default().text("not_allowed"),
+				MessageCategory::Reply,
+			);
+			return;
+		}
+		self.remember_language(chat);
+		let lang = self.lang(chat.id);
+
+		let request_type = Request::from(msg);
+		let category = match request_type {
+			Request::Help => MessageCategory::Help,
+			_ => MessageCategory::Reply,
+		};
+		let s = match request_type {
+			Request::Help => get_string_help(lang),
+
+			Request::Subscribe => {
+				// The activity may have started after the last check
+				let s = match self.links.monitor.scan().await {
+					Some(snapshot) => {
+						// The checks scanned before are outdated
+						self.last_seq = self.last_seq.max(snapshot.seq);
+						self.local = snapshot.activities;
+						let current_actions = self.current_actions();
+						self.subscribe(chat.id, &current_actions)
+					}
+					None => None,
+				};
+				info!(user_id = %chat.id, subscribed = s.is_some(), "Subscribe request");
+				s.unwrap_or_else(|| lang.text("no_current_action").to_owned())
+			}
+
+			Request::History => self.get_string_history(lang),
+
+			Request::Status => self.get_string_status(lang),
+
+			// The cleaner replies itself
+			Request::Stats => {
+				self.request_cleaner(CleanerRequest::Stats { chat_id, lang });
+				return;
+			}
+
+			Request::Clean(age) => {
+				self.request_cleaner(CleanerRequest::Clean {
+					chat_id: message_chat,
+					age,
+					lang,
+				});
+				return;
+			}
+
+			Request::Quiet(args) => {
+				let s = self.set_quiet_hours(chat.id, &args);
+				self.links.outbox.send(chat_id, s, category);
+				// The quiet hours may have been removed
+				self.deliver_digests();
+				return;
+			}
+
+			Request::Language(code) => self.set_language(chat.id, code.as_deref()),
+
+			Request::Unknown(_) => {
+				let help = get_string_help(lang);
+				lang.format("unknown_command", &[("command", &msg), ("help", &help)])
+			}
+		};
+		self.links.outbox.send(chat_id, s, category)
+	}
+
+	/// Handles the buttons of the alerts about the hung activities
+	pub async fn process_button(&mut self, data: &str, user: &User) {
+		debug!(user_id = %user.id, data, "Button pressed");
+		let chat_id = ChatId(user.id.0 as i64);
+		if self.config.is_allowed(user.id) {
+			self.remember_language(user);
+		}
+		let lang = self.lang(user.id);
+		let Some((action, id)) = StallAction::parse(data) else {
+			warn!(user_id = %user.id, data, "Unknown button");
+			return;
+		};
+		// Only the subscribers of the activity may stop it
+		let is_subscribed = self
+			.subscribers
+			.get(&user.id)
+			.is_some_and(|actions| actions.contains_key(&id));
+		if !self.config.is_allowed(user.id) || !is_subscribed {
+			warn!(user_id = %user.id, %id, ?action, "The user is not subscribed to the action");
+			self.links
+				.outbox
+				.send(chat_id, lang.text("not_subscribed"), MessageCategory::Reply);
+			return;
+		}
+
+		info!(user_id = %user.id, %id, ?action, "Stalled action");
+		let s = match action {
+			StallAction::Keep => {
+				if let Some(stall) = self.stalls.get_mut(&id) {
+					stall.rearmed = true;
+				}
+				lang.text("keep_waiting").to_owned()
+			}
+			StallAction::Stop => {
+				let count = if id.is_local() {
+					self.links.monitor.stop(id.root).await
+				} else {
+					match &self.links.agents {
+						Some(agents) => agents.stop(&id.host, id.root).await,
+						None => None,
+					}
+				};
+				match count {
+					Some(0) => lang.text("already_completed").to_owned(),
+					Some(count) => lang.format("asked_to_stop", &[("count", &count)]),
+					None if id.is_local() => lang.text("already_completed").to_owned(),
+					None => lang.format("agent_not_connected", &[("host", &id.host)]),
+				}
+			}
+		};
+		self.links.outbox.send(chat_id, s, MessageCategory::Reply);
+	}
+
+	fn request_cleaner(&self, request: CleanerRequest) {
+		if let Err(e) = self.links.cleaner.send(request) {
+			warn!(request = ?e.0, "The cleaner has stopped");
+		}
+	}
+
+	/// Reports the new and completed activities found by the scan of this machine
+	#[tracing::instrument(name = "check", skip_all, fields(seq = snapshot.seq))]
+	pub fn process_check(&mut self, snapshot: Snapshot) {
+		if snapshot.seq <= self.last_seq {
+			debug!("The snapshot is outdated");
+			return;
+		}
+		self.last_seq = snapshot.seq;
+
+		if self.config_watcher.is_changed() {
+			self.reload(false);
+		}
+
+		self.local = snapshot.activities;
+		self.check();
+	}
+
+	/// Handles the reports of the agents and the changes of their connections
+	#[tracing::instrument(name = "agent", skip_all)]
+	pub fn process_agent_event(&mut self, event: AgentEvent) {
+		let owner_chat = ChatId(self.config.owner_id.0 as i64);
+		let lang = self.lang(self.config.owner_id);
+		match event {
+			AgentEvent::Connected { host } => {
+				// A new agent is noticed by its activities, only the reconnections are reported
+				if self
+					.remote
+					.get(&host)
+					.is_some_and(|remote| !remote.connected)
+				{
+					self.links.outbox.send(
+						owner_chat,
+						lang.format("agent_reconnected", &[("host", &host)]),
+						MessageCategory::Notification,
+					);
+				}
+			}
+			AgentEvent::Report { host, activities } => {
+				let activities = activities.into_iter().map(|a| a.with_host(&host)).collect();
+				let remote = RemoteHost {
+					activities,
+					connected: true,
+				};
+				self.remote.insert(host, remote);
+				self.check();
+			}
+			AgentEvent::Disconnected { host } => {
+				if let Some(remote) = self.remote.get_mut(&host) {
+					remote.connected = false;
+				}
+				self.links.outbox.send(
+					owner_chat,
+					lang.format("agent_lost", &[("host", &host)]),
+					MessageCategory::Failure,
+				);
+				self.flush();
+			}
+		}
+	}
+
+	/// Reports the new and completed activities of all the machines
+	fn check(&mut self) {
+		let current_actions = self.current_actions();
+		self.process_auto_subscribe(&current_actions);
+		self.process_stalls(&current_actions);
+
+		let pid_list_new: HashSet<_> = current_actions.iter().map(|a| a.activity_id()).collect();
+		let usage = self.update_history(&current_actions);
+		self.process_overtime();
+
+		let mut msg_list = Vec::new();
+
+		for (chat, actions) in self.subscribers.iter_mut() {
+			assert_ne!(actions.len(), 0);
+
+			// List of completed actions
+			let completed_list: Vec<_> = actions
+				.keys()
+				.filter(|id| !pid_list_new.contains(*id))
+				.cloned()
+				.collect();
+			for pid in completed_list {
+				if let Some(act) = actions.get(&pid) {
+					info!(user_id = %chat, %pid, kind = %act.0, "Action completed");
+					let lang = user_lang(&self.preferences, chat);
+					let mut msg = lang.format(
+						"completed",
+						&[
+							("label", &host_label(&self.config.agents, &pid.host)),
+							("kind", &lang.kind(&act.0)),
+							("path", &format_path(&act.1, lang)),
+						],
+					);
+					// Unknown if the activity has completed before its first check
+					if let Some(usage) = usage.get(&pid) {
+						let usage = format_resource_usage(usage, lang);
+						msg += "\n";
+						msg += &lang.format("used", &[("usage", &usage)]);
+					}
+					msg_list.push((*chat, act.0.clone(), msg));
+				}
+				actions.remove(&pid);
+			}
+		}
+		let is_changed = !msg_list.is_empty();
+		for (user_id, kind, msg) in msg_list {
+			self.notify(
+				user_id,
+				&kind,
+				MessageCategory::Notification,
+				msg,
+				Vec::new(),
+			);
+		}
+		self.subscribers.retain(|_, actions| !actions.is_empty());
+		if is_changed {
+			subscriptions::save(&*self.storage, &self.subscribers);
+			self.watch_subscribed();
+		}
+
+		self.send_pending(false);
+		self.deliver_digests();
+		self.process_daily_digest();
+		// The messages are saved once per check, not on every change
+		self.flush();
+	}
+
+	/// Remembers the new activities and moves the completed ones to the history.
+	/// Returns the resources used by the completed activities
+	fn update_history(
+		&mut self,
+		current_actions: &[ProcessDescriptionWithPid],
+	) -> HashMap<ActivityId, ResourceUsage> {
+		let now = chrono::Utc::now();
+		let current: HashSet<_> = current_actions.iter().map(|a| a.activity_id()).collect();
+		let completed: Vec<_> = self
+			.running
+			.keys()
+			.filter(|id| !current.contains(id))
+			.cloned()
+			.collect();
+		let mut usage = HashMap::new();
+		for id in completed {
+			let act = self.running.remove(&id).unwrap();
+			info!(%id, kind = %act.kind, usage = ?act.usage, "Resource usage");
+			usage.insert(id.clone(), act.usage);
+			self.history.add(HistoryEntry {
+				host: id.host,
+				kind: act.kind,
+				description: act.description,
+				started: act.started,
+				finished: now,
+				usage: Some(act.usage),
+			});
+		}
+		for a in current_actions {
+			self.running
+				.entry(a.activity_id())
+				.or_insert_with(|| RunningActivity {
+					kind: a.activity_kind().clone(),
+					description: a.description().map(|x| x.to_owned()),
+					started: now,
+					usage: ResourceUsage::default(),
+				})
+				.usage = *a.usage();
+		}
+		usage
+	}
+
+	/// Warns the subscribers of the activities running longer than `max_duration`.
+	/// The warning is repeated every `max_duration.repeat` if it is set
+	fn process_overtime(&mut self) {
+		let now = chrono::Utc::now();
+		self.overtime.retain(|id, _| self.running.contains_key(id));
+		let mut msg_list = Vec::new();
+		for (id, act) in self.running.iter() {
+			let max_duration = &self.config.max_duration;
+			let Some(limit) = max_duration.get(&act.kind, act.description.as_deref()) else {
+				continue;
+			};
+			let elapsed = (now - act.started).to_std().unwrap_or_default();
+			let is_due = match self.overtime.get(id) {
+				None => elapsed >= limit,
+				Some(warned) => max_duration
+					.repeat
+					.is_some_and(|repeat| elapsed >= *warned + repeat),
+			};
+			if !is_due {
+				continue;
+			}
+			warn!(%id, kind = %act.kind, ?elapsed, ?limit, "The action runs too long");
+			self.overtime.insert(id.clone(), elapsed);
+
+			for (user_id, actions) in self.subscribers.iter() {
+				if !actions.contains_key(id) {
+					continue;
+				}
+				let lang = user_lang(&self.preferences, user_id);
+				let msg = lang.format(
+					"overtime",
+					&[
+						("label", &host_label(&self.config.agents, &id.host)),
+						("kind", &lang.kind(&act.kind)),
+						("path", &format_path(&act.description, lang)),
+						("elapsed", &duration::format_duration(&elapsed)),
+						("limit", &duration::format_duration(&limit)),
+					],
+				);
+				msg_list.push((*user_id, act.kind.clone(), msg));
+			}
+		}
+		for (user_id, kind, msg) in msg_list {
+			self.notify(user_id, &kind, MessageCategory::Failure, msg, Vec::new());
+		}
+	}
+
+	/// Alerts the subscribers of the activities which have been idle for `stall_timeout`
+	fn process_stalls(&mut self, current_actions: &[ProcessDescriptionWithPid]) {
+		let current: HashSet<_> = current_actions.iter().map(|a| a.activity_id()).collect();
+		self.stalls.retain(|id, _| current.contains(id));
+		let Some(stall_timeout) = self.config.stall_timeout else {
+			return;
+		};
+
+		for action in current_actions {
+			let id = action.activity_id();
+			let idle = action.idle();
+			// The activity has worked since the alert
+			if self
+				.stalls
+				.get(&id)
+				.is_some_and(|stall| idle < stall.alerted)
+			{
+				self.stalls.remove(&id);
+			}
+			let is_due = match self.stalls.get(&id) {
+				None => idle >= stall_timeout,
+				Some(stall) => stall.rearmed && idle >= stall.alerted + stall_timeout,
+			};
+			if !is_due {
+				continue;
+			}
+			warn!(%id, kind = %action.activity_kind(), ?idle, "The action seems to hang");
+			self.stalls.insert(
+				id.clone(),
+				Stall {
+					alerted: idle,
+					rearmed: false,
+				},
+			);
+
+			let users: Vec<_> = self
+				.subscribers
+				.iter()
+				.filter(|(_, actions)| actions.contains_key(&id))
+				.map(|(user_id, _)| *user_id)
+				.collect();
+			for user_id in users {
+				let lang = self.lang(user_id);
+				let msg = lang.format(
+					"stall",
+					&[
+						("label", &host_label(&self.config.agents, &id.host)),
+						("kind", &lang.kind(action.activity_kind())),
+						(
+							"path",
+							&format_path(&action.description().map(|x| x.to_owned()), lang),
+						),
+						("idle", &duration::format_duration(&idle)),
+					],
+				);
+				let buttons = vec![
+					StallAction::Keep.button(lang.text("button.keep"), &id),
+					StallAction::Stop.button(lang.text("button.stop"), &id),
+				];
+				self.notify(
+					user_id,
+					action.activity_kind(),
+					MessageCategory::Failure,
+					msg,
+					buttons,
+				);
+			}
+		}
+	}
+
+	/// The language of the messages to the user
+	fn lang(&self, user_id: UserId) -> Lang {
+		user_lang(&self.preferences, &user_id)
+	}
+
+	/// Follows the language of the Telegram client of the user, it is used unless /language is set
+	fn remember_language(&mut self, user: &User) {
+		let telegram_language = user.language_code.as_deref().and_then(Lang::from_code);
+		let current = self
+			.preferences
+			.get(&user.id)
+			.and_then(|p| p.telegram_language);
+		if telegram_language == current {
+			return;
+		}
+		debug!(user_id = %user.id, ?telegram_language, "Telegram language changed");
+		self.preferences
+			.entry(user.id)
+			.or_default()
+			.telegram_language = telegram_language;
+		self.save_preferences();
+	}
+
+	/// Drops the default preferences, saves the rest and publishes the languages
+	fn save_preferences(&mut self) {
+		self.preferences.retain(|_, p| *p != Preferences::default());
+		preferences::save(&*self.storage, &self.preferences);
+		self.links
+			.languages
+			.send_replace(preferences::languages(&self.preferences));
+	}
+
+	/// Handles /language: shows the language without the argument, "auto" follows Telegram
+	fn set_language(&mut self, user_id: UserId, code: Option<&str>) -> String {
+		let language = match code {
+			None => self.preferences.get(&user_id).and_then(|p| p.language),
+			Some(code) if code.eq_ignore_ascii_case("auto") => None,
+			Some(code) => match Lang::from_code(code) {
+				Some(lang) => Some(lang),
+				None => {
+					let languages: Vec<_> = Lang::ALL.iter().map(|lang| lang.code()).collect();
+					return self.lang(user_id).format(
+						"unknown_language",
+						&[("value", &code), ("languages", &languages.join(", "))],
+					);
+				}
+			},
+		};
+		if code.is_some() {
+			info!(%user_id, ?language, "Language");
+			self.preferences.entry(user_id).or_default().language = language;
+			self.save_preferences();
+		}
+		let lang = self.lang(user_id);
+		let key = if language.is_some() {
+			"language"
+		} else {
+			"language_auto"
+		};
+		lang.format(key, &[("language", &lang.text("language.name"))])
+	}
+
+	/// Sends the notification about an activity of the kind, the quiet hours of the user decide how.
+	/// The category is `Notification` or `Failure` for the alerts about the problems
+	fn notify(
+		&mut self,
+		user_id: UserId,
+		kind: &ActivityKind,
+		category: MessageCategory,
+		msg: String,
+		buttons: Vec<Button>,
+	) {
+		let quiet = self
+			.preferences
+			.get(&user_id)
+			.and_then(|p| p.quiet_now(chrono::Utc::now()));
+		match quiet.map(|q| q.mode(kind)) {
+			None => self.send_notification(user_id, msg, category, buttons, false),
+			Some(QuietMode::Silent) => {
+				self.send_notification(user_id, msg, category, buttons, true)
+			}
+			// The buttons are outdated by the end of the quiet hours
+			Some(QuietMode::Digest) => self.held.entry(user_id).or_default().push((category, msg)),
+			Some(QuietMode::Drop) => debug!(%user_id, %kind, "The notification is dropped"),
+		}
+	}
+
+	/// Sends the notification or keeps it to merge with the next ones.
+	/// The alerts with the buttons and the failures kept longer than the notifications are not merged
+	fn send_notification(
+		&mut self,
+		user_id: UserId,
+		msg: String,
+		category: MessageCategory,
+		buttons: Vec<Button>,
+		silent: bool,
+	) {
+		let mergeable = buttons.is_empty() && category == MessageCategory::Notification;
+		if self.config.digest.window.is_some() && mergeable {
+			let pending = self.pending.entry(user_id).or_insert_with(|| Pending {
+				messages: Vec::new(),
+				since: std::time::Instant::now(),
+				silent: true,
+			});
+			pending.messages.push(msg);
+			pending.silent &= silent;
+			return;
+		}
+		let chat_id = ChatId(user_id.0 as i64);
+		if silent {
+			self.links
+				.outbox
+				.send_silently(chat_id, msg, category, buttons);
+		} else {
+			self.links
+				.outbox
+				.send_with_buttons(chat_id, msg, category, buttons);
+		}
+	}
+
+	/// Sends the merged notifications whose digest window has passed, all of them if `force` is set.
+	/// The window is counted in the checks, so it lasts until the first check after it
+	fn send_pending(&mut self, force: bool) {
+		let window = self.config.digest.window.unwrap_or_default();
+		let due: Vec<_> = self
+			.pending
+			.iter()
+			.filter(|(_, pending)| force || pending.since.elapsed() >= window)
+			.map(|(user_id, _)| *user_id)
+			.collect();
+		for user_id in due {
+			let pending = self.pending.remove(&user_id).unwrap();
+			let header = match pending.messages.len() {
+				1 => String::new(),
+				count => self
+					.lang(user_id)
+					.format("notifications", &[("count", &count)]),
+			};
+			let chat_id = ChatId(user_id.0 as i64);
+			for msg in join_messages(&header, &pending.messages, "\n\n") {
+				if pending.silent {
+					self.links.outbox.send_silently(
+						chat_id,
+						msg,
+						MessageCategory::Notification,
+						Vec::new(),
+					);
+				} else {
+					self.links
+						.outbox
+						.send(chat_id, msg, MessageCategory::Notification);
+				}
+			}
+		}
+	}
+
+	/// Sends the owner the actions completed during the day at the `digest.daily` time
+	fn process_daily_digest(&mut self) {
+		let now = chrono::Utc::now();
+		let last_checked = std::mem::replace(&mut self.daily_checked, now);
+		let Some(daily) = &self.config.digest.daily else {
+			return;
+		};
+		let scheduled = daily.last_before(now);
+		if scheduled <= last_checked {
+			return;
+		}
+		let since = scheduled - chrono::Duration::days(1);
+		let lang = self.lang(self.config.owner_id);
+		let entries: Vec<_> = self
+			.history
+			.entries()
+			.filter(|entry| entry.finished > since)
+			.map(|entry| {
+				let label = host_label(&self.config.agents, &entry.host);
+				format_history_entry(entry, &label, lang)
+			})
+			.collect();
+		let mut header = match entries.len() {
+			0 => lang.text("daily_none").to_owned(),
+			count => lang.format("daily", &[("count", &count)]),
+		};
+		let running = self.current_actions().len();
+		if running > 0 {
+			header += &lang.format("daily_running", &[("count", &running)]);
+		}
+		info!(completed = entries.len(), running, "Daily digest");
+		let owner_chat = ChatId(self.config.owner_id.0 as i64);
+		for msg in join_messages(&header, &entries, "\n") {
+			self.links
+				.outbox
+				.send(owner_chat, msg, MessageCategory::Notification);
+		}
+	}
+
+	/// Sends the held notifications to the users whose quiet hours have ended
+	fn deliver_digests(&mut self) {
+		let now = chrono::Utc::now();
+		let ended: Vec<_> = self
+			.held
+			.keys()
+			.filter(|user_id| {
+				self.preferences
+					.get(user_id)
+					.and_then(|p| p.quiet_now(now))
+					.is_none()
+			})
+			.copied()
+			.collect();
+		for user_id in ended {
+			let held = self.held.remove(&user_id).unwrap_or_default();
+			info!(%user_id, count = held.len(), "Quiet hours digest");
+			// The digest with a failure is kept as long as the failure
+			let category = if held.iter().any(|(c, _)| *c == MessageCategory::Failure) {
+				MessageCategory::Failure
+			} else {
+				MessageCategory::Notification
+			};
+			let held: Vec<_> = held.into_iter().map(|(_, msg)| msg).collect();
+			let header = self.lang(user_id).text("quiet_digest");
+			for msg in join_messages(header, &held, "\n\n") {
+				self.links
+					.outbox
+					.send(ChatId(user_id.0 as i64), msg, category);
+			}
+		}
+	}
+
+	/// Handles /quiet: shows the quiet hours without the arguments, removes them with "off"
+	fn set_quiet_hours(&mut self, user_id: UserId, args: &[String]) -> String {
+		let lang = self.lang(user_id);
+		let quiet_hours = match args {
+			[] => {
+				let quiet = self
+					.preferences
+					.get(&user_id)
+					.and_then(|p| p.quiet_hours.as_ref());
+				return match quiet {
+					Some(quiet) => lang.format("quiet_hours", &[("quiet", quiet)]),
+					None => lang.text("no_quiet_hours").to_owned(),
+				};
+			}
+			[off] if off.eq_ignore_ascii_case("off") => None,
+			args => match QuietHours::parse(args) {
+				Ok(quiet) => Some(quiet),
+				Err(e) => return format!("{}. \n{}", e.describe(lang), get_string_help(lang)),
+			},
+		};
+		info!(%user_id, quiet_hours = ?quiet_hours.as_ref().map(|q| q.to_string()), "Quiet hours");
+		let s = match &quiet_hours {
+			Some(quiet) => lang.format("quiet_hours", &[("quiet", quiet)]),
+			None => lang.text("quiet_removed").to_owned(),
+		};
+		self.preferences.entry(user_id).or_default().quiet_hours = quiet_hours;
+		self.save_preferences();
+		s
+	}
+
+	fn get_string_history(&self, lang: Lang) -> String {
+		let entries: Vec<_> = self
+			.history
+			.entries()
+			.rev()
+			.take(HISTORY_SHOWN)
+			.map(|entry| {
+				let label = host_label(&self.config.agents, &entry.host);
+				format_history_entry(entry, &label, lang)
+			})
+			.collect();
+		if entries.is_empty() {
+			lang.text("no_history").to_owned()
+		} else {
+			format!("{}\n{}", lang.text("history"), entries.join("\n"))
+		}
+	}
+
+	/// The running activities grouped by the machine
+	fn get_string_status(&self, lang: Lang) -> String {
+		let format_list = |activities: &[ProcessDescriptionWithPid]| -> Vec<String> {
+			activities
+				.iter()
+				.map(|a| {
+					format!(
+						"{}{}: {}",
+						lang.kind(a.activity_kind()),
+						format_path(&a.description().map(|x| x.to_owned()), lang),
+						format_usage(a, lang)
+					)
+				})
+				.collect()
+		};
+		let Some(agents) = &self.config.agents else {
+			let list = format_list(&self.local);
+			if list.is_empty() {
+				return lang.text("no_current_action").to_owned();
+			}
+			return format!("{}\n{}", lang.text("running_actions"), list.join("\n"));
+		};
+
+		let mut hosts = vec![(agents.host.as_str(), &self.local[..], true)];
+		hosts.extend(
+			self.remote
+				.iter()
+				.map(|(host, remote)| (host.as_str(), &remote.activities[..], remote.connected)),
+		);
+		let mut res = Vec::new();
+		for (host, activities, connected) in hosts {
+			let mut s = host.to_owned();
+			if !connected {
+				s += lang.text("host_disconnected");
+			}
+			let list = format_list(activities);
+			if list.is_empty() {
+				s += lang.text("host_idle");
+			} else {
+				s += ":";
+				for line in list {
+					s += "\n  ";
+					s += &line;
+				}
+			}
+			res.push(s);
+		}
+		res.join("\n")
+	}
+
+	fn process_auto_subscribe(&mut self, current_actions: &[ProcessDescriptionWithPid]) {
+		if !self.config.auto_subscribe {
+			return;
+		}
+
+		let owner_id = self.config.owner_id;
+		let lang = self.lang(owner_id);
+		let current_subscribers = self.subscribers.get(&owner_id);
+		let mut msg_list = Vec::new();
+		for action in current_actions {
+			let id = action.activity_id();
+			if !current_subscribers.is_some_and(|actions| actions.contains_key(&id)) {
+				info!(pid = %id, kind = %action.activity_kind(), "New action");
+				let msg = lang.format(
+					"new_action",
+					&[
+						("label", &host_label(&self.config.agents, &id.host)),
+						("kind", &lang.kind(action.activity_kind())),
+						("path", &action.description().unwrap_or("")),
+					],
+				);
+				msg_list.push((action.activity_kind(), msg));
+			}
+		}
+		for (kind, msg) in msg_list {
+			self.notify(
+				owner_id,
+				kind,
+				MessageCategory::Notification,
+				msg,
+				Vec::new(),
+			);
+		}
+
+		self.subscribe(self.config.owner_id, current_actions);
+	}
+
+	/// Re-reads the config file on request (SIGHUP) and applies the changed settings.
+	/// The result is reported to the owner, even if nothing has changed
+	pub fn reload_config(&mut self) {
+		self.reload(true);
+	}
+
+	/// The reload caused by the changed modification time of the file reports only the changes and the errors
+	#[tracing::instrument(name = "reload", skip_all)]
+	fn reload(&mut self, report_unchanged: bool) {
+		let lang = self.lang(self.config.owner_id);
+		let (msg, category) = match config::read_config(self.config_watcher.path()) {
+			Ok(mut new_config) => {
+				let changes = config::describe_changes(&self.config, &new_config);
+				if changes.is_empty() {
+					debug!("The configuration is not changed");
+					if report_unchanged {
+						self.links.outbox.send(
+							ChatId(self.config.owner_id.0 as i64),
+							lang.text("config_unchanged"),
+							MessageCategory::Notification,
+						);
+					}
+					return;
+				}
+				info!(?changes, "Configuration reloaded");
+				// The bot can't switch to another token or agent address on the fly
+				new_config.token = self.config.token.clone();
+				new_config.agents = self.config.agents.clone();
+				self.config = new_config;
+				self.links.config.send_replace(self.config.clone());
+				(
+					format!("{}\n{}", lang.text("config_reloaded"), changes.join("\n")),
+					MessageCategory::Notification,
+				)
+			}
+			Err(e) => {
+				warn!(error = %e, "Configuration reload failed");
+				(
+					lang.format("config_reload_failed", &[("error", &e)]),
+					MessageCategory::Failure,
+				)
+			}
+		};
+		self.links
+			.outbox
+			.send(ChatId(self.config.owner_id.0 as i64), msg, category);
+	}
+
+	/// Tells the subscribers which actions are not watched until the restart and the owner that the bot is stopping.
+	/// The subscriptions stay saved to resume after the restart
+	#[tracing::instrument(name = "shutdown", skip_all)]
+	pub fn notify_shutdown(&mut self) {
+		self.send_pending(true);
+		let owner_id = self.config.owner_id;
+		let owner_notified = self.subscribers.contains_key(&owner_id);
+		for (user_id, actions) in self.subscribers.iter() {
+			let lang = self.lang(*user_id);
+			let mut msg = lang.text("stopping_watched").to_owned();
+			for (id, (kind, path)) in actions.iter() {
+				let label = host_label(&self.config.agents, &id.host);
+				msg += &format!("\n{}{}{}", label, lang.kind(kind), format_path(path, lang));
+			}
+			self.links
+				.outbox
+				.send(ChatId(user_id.0 as i64), msg, MessageCategory::Startup);
+		}
+		if !owner_notified {
+			self.links.outbox.send(
+				ChatId(owner_id.0 as i64),
+				self.lang(owner_id).text("stopping"),
+				MessageCategory::Startup,
+			);
+		}
+	}
+
+	/// Tells the owner that the bot has started
+	pub fn notify_startup(&self) {
+		let owner_id = self.config.owner_id;
+		self.links.outbox.send(
+			ChatId(owner_id.0 as i64),
+			self.lang(owner_id).text("started"),
+			MessageCategory::Startup,
+		);
+	}
+}
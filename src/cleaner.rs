@@ -0,0 +1,288 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures::{pin_mut, select, FutureExt};
+use teloxide::types::{ChatId, MessageId, UserId};
+use teloxide::{ApiError, RequestError};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, warn};
+
+use crate::config::{self, Config};
+use crate::deletion::{DeleteFailure, DeleteStats};
+use crate::i18n::Lang;
+use crate::logging;
+use crate::msg_storage::MessageCategory;
+use crate::notifier::{Outbox, SharedMessages};
+use crate::preferences::Languages;
+use crate::telegram::Sender;
+
+/// Longer flood waits asked by Telegram stop the deletion, the rest of the messages wait for the next pass
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// The commands handled by the cleaner, the replies go to the chat through the outbox
+#[derive(Debug)]
+pub enum CleanerRequest {
+	/// Delete the messages of the chat older than the age, all of them if the age is absent
+	Clean {
+		chat_id: ChatId,
+		age: Option<Duration>,
+		lang: Lang,
+	},
+	Stats {
+		chat_id: ChatId,
+		lang: Lang,
+	},
+}
+
+/// Deletes the old messages by the cleanup timer and on request. The pauses between the deletions
+/// don't hold the other tasks
+pub struct Cleaner<S: Sender> {
+	api: S,
+	messages: SharedMessages,
+	config: watch::Receiver<Config>,
+	/// The placeholders of the undeletable messages are written in the languages of the chats
+	languages: watch::Receiver<Languages>,
+	outbox: Outbox,
+	/// Results of the message deletions since the start
+	stats: DeleteStats,
+}
+
+impl<S: Sender> Cleaner<S> {
+	pub fn new(
+		api: S,
+		messages: SharedMessages,
+		config: watch::Receiver<Config>,
+		languages: watch::Receiver<Languages>,
+		outbox: Outbox,
+	) -> Self {
+		Self {
+			api,
+			messages,
+			config,
+			languages,
+			outbox,
+			stats: DeleteStats::default(),
+		}
+	}
+
+	/// Stops when the request channel is closed
+	pub async fn run(mut self, mut requests: mpsc::UnboundedReceiver<CleanerRequest>) {
+		let mut timer = tokio::time::interval(self.config.borrow().cleanup_interval);
+		loop {
+			// The interval may be changed by the config reload
+			let cleanup_interval = self.config.borrow().cleanup_interval;
+			if timer.period() != cleanup_interval {
+				timer = tokio::time::interval_at(
+					tokio::time::Instant::now() + cleanup_interval,
+					cleanup_interval,
+				);
+			}
+
+			let request = {
+				let tick = timer.tick().fuse();
+				let request = requests.recv().fuse();
+				let config_changed = self.config.changed().fuse();
+				pin_mut!(tick, request, config_changed);
+				select! {
+					_ = tick => None,
+					request = request => match request {
+						Some(request) => Some(request),
+						None => break,
+					},
+					changed = config_changed => match changed {
+						Ok(()) => continue,
+						// The bot has stopped
+						Err(_) => break,
+					},
+				}
+			};
+			match request {
+				None => self.delete_old_messages().await,
+				Some(CleanerRequest::Clean { chat_id, age, lang }) => {
+					let s = self.clean(chat_id, age, lang).await;
+					self.outbox.send(chat_id, s, MessageCategory::Reply);
+				}
+				Some(CleanerRequest::Stats { chat_id, lang }) => {
+					self.outbox
+						.send(chat_id, self.get_string_stats(lang), MessageCategory::Reply);
+				}
+			}
+		}
+		debug!("The cleaner has stopped");
+	}
+
+	/// Deletes the chat messages older than the age right away, except the pinned ones
+	#[tracing::instrument(name = "clean", skip_all, fields(%chat_id))]
+	async fn clean(&mut self, chat_id: ChatId, age: Option<Duration>, lang: Lang) -> String {
+		let messages: Vec<_> = {
+			let storage = self.messages.lock().unwrap();
+			storage
+				.get_old_messages(&age.unwrap_or_default())
+				.into_iter()
+				.filter(|id| {
+					id.0 == chat_id && storage.category(id) != Some(MessageCategory::Pinned)
+				})
+				.collect()
+		};
+		if messages.is_empty() {
+			return lang.text("clean.nothing").to_owned();
+		}
+		let stats = self.delete_messages(messages).await;
+		info!(?stats, "Chat cleaned");
+		self.messages.lock().unwrap().flush();
+		let mut res = lang.format("clean.deleted", &[("count", &stats.removed())]);
+		if stats.replaced != 0 {
+			res += &lang.format("clean.replaced", &[("count", &stats.replaced)]);
+		}
+		if stats.failed() != 0 {
+			res += &lang.format("clean.failed", &[("count", &stats.failed())]);
+		}
+		res
+	}
+
+	#[tracing::instrument(name = "delete", skip_all)]
+	pub async fn delete_old_messages(&mut self) {
+		let old_msg = {
+			let config = self.config.borrow();
+			self.messages.lock().unwrap().get_expired_messages(
+				|(chat_id, _), category| match config.retention(chat_id, category) {
+					config::Retention::Never => None,
+					config::Retention::After(age) => Some(age),
+				},
+			)
+		};
+		debug!(count = old_msg.len(), "Deleting old messages");
+		let stats = self.delete_messages(old_msg).await;
+		info!(?stats, "Old messages deleted");
+		self.messages.lock().unwrap().flush();
+	}
+
+	/// Deletes the messages and forgets them. The bot's messages which are too old to delete are edited
+	/// to the placeholder. The messages failed for a temporary reason are kept to retry later
+	/// unless they are older than `undeletable_ttl`. The message is retried after the flood wait
+	/// asked by Telegram, a wait longer than `MAX_RETRY_AFTER` leaves the rest for the next pass
+	async fn delete_messages(&mut self, old_msg: Vec<(ChatId, i32)>) -> DeleteStats {
+		let now = chrono::Utc::now();
+		let mut stats = DeleteStats::default();
+		let mut forgotten = Vec::new();
+		let mut retried = HashSet::new();
+		let mut postponed = 0;
+		let count = old_msg.len();
+		for (i, (chat_id, msg_id)) in old_msg.into_iter().enumerate() {
+			if i != 0 {
+				let delete_pause = self.config.borrow().delete_pause;
+				tokio::time::sleep(delete_pause).await;
+			}
+			let mut res = self.api.delete_message(chat_id, MessageId(msg_id)).await;
+			while let Err(RequestError::RetryAfter(wait)) = res {
+				if wait > MAX_RETRY_AFTER {
+					break;
+				}
+				debug!(?wait, "Waiting to delete more messages");
+				tokio::time::sleep(wait).await;
+				res = self.api.delete_message(chat_id, MessageId(msg_id)).await;
+			}
+			let e = match res {
+				Ok(_) => {
+					stats.deleted += 1;
+					forgotten.push((chat_id, msg_id));
+					continue;
+				}
+				Err(RequestError::RetryAfter(wait)) => {
+					postponed = (count - i) as u64;
+					warn!(?wait, postponed, "Deletion postponed by the flood wait");
+					break;
+				}
+				Err(e) => e,
+			};
+
+			let id = (chat_id, msg_id);
+			let (is_own, age) = {
+				let storage = self.messages.lock().unwrap();
+				let is_own = storage.category(&id) != Some(MessageCategory::Command);
+				let age = storage
+					.date(&id)
+					.and_then(|date| (now - date).to_std().ok())
+					.unwrap_or_default();
+				(is_own, age)
+			};
+			let failure = DeleteFailure::classify(&e, is_own, age);
+			warn!(%chat_id, msg_id, ?failure, error = %logging::error_chain(&e), "Cannot delete the message");
+			match failure {
+				DeleteFailure::NotFound => stats.not_found += 1,
+				DeleteFailure::TooOld => {
+					if self.replace_with_placeholder(chat_id, msg_id).await {
+						stats.replaced += 1;
+					} else {
+						stats.no_rights += 1;
+					}
+				}
+				DeleteFailure::NoRights => stats.no_rights += 1,
+				DeleteFailure::Network | DeleteFailure::Other => {
+					retried.insert(id);
+					continue;
+				}
+			}
+			forgotten.push(id);
+		}
+
+		let mut storage = self.messages.lock().unwrap();
+		if !retried.is_empty() {
+			// Get a list of very old messages which can't be deleted anymore
+			let old_msg = storage.get_old_messages(&self.config.borrow().undeletable_ttl);
+			for v in old_msg.iter() {
+				if retried.remove(v) {
+					debug!(chat_id = %v.0, msg_id = v.1, "Giving up deleting the message");
+					stats.given_up += 1;
+					forgotten.push(*v);
+				}
+			}
+		}
+		stats.retried = retried.len() as u64 + postponed;
+		storage.remove_messages(forgotten);
+		self.stats += stats;
+		stats
+	}
+
+	/// Edits the message which can't be deleted. Returns false if it can't be edited either
+	async fn replace_with_placeholder(&self, chat_id: ChatId, msg_id: i32) -> bool {
+		let placeholder = self
+			.chat_lang(chat_id)
+			.text("deleted_placeholder")
+			.to_owned();
+		let res = self
+			.api
+			.edit_message_text(chat_id, MessageId(msg_id), placeholder)
+			.await;
+		match res {
+			Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => true,
+			Err(e) => {
+				warn!(%chat_id, msg_id, error = %logging::error_chain(&e), "Cannot replace the message");
+				false
+			}
+		}
+	}
+
+	/// The language of the user in a private chat, the owner's one in a group
+	fn chat_lang(&self, chat_id: ChatId) -> Lang {
+		let user_id = if chat_id.is_user() {
+			UserId(chat_id.0 as u64)
+		} else {
+			self.config.borrow().owner_id
+		};
+		self.languages
+			.borrow()
+			.get(&user_id)
+			.copied()
+			.unwrap_or_default()
+	}
+
+	fn get_string_stats(&self, lang: Lang) -> String {
+		let count = self.messages.lock().unwrap().len();
+		format!(
+			"{}\n{}",
+			lang.format("stats", &[("count", &count)]),
+			self.stats.describe(lang)
+		)
+	}
+}
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+/// SBIS build progress notification bot
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+	/// Path to the configuration file [default: config.ini next to the executable]
+	#[arg(long, value_name = "FILE")]
+	pub config: Option<PathBuf>,
+
+	/// Directory for the bot state files [default: the executable directory]
+	#[arg(long, value_name = "DIR")]
+	pub data_dir: Option<PathBuf>,
+
+	/// Run a single check cycle and exit instead of serving forever
+	#[arg(long)]
+	pub once: bool,
+
+	#[command(subcommand)]
+	pub command: Option<Command>,
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum Command {
+	/// Parse the configuration file, print the result and exit
+	CheckConfig,
+	/// Print the currently running activities and exit, without starting Telegram
+	ListActivities,
+	/// Report the activities of this machine to the bot given in the [agent] section
+	Agent,
+}
+
+fn exe_dir() -> PathBuf {
+	let mut path = std::env::current_exe().unwrap();
+	path.pop();
+	path
+}
+
+impl Cli {
+	pub fn config_path(&self) -> PathBuf {
+		self.config
+			.clone()
+			.unwrap_or_else(|| exe_dir().join("config.ini"))
+	}
+
+	pub fn data_dir(&self) -> PathBuf {
+		self.data_dir.clone().unwrap_or_else(exe_dir)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_cli() {
+		let cli = Cli::parse_from(["bot"]);
+		assert!(cli.command.is_none());
+		assert!(!cli.once);
+		assert!(cli.config_path().ends_with("config.ini"));
+
+		let cli = Cli::parse_from([
+			"bot",
+			"--config",
+			"/etc/bot/a.ini",
+			"--data-dir",
+			"/var/lib/bot",
+			"--once",
+		]);
+		assert_eq!(cli.config_path(), PathBuf::from("/etc/bot/a.ini"));
+		assert_eq!(cli.data_dir(), PathBuf::from("/var/lib/bot"));
+		assert!(cli.once);
+
+		let cli = Cli::parse_from(["bot", "--config", "b.ini", "check-config"]);
+		assert_eq!(cli.command, Some(Command::CheckConfig));
+
+		let cli = Cli::parse_from(["bot", "list-activities"]);
+		assert_eq!(cli.command, Some(Command::ListActivities));
+
+		let cli = Cli::parse_from(["bot", "agent"]);
+		assert_eq!(cli.command, Some(Command::Agent));
+	}
+}
@@ -0,0 +1,246 @@
+use std::path::Path;
+
+/// Removes the quotes around the argument, the Windows command lines often keep them
+pub fn unquote(arg: &str) -> &str {
+	let arg = arg.trim();
+	for quote in ['"', '\''] {
+		if let Some(s) = arg.strip_prefix(quote).and_then(|s| s.strip_suffix(quote)) {
+			return s;
+		}
+	}
+	arg
+}
+
+/// Value of the option written as "--name value" or "--name=value"
+pub fn option_value<'a>(cmd: &'a [String], name: &str) -> Option<&'a str> {
+	let mut args = cmd.iter().map(|arg| unquote(arg));
+	while let Some(arg) = args.next() {
+		if arg == name {
+			return args.next();
+		}
+		if let Some(value) = arg.strip_prefix(name).and_then(|s| s.strip_prefix('=')) {
+			return Some(unquote(value));
+		}
+	}
+	None
+}
+
+/// Splits the normalized path into the root ("/", "//" for UNC paths, "C:/" or "") and the rest
+fn split_root(path: &str) -> (&str, &str) {
+	if path.starts_with("//") {
+		return path.split_at(2);
+	}
+	if path.starts_with('/') {
+		return path.split_at(1);
+	}
+	let bytes = path.as_bytes();
+	if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+		let len = if bytes.get(2) == Some(&b'/') { 3 } else { 2 };
+		return path.split_at(len);
+	}
+	("", path)
+}
+
+/// True for "/", "C:/" and the other paths without components
+pub fn is_root(path: &str) -> bool {
+	let (root, rest) = split_root(path);
+	!root.is_empty() && rest.is_empty()
+}
+
+/// Brings a path from the command line of any platform to one form: the separators become '/',
+/// the quotes, "." and ".." are removed, the drive letter is upper-cased.
+/// A relative path is resolved against `cwd` if it is known
+pub fn normalize_path(path: &str, cwd: Option<&Path>) -> String {
+	let path = unquote(path).replace('\\', "/");
+	let (root, rest) = split_root(&path);
+	if root.is_empty() {
+		if let Some(cwd) = cwd {
+			let cwd = normalize_path(&cwd.to_string_lossy(), None);
+			if !split_root(&cwd).0.is_empty() {
+				return normalize_path(&format!("{}/{}", cwd, rest), None);
+			}
+		}
+	}
+
+	let mut components: Vec<&str> = Vec::new();
+	for c in rest.split('/') {
+		match c {
+			"" | "." => {}
+			".." => {
+				if components.last().is_some_and(|last| *last != "..") {
+					components.pop();
+				} else if root.is_empty() {
+					// A relative path may go above its start, the root has no parent
+					components.push(c);
+				}
+			}
+			c => components.push(c),
+		}
+	}
+
+	let root = match root.as_bytes() {
+		[drive, b':', ..] => format!("{}:/", drive.to_ascii_uppercase() as char),
+		_ => root.to_owned(),
+	};
+	if root.is_empty() && components.is_empty() {
+		return ".".to_owned();
+	}
+	root + &components.join("/")
+}
+
+/// Removes the trailing components of the normalized path: "C:/a/b/c" without "b/c" is "C:/a".
+/// Returns None if the path doesn't end with these components or consists of them only
+pub fn strip_components<'a>(path: &'a str, suffix: &str) -> Option<&'a str> {
+	let rest = path.strip_suffix(suffix)?;
+	if rest.is_empty() {
+		return None;
+	}
+	let (root, dir) = split_root(rest);
+	if dir.is_empty() {
+		// The components are right under the root
+		return (!root.is_empty()).then_some(root);
+	}
+	dir.strip_suffix('/').map(|_| &rest[..rest.len() - 1])
+}
+
+/// The last component of the normalized path
+pub fn file_name(path: &str) -> &str {
+	let (_, rest) = split_root(path);
+	rest.rsplit('/').next().unwrap_or(rest)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_unquote() {
+		assert_eq!(
+			unquote(r#""C:\Program Files\a.py""#),
+			r"C:\Program Files\a.py"
+		);
+		assert_eq!(unquote("'/opt/a b'"), "/opt/a b");
+		assert_eq!(unquote(" plain "), "plain");
+		assert_eq!(unquote(r#""unbalanced"#), r#""unbalanced"#);
+		assert_eq!(unquote(r#"""#), r#"""#);
+	}
+
+	#[test]
+	fn test_option_value() {
+		let cmd: Vec<String> = [
+			"tool",
+			"--store",
+			r#""C:\store""#,
+			"--logs_dir=/var/log/x",
+			"--last",
+		]
+		.iter()
+		.map(|s| s.to_string())
+		.collect();
+		assert_eq!(option_value(&cmd, "--store"), Some(r"C:\store"));
+		assert_eq!(option_value(&cmd, "--logs_dir"), Some("/var/log/x"));
+		assert_eq!(option_value(&cmd, "--last"), None);
+		assert_eq!(option_value(&cmd, "--logs"), None);
+		assert_eq!(option_value(&cmd, "--absent"), None);
+	}
+
+	#[test]
+	fn test_normalize_path() {
+		let cases = [
+			// Windows
+			(
+				r"C:\Saby\deploy2\config\test.s3deploy",
+				None,
+				"C:/Saby/deploy2/config/test.s3deploy",
+			),
+			(r"c:/Saby\deploy2\\logs\", None, "C:/Saby/deploy2/logs"),
+			(
+				r#""D:\work dir\.\repo\..\online-inside""#,
+				None,
+				"D:/work dir/online-inside",
+			),
+			(r"C:\..\a", None, "C:/a"),
+			(r"C:\", None, "C:/"),
+			(r"\\server\share\repo", None, "//server/share/repo"),
+			(
+				r"online-inside\update_to_revisions.py",
+				Some(r"C:\repo"),
+				"C:/repo/online-inside/update_to_revisions.py",
+			),
+			(r"..\build", Some(r"D:\work\repo"), "D:/work/build"),
+			// Linux
+			(
+				"/home/user/repo/online-inside/update_to_revisions.py",
+				None,
+				"/home/user/repo/online-inside/update_to_revisions.py",
+			),
+			("/home/user//repo/./build/", None, "/home/user/repo/build"),
+			("/../etc", None, "/etc"),
+			("'/opt/my builds/a'", None, "/opt/my builds/a"),
+			("build", Some("/home/user/repo"), "/home/user/repo/build"),
+			("../logs", Some("/srv/deploy/config"), "/srv/deploy/logs"),
+			(".", Some("/srv"), "/srv"),
+			// Relative without a known cwd
+			("build/./x", None, "build/x"),
+			("../a/../../b", None, "../../b"),
+			("a/..", None, "."),
+			("a", Some("relative/cwd"), "a"),
+		];
+		for (path, cwd, expected) in cases {
+			assert_eq!(
+				normalize_path(path, cwd.map(Path::new)),
+				expected,
+				"{}",
+				path
+			);
+		}
+	}
+
+	#[test]
+	fn test_strip_components() {
+		let suffix = "online-inside/update_to_revisions.py";
+		assert_eq!(
+			strip_components("C:/repo/online-inside/update_to_revisions.py", suffix),
+			Some("C:/repo")
+		);
+		assert_eq!(
+			strip_components("/repo/online-inside/update_to_revisions.py", suffix),
+			Some("/repo")
+		);
+		assert_eq!(
+			strip_components("C:/online-inside/update_to_revisions.py", suffix),
+			Some("C:/")
+		);
+		assert_eq!(
+			strip_components("/online-inside/update_to_revisions.py", suffix),
+			Some("/")
+		);
+		assert_eq!(
+			strip_components("/repo/my-online-inside/update_to_revisions.py", suffix),
+			None
+		);
+		assert_eq!(
+			strip_components("online-inside/update_to_revisions.py", suffix),
+			None
+		);
+		assert_eq!(
+			strip_components("/repo/update_to_revisions.py", suffix),
+			None
+		);
+	}
+
+	#[test]
+	fn test_is_root() {
+		assert!(is_root("/"));
+		assert!(is_root("C:/"));
+		assert!(!is_root("C:/a"));
+		assert!(!is_root(""));
+	}
+
+	#[test]
+	fn test_file_name() {
+		assert_eq!(file_name("C:/repo/a.py"), "a.py");
+		assert_eq!(file_name("a.py"), "a.py");
+		assert_eq!(file_name("/"), "");
+	}
+}
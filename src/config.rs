@@ -1,43 +1,689 @@
-use teloxide::types::UserId;
+use std::collections::HashMap;
+use std::time::Duration;
 
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use teloxide::types::{ChatId, UserId};
+
+use crate::activity::ActivityKind;
+use crate::cmdline::normalize_path;
+use crate::duration::{format_duration, parse_duration};
+use crate::logging::{LogConfig, LogOutput, LogRotation};
+use crate::msg_storage::MessageCategory;
+use crate::storage::StorageConfig;
+
+/// How long a message is kept in the chat
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+	Never,
+	After(Duration),
+}
+
+impl std::str::FromStr for Retention {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.trim() == "never" {
+			Ok(Retention::Never)
+		} else {
+			parse_duration(s).map(Retention::After).ok_or(())
+		}
+	}
+}
+
+impl std::fmt::Display for Retention {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Retention::Never => write!(f, "never"),
+			Retention::After(d) => write!(f, "{}", format_duration(d)),
+		}
+	}
+}
+
+/// Expected maximum durations of the activities, the [max_duration] section
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaxDuration {
+	pub kinds: HashMap<ActivityKind, Duration>,
+	/// Limits of the activities with the particular normalized paths, they win over the kind ones
+	pub paths: Vec<(String, Duration)>,
+	/// The warning is repeated at this interval while the activity runs. None sends it once
+	pub repeat: Option<Duration>,
+}
+
+impl MaxDuration {
+	/// The limit of the activity, None if it may run for any time
+	pub fn get(&self, kind: &ActivityKind, description: Option<&str>) -> Option<Duration> {
+		description
+			.and_then(|description| self.paths.iter().find(|(path, _)| path == description))
+			.map(|(_, limit)| *limit)
+			.or_else(|| self.kinds.get(kind).copied())
+	}
+}
+
+/// The time of the daily digest, the `daily` key of the [digest] section
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DailyDigest {
+	pub time: NaiveTime,
+	pub timezone: chrono_tz::Tz,
+}
+
+impl DailyDigest {
+	/// The latest time of the digest not after `now`
+	pub fn last_before(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+		let scheduled = |date: NaiveDate| {
+			let local = date.and_time(self.time);
+			self.timezone
+				.from_local_datetime(&local)
+				.earliest()
+				// The time is skipped by the DST change
+				.or_else(|| {
+					let later = local + chrono::Duration::hours(1);
+					self.timezone.from_local_datetime(&later).earliest()
+				})
+				.map_or_else(|| local.and_utc(), |t| t.to_utc())
+		};
+		let today = now.with_timezone(&self.timezone).date_naive();
+		let res = scheduled(today);
+		if res <= now {
+			res
+		} else {
+			scheduled(today.pred_opt().unwrap_or(today))
+		}
+	}
+}
+
+/// Merging the notifications, the [digest] section
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DigestConfig {
+	/// The notifications to a chat within this time are sent as one message, zero merges those of one check.
+	/// None sends every notification at once
+	pub window: Option<Duration>,
+	/// The owner gets the summary of the completed actions every day
+	pub daily: Option<DailyDigest>,
+}
+
+/// Longest host label, the label is a part of the button data limited by Telegram
+pub const MAX_HOST_LEN: usize = 32;
+
+/// The [agents] section: the bot accepts the activities found by the agents on the other machines
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgentsConfig {
+	pub listen: std::net::SocketAddr,
+	/// Shared by the bot and the agents
+	pub token: String,
+	/// Label of this machine in the notifications
+	pub host: String,
+}
+
+/// Settings of the agent mode: the [agent] section besides `check_interval`, `watch_processes` and [log]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgentConfig {
+	/// Address of the bot, "host:port"
+	pub server: String,
+	pub token: String,
+	/// Label of this machine in the notifications
+	pub host: String,
+	pub check_interval: Duration,
+	pub watch_processes: bool,
+	pub log: LogConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Config {
 	pub owner_id: UserId,
 	pub token: String,
 	pub auto_subscribe: bool,
+	/// Users allowed to talk to the bot besides the owner. Everyone is allowed if the list is empty
+	pub users: Vec<UserId>,
+
+	/// How often the running activities are checked
+	pub check_interval: Duration,
+	/// Whether the process starts and exits are watched between the checks (Linux only)
+	pub watch_processes: bool,
+	/// The subscribers are alerted when an activity uses no CPU and does no I/O for this time.
+	/// None disables the alerts
+	pub stall_timeout: Option<Duration>,
+	/// The subscribers are warned when an activity runs longer than expected
+	pub max_duration: MaxDuration,
+	pub digest: DigestConfig,
+	/// How often the old messages are deleted
+	pub cleanup_interval: Duration,
+	/// Pause between two message deletions
+	pub delete_pause: Duration,
+	/// Default message age after which the message is deleted
+	pub message_ttl: Retention,
+	/// Message age after which the bot stops trying to delete the message
+	pub undeletable_ttl: Duration,
+	/// Overrides of `message_ttl` for the particular chats, the [retention] section
+	pub chat_retention: HashMap<ChatId, Retention>,
+	/// Overrides of `message_ttl` for the message categories, the [category_ttl] section
+	pub category_ttl: HashMap<MessageCategory, Retention>,
+	/// Maximum time to notify the subscribers on exit
+	pub shutdown_timeout: Duration,
+
+	/// The [log] section
+	pub log: LogConfig,
+	/// Where the messages, subscriptions and history are kept
+	pub storage: StorageConfig,
+	/// None if the bot watches this machine only
+	pub agents: Option<AgentsConfig>,
+}
+
+impl Config {
+	pub fn is_allowed(&self, user: UserId) -> bool {
+		self.users.is_empty() || user == self.owner_id || self.users.contains(&user)
+	}
+
+	/// Pinned messages are never deleted. Otherwise the chat setting wins over the category one
+	pub fn retention(&self, chat_id: &ChatId, category: MessageCategory) -> Retention {
+		if category == MessageCategory::Pinned {
+			return Retention::Never;
+		}
+		*self
+			.chat_retention
+			.get(chat_id)
+			.or_else(|| self.category_ttl.get(&category))
+			.unwrap_or(&self.message_ttl)
+	}
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+	Ini(ini::Error),
+	MissingKey(&'static str),
+	InvalidValue { key: String, value: String },
+}
+
+impl std::fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ConfigError::Ini(e) => write!(f, "cannot read the config file: {}", e),
+			ConfigError::MissingKey(key) => write!(f, "missing required key \"{}\"", key),
+			ConfigError::InvalidValue { key, value } => {
+				write!(f, "invalid value \"{}\" for key \"{}\"", value, key)
+			}
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+fn parse_value<V: std::str::FromStr>(key: &str, value: &str) -> Result<V, ConfigError> {
+	value.trim().parse().map_err(|_| ConfigError::InvalidValue {
+		key: key.to_owned(),
+		value: value.to_owned(),
+	})
+}
+
+/// Parses a non-zero duration, `default` is used if the key is absent
+fn parse_interval(
+	section: &ini::Properties,
+	key: &str,
+	default: Duration,
+) -> Result<Duration, ConfigError> {
+	match section.get(key) {
+		Some(s) => {
+			parse_duration(s)
+				.filter(|d| !d.is_zero())
+				.ok_or_else(|| ConfigError::InvalidValue {
+					key: key.to_owned(),
+					value: s.to_owned(),
+				})
+		}
+		None => Ok(default),
+	}
+}
+
+/// A required non-empty value
+fn required<'a>(
+	section: &'a ini::Properties,
+	key: &'static str,
+	name: &'static str,
+) -> Result<&'a str, ConfigError> {
+	let value = section.get(key).ok_or(ConfigError::MissingKey(name))?;
+	if value.trim().is_empty() {
+		return Err(ConfigError::InvalidValue {
+			key: name.to_owned(),
+			value: value.to_owned(),
+		});
+	}
+	Ok(value.trim())
+}
+
+/// The label of the machine, the host name by default
+fn read_host(section: &ini::Properties, key: &str) -> Result<String, ConfigError> {
+	let host = match section.get("host") {
+		Some(host) => host.trim().to_owned(),
+		None => sysinfo::System::host_name().unwrap_or_else(|| "localhost".to_owned()),
+	};
+	if host.is_empty() || host.len() > MAX_HOST_LEN {
+		return Err(ConfigError::InvalidValue {
+			key: key.to_owned(),
+			value: host,
+		});
+	}
+	Ok(host)
 }
 
-pub fn read_config() -> Config {
-	let mut path = std::env::current_exe().unwrap();
-	path.pop();
-	path.push("config.ini");
+fn read_agents_config(section: &ini::Properties) -> Result<AgentsConfig, ConfigError> {
+	let listen = required(section, "listen", "agents.listen")?;
+	Ok(AgentsConfig {
+		listen: parse_value("agents.listen", listen)?,
+		token: required(section, "token", "agents.token")?.to_owned(),
+		host: read_host(section, "agents.host")?,
+	})
+}
+
+/// Reads the settings of the agent mode, the Telegram settings are not required
+pub fn read_agent_config(path: &std::path::Path) -> Result<AgentConfig, ConfigError> {
+	let inifile = ini::Ini::load_from_file(path).map_err(ConfigError::Ini)?;
+	let general = inifile.general_section();
+	let section = inifile
+		.section(Some("agent"))
+		.ok_or(ConfigError::MissingKey("agent.server"))?;
+	Ok(AgentConfig {
+		server: required(section, "server", "agent.server")?.to_owned(),
+		token: required(section, "token", "agent.token")?.to_owned(),
+		host: read_host(section, "agent.host")?,
+		check_interval: parse_interval(general, "check_interval", Duration::from_secs(10))?,
+		watch_processes: match general.get("watch_processes") {
+			Some(s) => parse_value("watch_processes", s)?,
+			None => true,
+		},
+		log: match inifile.section(Some("log")) {
+			Some(section) => read_log_config(section)?,
+			None => LogConfig::default(),
+		},
+	})
+}
 
-	read_config_from_file(path)
+fn parse_user_list(key: &'static str, value: &str) -> Result<Vec<UserId>, ConfigError> {
+	value
+		.split(',')
+		.map(str::trim)
+		.filter(|s| !s.is_empty())
+		.map(|s| parse_value(key, s).map(UserId))
+		.collect()
 }
 
-fn read_config_from_file(path: std::path::PathBuf) -> Config {
-	let inifile = ini::Ini::load_from_file(path).unwrap();
-	let section = inifile.section::<String>(None).unwrap();
-	let token = section.get("token").unwrap();
+pub fn read_config(path: &std::path::Path) -> Result<Config, ConfigError> {
+	let inifile = ini::Ini::load_from_file(path).map_err(ConfigError::Ini)?;
+	let section = inifile.general_section();
+	let token = section
+		.get("token")
+		.ok_or(ConfigError::MissingKey("token"))?;
+	if token.trim().is_empty() {
+		return Err(ConfigError::InvalidValue {
+			key: "token".to_owned(),
+			value: token.to_owned(),
+		});
+	}
 	let owner_id = section
 		.get("owner_id")
-		.and_then(|s| s.parse().ok())
-		.map(UserId)
-		.unwrap();
-
-	let auto_subscribe = section
-		.get("auto_subscribe")
-		.and_then(|s| s.parse().ok())
-		.unwrap_or(true);
-
-	println!(
-		"Token: {}, owner_id: {:?}, auto_subscribe: {}",
-		token, owner_id, auto_subscribe
-	);
-
-	Config {
-		owner_id: owner_id,
+		.ok_or(ConfigError::MissingKey("owner_id"))
+		.and_then(|s| parse_value("owner_id", s))
+		.map(UserId)?;
+
+	let auto_subscribe = match section.get("auto_subscribe") {
+		Some(s) => parse_value("auto_subscribe", s)?,
+		None => true,
+	};
+
+	let users = match section.get("users") {
+		Some(s) => parse_user_list("users", s)?,
+		None => Vec::new(),
+	};
+
+	let check_interval = parse_interval(section, "check_interval", Duration::from_secs(10))?;
+	let watch_processes = match section.get("watch_processes") {
+		Some(s) => parse_value("watch_processes", s)?,
+		None => true,
+	};
+	let stall_timeout = match section.get("stall_timeout").map(str::trim) {
+		Some("never") => None,
+		Some(s) => Some(parse_duration(s).ok_or_else(|| ConfigError::InvalidValue {
+			key: "stall_timeout".to_owned(),
+			value: s.to_owned(),
+		})?),
+		None => Some(Duration::from_secs(60 * 30)),
+	};
+	let cleanup_interval = parse_interval(
+		section,
+		"cleanup_interval",
+		Duration::from_secs(60 * 60 * 4),
+	)?;
+	let undeletable_ttl = parse_interval(
+		section,
+		"undeletable_ttl",
+		Duration::from_secs(60 * 60 * 24 * 10),
+	)?;
+	let shutdown_timeout = parse_interval(section, "shutdown_timeout", Duration::from_secs(10))?;
+	let delete_pause = match section.get("delete_pause") {
+		Some(s) => parse_duration(s).ok_or_else(|| ConfigError::InvalidValue {
+			key: "delete_pause".to_owned(),
+			value: s.to_owned(),
+		})?,
+		None => Duration::from_secs(1),
+	};
+	let message_ttl = match section.get("message_ttl") {
+		Some(s) => parse_value("message_ttl", s)?,
+		None => Retention::After(Duration::from_secs(60 * 60 * 24)),
+	};
+
+	let mut chat_retention = HashMap::new();
+	if let Some(section) = inifile.section(Some("retention")) {
+		for (chat, retention) in section.iter() {
+			let key = format!("retention.{}", chat);
+			let chat_id = parse_value(&key, chat).map(ChatId)?;
+			chat_retention.insert(chat_id, parse_value(&key, retention)?);
+		}
+	}
+
+	let mut category_ttl = default_category_ttl();
+	if let Some(section) = inifile.section(Some("category_ttl")) {
+		for (category, retention) in section.iter() {
+			let key = format!("category_ttl.{}", category);
+			category_ttl.insert(parse_value(&key, category)?, parse_value(&key, retention)?);
+		}
+	}
+
+	let max_duration = match inifile.section(Some("max_duration")) {
+		Some(section) => read_max_duration(section)?,
+		None => MaxDuration::default(),
+	};
+
+	let digest = match inifile.section(Some("digest")) {
+		Some(section) => read_digest(section)?,
+		None => DigestConfig::default(),
+	};
+
+	let storage = match section.get("storage").unwrap_or("file") {
+		"file" => StorageConfig::File,
+		"memory" => StorageConfig::Memory,
+		"sqlite" => StorageConfig::Sqlite {
+			path: section.get("storage_path").unwrap_or("state.sqlite").into(),
+		},
+		storage => {
+			return Err(ConfigError::InvalidValue {
+				key: "storage".to_owned(),
+				value: storage.to_owned(),
+			})
+		}
+	};
+
+	let log = match inifile.section(Some("log")) {
+		Some(section) => read_log_config(section)?,
+		None => LogConfig::default(),
+	};
+
+	let agents = match inifile.section(Some("agents")) {
+		Some(section) => Some(read_agents_config(section)?),
+		None => None,
+	};
+
+	Ok(Config {
+		owner_id,
 		token: token.to_owned(),
-		auto_subscribe: auto_subscribe,
+		auto_subscribe,
+		users,
+		check_interval,
+		watch_processes,
+		stall_timeout,
+		max_duration,
+		digest,
+		cleanup_interval,
+		delete_pause,
+		message_ttl,
+		undeletable_ttl,
+		chat_retention,
+		category_ttl,
+		shutdown_timeout,
+		log,
+		storage,
+		agents,
+	})
+}
+
+/// The categories missing here are kept for `message_ttl`
+fn default_category_ttl() -> HashMap<MessageCategory, Retention> {
+	let hour = Duration::from_secs(60 * 60);
+	HashMap::from([
+		(MessageCategory::Failure, Retention::After(hour * 24 * 7)),
+		(MessageCategory::Startup, Retention::After(hour)),
+		(MessageCategory::Help, Retention::After(hour)),
+		(MessageCategory::Pinned, Retention::Never),
+	])
+}
+
+/// The keys are the activity kinds, "path" (repeatable) with "<duration> <path>" values and "repeat"
+fn read_max_duration(section: &ini::Properties) -> Result<MaxDuration, ConfigError> {
+	let mut res = MaxDuration::default();
+	for (key, value) in section.iter() {
+		let full_key = format!("max_duration.{}", key);
+		let invalid = || ConfigError::InvalidValue {
+			key: full_key.clone(),
+			value: value.to_owned(),
+		};
+		match key {
+			"repeat" => {
+				res.repeat = Some(
+					parse_duration(value)
+						.filter(|d| !d.is_zero())
+						.ok_or_else(invalid)?,
+				);
+			}
+			"path" => {
+				let (limit, path) = value
+					.trim()
+					.split_once(char::is_whitespace)
+					.ok_or_else(invalid)?;
+				let limit = parse_duration(limit).ok_or_else(invalid)?;
+				res.paths.push((normalize_path(path, None), limit));
+			}
+			kind => {
+				let kind = parse_value(&full_key, kind)?;
+				res.kinds
+					.insert(kind, parse_duration(value).ok_or_else(invalid)?);
+			}
+		}
+	}
+	Ok(res)
+}
+
+/// "window = <duration>" and "daily = HH:MM [timezone]", the timezone is UTC by default
+fn read_digest(section: &ini::Properties) -> Result<DigestConfig, ConfigError> {
+	let mut res = DigestConfig::default();
+	if let Some(window) = section.get("window") {
+		res.window = Some(
+			parse_duration(window).ok_or_else(|| ConfigError::InvalidValue {
+				key: "digest.window".to_owned(),
+				value: window.to_owned(),
+			})?,
+		);
+	}
+	if let Some(daily) = section.get("daily") {
+		let invalid = || ConfigError::InvalidValue {
+			key: "digest.daily".to_owned(),
+			value: daily.to_owned(),
+		};
+		let mut parts = daily.split_whitespace();
+		let time = parts.next().ok_or_else(invalid)?;
+		let time = NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| invalid())?;
+		let timezone = match parts.next() {
+			Some(timezone) => timezone.parse().map_err(|_| invalid())?,
+			None => chrono_tz::UTC,
+		};
+		if parts.next().is_some() {
+			return Err(invalid());
+		}
+		res.daily = Some(DailyDigest { time, timezone });
+	}
+	Ok(res)
+}
+
+fn read_log_config(section: &ini::Properties) -> Result<LogConfig, ConfigError> {
+	let mut config = LogConfig::default();
+	if let Some(level) = section.get("level") {
+		if !crate::logging::is_valid_filter(level) {
+			return Err(ConfigError::InvalidValue {
+				key: "log.level".to_owned(),
+				value: level.to_owned(),
+			});
+		}
+		config.level = level.to_owned();
+	}
+
+	config.output = match section.get("output").unwrap_or("stderr") {
+		"stderr" => LogOutput::Stderr,
+		"journald" => LogOutput::Journald,
+		"file" => LogOutput::File {
+			path: section.get("file").unwrap_or("bot.log").into(),
+			rotation: match section.get("rotation") {
+				Some(s) => parse_value("log.rotation", s)?,
+				None => LogRotation::Daily,
+			},
+		},
+		output => {
+			return Err(ConfigError::InvalidValue {
+				key: "log.output".to_owned(),
+				value: output.to_owned(),
+			})
+		}
+	};
+
+	Ok(config)
+}
+
+/// Describes the settings changed between two configurations, one line per setting
+pub fn describe_changes(old: &Config, new: &Config) -> Vec<String> {
+	let mut changes = Vec::new();
+	if old.token != new.token {
+		changes.push("token: changed, restart the bot to apply it".to_owned());
+	}
+	if old.owner_id != new.owner_id {
+		changes.push(format!("owner_id: {} -> {}", old.owner_id, new.owner_id));
+	}
+	if old.auto_subscribe != new.auto_subscribe {
+		changes.push(format!(
+			"auto_subscribe: {} -> {}",
+			old.auto_subscribe, new.auto_subscribe
+		));
+	}
+	if old.watch_processes != new.watch_processes {
+		changes.push(format!(
+			"watch_processes: {} -> {}",
+			old.watch_processes, new.watch_processes
+		));
+	}
+	if old.users != new.users {
+		let to_string = |users: &[UserId]| {
+			users
+				.iter()
+				.map(|u| u.to_string())
+				.collect::<Vec<_>>()
+				.join(", ")
+		};
+		changes.push(format!(
+			"users: [{}] -> [{}]",
+			to_string(&old.users),
+			to_string(&new.users)
+		));
+	}
+
+	let durations = [
+		("check_interval", old.check_interval, new.check_interval),
+		(
+			"cleanup_interval",
+			old.cleanup_interval,
+			new.cleanup_interval,
+		),
+		("delete_pause", old.delete_pause, new.delete_pause),
+		("undeletable_ttl", old.undeletable_ttl, new.undeletable_ttl),
+		(
+			"shutdown_timeout",
+			old.shutdown_timeout,
+			new.shutdown_timeout,
+		),
+	];
+	for (key, old_value, new_value) in durations {
+		if old_value != new_value {
+			changes.push(format!(
+				"{}: {} -> {}",
+				key,
+				format_duration(&old_value),
+				format_duration(&new_value)
+			));
+		}
+	}
+	if old.stall_timeout != new.stall_timeout {
+		let to_string = |timeout: &Option<Duration>| match timeout {
+			Some(timeout) => format_duration(timeout),
+			None => "never".to_owned(),
+		};
+		changes.push(format!(
+			"stall_timeout: {} -> {}",
+			to_string(&old.stall_timeout),
+			to_string(&new.stall_timeout)
+		));
+	}
+	if old.max_duration != new.max_duration {
+		changes.push("max_duration: changed".to_owned());
+	}
+	if old.digest != new.digest {
+		changes.push("digest: changed".to_owned());
+	}
+	if old.message_ttl != new.message_ttl {
+		changes.push(format!(
+			"message_ttl: {} -> {}",
+			old.message_ttl, new.message_ttl
+		));
+	}
+	if old.chat_retention != new.chat_retention {
+		changes.push("retention: changed".to_owned());
+	}
+	if old.category_ttl != new.category_ttl {
+		changes.push("category_ttl: changed".to_owned());
+	}
+	if old.log != new.log {
+		changes.push("log: changed, restart the bot to apply it".to_owned());
+	}
+	if old.storage != new.storage {
+		changes.push("storage: changed, restart the bot to apply it".to_owned());
+	}
+	if old.agents != new.agents {
+		changes.push("agents: changed, restart the bot to apply it".to_owned());
+	}
+	changes
+}
+
+/// Detects modifications of the config file by its modification time
+pub struct ConfigWatcher {
+	path: std::path::PathBuf,
+	modified: Option<std::time::SystemTime>,
+}
+
+impl ConfigWatcher {
+	pub fn new(path: std::path::PathBuf) -> Self {
+		let modified = ConfigWatcher::modification_time(&path);
+		Self { path, modified }
+	}
+
+	fn modification_time(path: &std::path::Path) -> Option<std::time::SystemTime> {
+		std::fs::metadata(path).and_then(|m| m.modified()).ok()
+	}
+
+	pub fn path(&self) -> &std::path::Path {
+		&self.path
+	}
+
+	/// Returns true if the file has been modified since the previous call
+	pub fn is_changed(&mut self) -> bool {
+		let modified = ConfigWatcher::modification_time(&self.path);
+		if modified != self.modified {
+			self.modified = modified;
+			true
+		} else {
+			false
+		}
 	}
 }
 
@@ -51,18 +697,463 @@ mod test {
 	fn test_config() {
 		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
 		ini_file
-			.write(
+			.write_all(
 				br#"
 token="token"
 owner_id = "42"
 auto_subscribe="false"
+watch_processes = false
 		"#,
 			)
 			.unwrap();
 		ini_file.flush().unwrap();
-		let config = read_config_from_file(ini_file.path().to_path_buf());
-		assert_eq!(config.auto_subscribe, false);
+		let config = read_config(ini_file.path()).unwrap();
+		assert!(!config.auto_subscribe);
+		assert!(!config.watch_processes);
 		assert_eq!(config.token, "token");
 		assert_eq!(config.owner_id.0, 42);
 	}
+
+	#[test]
+	fn test_config_errors() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file.write_all(b"owner_id = 42\n").unwrap();
+		ini_file.flush().unwrap();
+		assert!(matches!(
+			read_config(ini_file.path()),
+			Err(ConfigError::MissingKey("token"))
+		));
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file.write_all(b"token = t\nowner_id = abc\n").unwrap();
+		ini_file.flush().unwrap();
+		assert!(matches!(
+			read_config(ini_file.path()),
+			Err(ConfigError::InvalidValue { key, .. }) if key == "owner_id"
+		));
+
+		assert!(matches!(
+			read_config(std::path::Path::new("/nonexistent/config.ini")),
+			Err(ConfigError::Ini(_))
+		));
+	}
+
+	#[test]
+	fn test_users() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(b"token = t\nowner_id = 1\nusers = 2, 3\n")
+			.unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		assert_eq!(config.users, [UserId(2), UserId(3)]);
+		assert!(config.is_allowed(UserId(1)));
+		assert!(config.is_allowed(UserId(3)));
+		assert!(!config.is_allowed(UserId(4)));
+
+		let mut new_config = config.clone();
+		new_config.users.clear();
+		new_config.auto_subscribe = false;
+		assert!(new_config.is_allowed(UserId(4)));
+		assert_eq!(
+			describe_changes(&config, &new_config),
+			["auto_subscribe: true -> false", "users: [2, 3] -> []"]
+		);
+		assert!(describe_changes(&config, &config).is_empty());
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(b"token = t\nowner_id = 1\nusers = 2, x\n")
+			.unwrap();
+		ini_file.flush().unwrap();
+		assert!(matches!(
+			read_config(ini_file.path()),
+			Err(ConfigError::InvalidValue { key, .. }) if key == "users"
+		));
+	}
+
+	#[test]
+	fn test_intervals() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file.write_all(b"token = t\nowner_id = 1\n").unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		assert_eq!(config.check_interval, Duration::from_secs(10));
+		assert_eq!(config.cleanup_interval, Duration::from_secs(4 * 3600));
+		assert_eq!(config.delete_pause, Duration::from_secs(1));
+		assert_eq!(
+			config.message_ttl,
+			Retention::After(Duration::from_secs(86400))
+		);
+		assert_eq!(config.undeletable_ttl, Duration::from_secs(10 * 86400));
+		assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+		assert_eq!(config.stall_timeout, Some(Duration::from_secs(1800)));
+		assert_eq!(config.storage, StorageConfig::File);
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(
+				br#"
+token = t
+owner_id = 1
+check_interval = 30s
+cleanup_interval = 1h30m
+storage = sqlite
+delete_pause = 0s
+message_ttl = 2h
+undeletable_ttl = 7d
+stall_timeout = never
+
+[retention]
+-100123 = never
+42 = 3d
+"#,
+			)
+			.unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		assert_eq!(config.check_interval, Duration::from_secs(30));
+		assert_eq!(config.cleanup_interval, Duration::from_secs(5400));
+		assert_eq!(config.delete_pause, Duration::ZERO);
+		assert_eq!(config.undeletable_ttl, Duration::from_secs(7 * 86400));
+		assert_eq!(config.stall_timeout, None);
+		assert_eq!(
+			config.storage,
+			StorageConfig::Sqlite {
+				path: "state.sqlite".into()
+			}
+		);
+		assert_eq!(
+			config.retention(&ChatId(-100123), MessageCategory::Reply),
+			Retention::Never
+		);
+		assert_eq!(
+			config.retention(&ChatId(42), MessageCategory::Reply),
+			Retention::After(Duration::from_secs(3 * 86400))
+		);
+		assert_eq!(
+			config.retention(&ChatId(1), MessageCategory::Reply),
+			Retention::After(Duration::from_secs(7200))
+		);
+
+		for wrong in [
+			"check_interval = 0s",
+			"cleanup_interval = 4",
+			"message_ttl = forever",
+			"undeletable_ttl = 100000000000000d",
+			"message_ttl = 36500000d",
+		] {
+			let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+			write!(ini_file, "token = t\nowner_id = 1\n{}\n", wrong).unwrap();
+			ini_file.flush().unwrap();
+			assert!(matches!(
+				read_config(ini_file.path()),
+				Err(ConfigError::InvalidValue { .. })
+			));
+		}
+	}
+
+	#[test]
+	fn test_category_ttl() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(b"token = t\nowner_id = 1\n[retention]\n42 = 3d\n[category_ttl]\nhelp = 10m\nnotification = never\n")
+			.unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		let chat = ChatId(1);
+		assert_eq!(
+			config.retention(&chat, MessageCategory::Help),
+			Retention::After(Duration::from_secs(600))
+		);
+		assert_eq!(
+			config.retention(&chat, MessageCategory::Notification),
+			Retention::Never
+		);
+		assert_eq!(
+			config.retention(&chat, MessageCategory::Failure),
+			Retention::After(Duration::from_secs(7 * 86400))
+		);
+		assert_eq!(
+			config.retention(&chat, MessageCategory::Startup),
+			Retention::After(Duration::from_secs(3600))
+		);
+		assert_eq!(
+			config.retention(&chat, MessageCategory::Command),
+			Retention::After(Duration::from_secs(86400))
+		);
+		assert_eq!(
+			config.retention(&ChatId(42), MessageCategory::Help),
+			Retention::After(Duration::from_secs(3 * 86400))
+		);
+		assert_eq!(
+			config.retention(&ChatId(42), MessageCategory::Pinned),
+			Retention::Never
+		);
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(b"token = t\nowner_id = 1\n[category_ttl]\nspam = 1h\n")
+			.unwrap();
+		ini_file.flush().unwrap();
+		assert!(matches!(
+			read_config(ini_file.path()),
+			Err(ConfigError::InvalidValue { key, .. }) if key == "category_ttl.spam"
+		));
+	}
+
+	#[test]
+	fn test_max_duration() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file.write_all(b"token = t\nowner_id = 1\n").unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		assert_eq!(config.max_duration, MaxDuration::default());
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(
+				br#"
+token = t
+owner_id = 1
+[max_duration]
+deploy = 40m
+build = 2h
+path = 1h C:/Saby/deploy2/config/test.s3deploy
+path = 10m "/home/user/my repo/"
+repeat = 15m
+"#,
+			)
+			.unwrap();
+		ini_file.flush().unwrap();
+		let new_config = read_config(ini_file.path()).unwrap();
+		let max_duration = &new_config.max_duration;
+		assert_eq!(max_duration.repeat, Some(Duration::from_secs(900)));
+		assert_eq!(
+			max_duration.get(&ActivityKind::Deploy, Some("C:/other.s3deploy")),
+			Some(Duration::from_secs(2400))
+		);
+		assert_eq!(
+			max_duration.get(
+				&ActivityKind::Deploy,
+				Some("C:/Saby/deploy2/config/test.s3deploy")
+			),
+			Some(Duration::from_secs(3600))
+		);
+		assert_eq!(
+			max_duration.get(&ActivityKind::Build, Some("/home/user/my repo")),
+			Some(Duration::from_secs(600))
+		);
+		assert_eq!(
+			max_duration.get(&ActivityKind::UpdateToRevision, None),
+			None
+		);
+		assert_eq!(
+			describe_changes(&config, &new_config),
+			["max_duration: changed"]
+		);
+
+		for (wrong, key) in [
+			("test = 1h", "max_duration.test"),
+			("build = long", "max_duration.build"),
+			("path = 1h", "max_duration.path"),
+			("repeat = 0s", "max_duration.repeat"),
+		] {
+			let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+			write!(
+				ini_file,
+				"token = t\nowner_id = 1\n[max_duration]\n{}\n",
+				wrong
+			)
+			.unwrap();
+			ini_file.flush().unwrap();
+			assert!(
+				matches!(
+					read_config(ini_file.path()),
+					Err(ConfigError::InvalidValue { key: k, .. }) if k == key
+				),
+				"{}",
+				wrong
+			);
+		}
+	}
+
+	#[test]
+	fn test_digest() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file.write_all(b"token = t\nowner_id = 1\n").unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		assert_eq!(config.digest, DigestConfig::default());
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(
+				b"token = t\nowner_id = 1\n[digest]\nwindow = 0s\ndaily = 09:30 Europe/Moscow\n",
+			)
+			.unwrap();
+		ini_file.flush().unwrap();
+		let new_config = read_config(ini_file.path()).unwrap();
+		assert_eq!(new_config.digest.window, Some(Duration::ZERO));
+		assert_eq!(describe_changes(&config, &new_config), ["digest: changed"]);
+
+		// Moscow is UTC+3
+		let daily = new_config.digest.daily.unwrap();
+		let utc = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+		assert_eq!(
+			daily.last_before(utc("2024-06-02T06:30:00Z")),
+			utc("2024-06-02T06:30:00Z")
+		);
+		assert_eq!(
+			daily.last_before(utc("2024-06-02T06:29:00Z")),
+			utc("2024-06-01T06:30:00Z")
+		);
+		assert_eq!(
+			daily.last_before(utc("2024-06-02T22:00:00Z")),
+			utc("2024-06-02T06:30:00Z")
+		);
+
+		// The skipped local time is moved by the DST change
+		let daily = DailyDigest {
+			time: NaiveTime::from_hms_opt(2, 30, 0).unwrap(),
+			timezone: chrono_tz::Europe::Berlin,
+		};
+		assert_eq!(
+			daily.last_before(utc("2024-03-31T12:00:00Z")),
+			utc("2024-03-31T01:30:00Z")
+		);
+
+		for (wrong, key) in [
+			("window = soon", "digest.window"),
+			("daily = 9", "digest.daily"),
+			("daily = 09:00 Mars/Olympus", "digest.daily"),
+			("daily = 09:00 UTC extra", "digest.daily"),
+		] {
+			let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+			write!(ini_file, "token = t\nowner_id = 1\n[digest]\n{}\n", wrong).unwrap();
+			ini_file.flush().unwrap();
+			assert!(
+				matches!(
+					read_config(ini_file.path()),
+					Err(ConfigError::InvalidValue { key: k, .. }) if k == key
+				),
+				"{}",
+				wrong
+			);
+		}
+	}
+
+	#[test]
+	fn test_agents_config() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file.write_all(b"token = t\nowner_id = 1\n").unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		assert_eq!(config.agents, None);
+		// The agent mode needs the [agent] section
+		assert!(matches!(
+			read_agent_config(ini_file.path()),
+			Err(ConfigError::MissingKey("agent.server"))
+		));
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(b"token = t\nowner_id = 1\n[agents]\nlisten = 0.0.0.0:7070\ntoken = secret\nhost = main\n")
+			.unwrap();
+		ini_file.flush().unwrap();
+		let new_config = read_config(ini_file.path()).unwrap();
+		assert_eq!(
+			new_config.agents,
+			Some(AgentsConfig {
+				listen: "0.0.0.0:7070".parse().unwrap(),
+				token: "secret".to_owned(),
+				host: "main".to_owned(),
+			})
+		);
+		assert_eq!(
+			describe_changes(&config, &new_config),
+			["agents: changed, restart the bot to apply it"]
+		);
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(b"check_interval = 30s\n[agent]\nserver = bot.local:7070\ntoken = secret\nhost = build1\n")
+			.unwrap();
+		ini_file.flush().unwrap();
+		let agent = read_agent_config(ini_file.path()).unwrap();
+		assert_eq!(agent.server, "bot.local:7070");
+		assert_eq!(agent.token, "secret");
+		assert_eq!(agent.host, "build1");
+		assert_eq!(agent.check_interval, Duration::from_secs(30));
+		assert!(agent.watch_processes);
+
+		for (wrong, key) in [
+			("[agents]\ntoken = s", "agents.listen"),
+			("[agents]\nlisten = nowhere\ntoken = s", "agents.listen"),
+			("[agents]\nlisten = 0.0.0.0:1\ntoken = ", "agents.token"),
+			(
+				"[agents]\nlisten = 0.0.0.0:1\ntoken = s\nhost = ",
+				"agents.host",
+			),
+		] {
+			let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+			write!(ini_file, "token = t\nowner_id = 1\n{}\n", wrong).unwrap();
+			ini_file.flush().unwrap();
+			let res = read_config(ini_file.path());
+			assert!(
+				matches!(&res, Err(ConfigError::InvalidValue { key: k, .. }) if k == key)
+					|| matches!(&res, Err(ConfigError::MissingKey(k)) if *k == key),
+				"{}",
+				wrong
+			);
+		}
+	}
+
+	#[test]
+	fn test_log_config() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file.write_all(b"token = t\nowner_id = 1\n").unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		assert_eq!(config.log, LogConfig::default());
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(b"token = t\nowner_id = 1\n[log]\nlevel = debug\noutput = file\nfile = logs/bot.log\nrotation = hourly\n")
+			.unwrap();
+		ini_file.flush().unwrap();
+		let config = read_config(ini_file.path()).unwrap();
+		assert_eq!(config.log.level, "debug");
+		assert_eq!(
+			config.log.output,
+			LogOutput::File {
+				path: "logs/bot.log".into(),
+				rotation: LogRotation::Hourly
+			}
+		);
+
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		ini_file
+			.write_all(b"token = t\nowner_id = 1\n[log]\noutput = syslog\n")
+			.unwrap();
+		ini_file.flush().unwrap();
+		assert!(matches!(
+			read_config(ini_file.path()),
+			Err(ConfigError::InvalidValue { key, .. }) if key == "log.output"
+		));
+	}
+
+	#[test]
+	fn test_config_watcher() {
+		let mut ini_file = tempfile::NamedTempFile::new().unwrap();
+		let mut watcher = ConfigWatcher::new(ini_file.path().to_path_buf());
+		assert!(!watcher.is_changed());
+
+		ini_file.write_all(b"token = t\n").unwrap();
+		ini_file.flush().unwrap();
+		let later = std::time::SystemTime::now() + std::time::Duration::from_secs(5);
+		ini_file.as_file().set_modified(later).unwrap();
+		assert!(watcher.is_changed());
+		assert!(!watcher.is_changed());
+	}
 }
@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use teloxide::{ApiError, RequestError};
+
+use crate::i18n::Lang;
+
+/// Telegram doesn't let the bots delete the messages older than 48 hours in the groups
+pub const DELETE_LIMIT: Duration = Duration::from_secs(60 * 60 * 48);
+
+/// Why a message can't be deleted, it defines what to do with the message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteFailure {
+	/// The message is already deleted, it is forgotten
+	NotFound,
+	/// The bot's own message is too old to delete, it is edited to the placeholder
+	TooOld,
+	/// The bot has no rights to delete the message and never will, it is forgotten
+	NoRights,
+	/// Temporary failure, the deletion is retried later
+	Network,
+	/// Unexpected error, the deletion is retried until the message becomes undeletable
+	Other,
+}
+
+impl DeleteFailure {
+	/// Telegram reports the same error for the old messages and for the ones the bot has no rights to delete,
+	/// so `is_own` and `age` of the message tell them apart
+	pub fn classify(e: &RequestError, is_own: bool, age: Duration) -> Self {
+		match e {
+			RequestError::Api(ApiError::MessageToDeleteNotFound | ApiError::MessageIdInvalid) => {
+				DeleteFailure::NotFound
+			}
+			RequestError::Api(ApiError::MessageCantBeDeleted) => {
+				if is_own && age >= DELETE_LIMIT {
+					DeleteFailure::TooOld
+				} else {
+					DeleteFailure::NoRights
+				}
+			}
+			RequestError::Api(
+				ApiError::BotBlocked
+				| ApiError::BotKicked
+				| ApiError::BotKickedFromSupergroup
+				| ApiError::ChatNotFound,
+			) => DeleteFailure::NoRights,
+			RequestError::Network(_) | RequestError::RetryAfter(_) | RequestError::Io(_) => {
+				DeleteFailure::Network
+			}
+			_ => DeleteFailure::Other,
+		}
+	}
+}
+
+/// Results of the message deletions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeleteStats {
+	pub deleted: u64,
+	/// Already deleted by someone else
+	pub not_found: u64,
+	/// Too old to delete, edited to the placeholder
+	pub replaced: u64,
+	/// Forgotten without deletion
+	pub no_rights: u64,
+	/// Kept to retry later
+	pub retried: u64,
+	/// Temporary failures of the messages older than `undeletable_ttl`, forgotten
+	pub given_up: u64,
+}
+
+impl DeleteStats {
+	/// The messages which are gone from the chats
+	pub fn removed(&self) -> u64 {
+		self.deleted + self.not_found
+	}
+
+	pub fn failed(&self) -> u64 {
+		self.no_rights + self.retried + self.given_up
+	}
+
+	/// One line per outcome
+	pub fn describe(&self, lang: Lang) -> String {
+		[
+			("stats.deleted", self.deleted),
+			("stats.not_found", self.not_found),
+			("stats.replaced", self.replaced),
+			("stats.no_rights", self.no_rights),
+			("stats.retried", self.retried),
+			("stats.given_up", self.given_up),
+		]
+		.map(|(key, count)| lang.format(key, &[("count", &count)]))
+		.join("\n")
+	}
+}
+
+impl std::ops::AddAssign for DeleteStats {
+	fn add_assign(&mut self, other: Self) {
+		self.deleted += other.deleted;
+		self.not_found += other.not_found;
+		self.replaced += other.replaced;
+		self.no_rights += other.no_rights;
+		self.retried += other.retried;
+		self.given_up += other.given_up;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_classify() {
+		let hour = Duration::from_secs(3600);
+		let cases = [
+			(
+				ApiError::MessageToDeleteNotFound,
+				true,
+				hour,
+				DeleteFailure::NotFound,
+			),
+			(
+				ApiError::MessageIdInvalid,
+				false,
+				hour,
+				DeleteFailure::NotFound,
+			),
+			(
+				ApiError::MessageCantBeDeleted,
+				true,
+				hour * 49,
+				DeleteFailure::TooOld,
+			),
+			(
+				ApiError::MessageCantBeDeleted,
+				true,
+				hour,
+				DeleteFailure::NoRights,
+			),
+			(
+				ApiError::MessageCantBeDeleted,
+				false,
+				hour * 49,
+				DeleteFailure::NoRights,
+			),
+			(ApiError::BotKicked, true, hour, DeleteFailure::NoRights),
+			(
+				ApiError::Unknown("Bad Request".to_owned()),
+				true,
+				hour,
+				DeleteFailure::Other,
+			),
+		];
+		for (e, is_own, age, expected) in cases {
+			let e = RequestError::Api(e);
+			assert_eq!(DeleteFailure::classify(&e, is_own, age), expected, "{}", e);
+		}
+		assert_eq!(
+			DeleteFailure::classify(&RequestError::RetryAfter(hour), true, hour),
+			DeleteFailure::Network
+		);
+		let e = RequestError::Io(std::io::ErrorKind::ConnectionReset.into());
+		assert_eq!(
+			DeleteFailure::classify(&e, true, hour),
+			DeleteFailure::Network
+		);
+	}
+
+	#[test]
+	fn test_stats() {
+		let mut stats = DeleteStats {
+			deleted: 3,
+			not_found: 1,
+			no_rights: 2,
+			..Default::default()
+		};
+		stats += DeleteStats {
+			deleted: 1,
+			retried: 1,
+			given_up: 1,
+			..Default::default()
+		};
+		assert_eq!(stats.removed(), 5);
+		assert_eq!(stats.failed(), 4);
+		assert!(stats
+			.describe(Lang::En)
+			.starts_with("deleted: 4\nalready deleted: 1\n"));
+		assert!(stats.describe(Lang::Ru).ends_with(
+			"\nнет прав на удаление: 2\nбудет повторено позже: 1\nпопытки прекращены: 1"
+		));
+	}
+}
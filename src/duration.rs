@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+const UNITS: [(&str, u64); 5] = [
+	("w", 60 * 60 * 24 * 7),
+	("d", 60 * 60 * 24),
+	("h", 60 * 60),
+	("m", 60),
+	("s", 1),
+];
+
+/// The longest accepted duration, the dates shifted by the longer ones may overflow
+pub const MAX_DURATION: Duration = Duration::from_secs(60 * 60 * 24 * 365 * 100);
+
+/// Parses a human-readable duration like "30s", "2h", "7d" or "1h30m", up to `MAX_DURATION`
+pub fn parse_duration(s: &str) -> Option<Duration> {
+	let s = s.trim();
+	if s.is_empty() {
+		return None;
+	}
+
+	let mut secs: u64 = 0;
+	let mut rest = s;
+	while !rest.is_empty() {
+		let digits = rest
+			.find(|c: char| !c.is_ascii_digit())
+			.unwrap_or(rest.len());
+		if digits == 0 {
+			return None;
+		}
+		let value: u64 = rest[..digits].parse().ok()?;
+		rest = rest[digits..].trim_start();
+
+		let unit_len = rest
+			.find(|c: char| !c.is_ascii_alphabetic())
+			.unwrap_or(rest.len());
+		let unit = &rest[..unit_len];
+		let multiplier = UNITS
+			.iter()
+			.find(|(name, _)| *name == unit)
+			.map(|(_, m)| *m)?;
+		rest = rest[unit_len..].trim_start();
+
+		secs = secs.checked_add(value.checked_mul(multiplier)?)?;
+	}
+
+	Some(Duration::from_secs(secs)).filter(|d| *d <= MAX_DURATION)
+}
+
+/// Formats the duration the same way it is written in the config, e.g. "1h30m"
+pub fn format_duration(d: &Duration) -> String {
+	let mut secs = d.as_secs();
+	if secs == 0 {
+		return "0s".to_owned();
+	}
+
+	let mut res = String::new();
+	for (name, multiplier) in UNITS.iter() {
+		if secs >= *multiplier {
+			res += &format!("{}{}", secs / multiplier, name);
+			secs %= multiplier;
+		}
+	}
+	res
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_parse_duration() {
+		assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+		assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 3600)));
+		assert_eq!(parse_duration("7d"), Some(Duration::from_secs(7 * 86400)));
+		assert_eq!(parse_duration("1w"), Some(Duration::from_secs(7 * 86400)));
+		assert_eq!(parse_duration(" 1h 30m "), Some(Duration::from_secs(5400)));
+		assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+		assert_eq!(parse_duration(""), None);
+		assert_eq!(parse_duration("30"), None);
+		assert_eq!(parse_duration("h"), None);
+		assert_eq!(parse_duration("5y"), None);
+		assert_eq!(parse_duration("-5s"), None);
+		assert_eq!(parse_duration("99999999999999999999d"), None);
+		assert_eq!(parse_duration("100000000000000d"), None);
+		assert_eq!(parse_duration("5218w"), None);
+		assert_eq!(
+			parse_duration("5214w"),
+			Some(Duration::from_secs(5214 * 7 * 86400))
+		);
+	}
+
+	#[test]
+	fn test_format_duration() {
+		assert_eq!(format_duration(&Duration::from_secs(30)), "30s");
+		assert_eq!(format_duration(&Duration::from_secs(5400)), "1h30m");
+		assert_eq!(format_duration(&Duration::from_secs(86400 * 10)), "1w3d");
+		assert_eq!(format_duration(&Duration::ZERO), "0s");
+		for s in ["4h", "1d", "2w1h", "1m1s"] {
+			assert_eq!(format_duration(&parse_duration(s).unwrap()), s);
+		}
+	}
+}
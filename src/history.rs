@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+
+use crate::activity::{ActivityKind, ResourceUsage};
+use crate::storage::SharedStorage;
+
+/// Key of the history in the storage
+const STORAGE_KEY: &str = "history";
+
+/// A completed activity
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HistoryEntry {
+	/// The label of the agent, empty for this machine
+	#[serde(default, skip_serializing_if = "String::is_empty")]
+	pub host: String,
+	pub kind: ActivityKind,
+	pub description: Option<String>,
+	/// When the bot has noticed the activity
+	pub started: chrono::DateTime<chrono::Utc>,
+	pub finished: chrono::DateTime<chrono::Utc>,
+	/// Absent in the entries written by the older versions
+	#[serde(default)]
+	pub usage: Option<ResourceUsage>,
+}
+
+/// The last completed activities, the oldest first
+pub struct History {
+	entries: VecDeque<HistoryEntry>,
+	max_len: usize,
+	storage: SharedStorage,
+}
+
+impl History {
+	pub fn new(storage: SharedStorage, max_len: usize) -> Self {
+		let entries = match storage.load(STORAGE_KEY) {
+			Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+				tracing::warn!(error = %e, "The history is damaged");
+				VecDeque::new()
+			}),
+			Ok(None) => VecDeque::new(),
+			Err(e) => {
+				tracing::error!(error = %e, "Cannot read the history");
+				VecDeque::new()
+			}
+		};
+		let mut res = Self {
+			entries,
+			max_len,
+			storage,
+		};
+		res.truncate();
+		res
+	}
+
+	fn truncate(&mut self) {
+		while self.entries.len() > self.max_len {
+			self.entries.pop_front();
+		}
+	}
+
+	pub fn add(&mut self, entry: HistoryEntry) {
+		self.entries.push_back(entry);
+		self.truncate();
+
+		let data = serde_json::to_vec(&self.entries).unwrap();
+		if let Err(e) = self.storage.save(STORAGE_KEY, &data) {
+			tracing::error!(error = %e, "Cannot write the history");
+		}
+	}
+
+	pub fn entries(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> {
+		self.entries.iter()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::storage::MemoryStorage;
+	use std::sync::Arc;
+
+	fn entry(description: &str) -> HistoryEntry {
+		let now = chrono::Utc::now();
+		HistoryEntry {
+			host: String::new(),
+			kind: ActivityKind::Build,
+			description: Some(description.to_owned()),
+			started: now - chrono::Duration::try_minutes(5).unwrap(),
+			finished: now,
+			usage: None,
+		}
+	}
+
+	#[test]
+	fn test_history() {
+		let storage: SharedStorage = Arc::new(MemoryStorage::default());
+		let mut history = History::new(storage.clone(), 2);
+		history.add(entry("a"));
+		history.add(entry("b"));
+		history.add(entry("c"));
+
+		let history = History::new(storage, 2);
+		let descriptions: Vec<_> = history
+			.entries()
+			.map(|e| e.description.as_deref().unwrap())
+			.collect();
+		assert_eq!(descriptions, ["b", "c"]);
+	}
+}
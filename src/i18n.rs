@@ -0,0 +1,418 @@
+use std::fmt::Display;
+
+use crate::activity::ActivityKind;
+
+/// The language of the messages of the bot
+#[derive(
+	Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+	#[default]
+	En,
+	Ru,
+}
+
+impl Lang {
+	pub const ALL: [Lang; 2] = [Lang::En, Lang::Ru];
+
+	pub fn code(self) -> &'static str {
+		match self {
+			Lang::En => "en",
+			Lang::Ru => "ru",
+		}
+	}
+
+	/// The language of the Telegram client like "ru" or "en-US", None if there is no translation
+	pub fn from_code(code: &str) -> Option<Lang> {
+		let primary = code.split(['-', '_']).next()?.to_ascii_lowercase();
+		Lang::ALL.into_iter().find(|lang| lang.code() == primary)
+	}
+
+	fn catalog(self) -> &'static [(&'static str, &'static str)] {
+		match self {
+			Lang::En => EN,
+			Lang::Ru => RU,
+		}
+	}
+
+	/// The message of the key. The English one is used if the translation is missing
+	pub fn text(self, key: &str) -> &'static str {
+		let find = |catalog: &'static [(&'static str, &'static str)]| {
+			catalog
+				.iter()
+				.find(|(k, _)| *k == key)
+				.map(|(_, text)| *text)
+		};
+		find(self.catalog())
+			.or_else(|| find(EN))
+			.unwrap_or_else(|| {
+				tracing::error!(key, "No message with the key");
+				""
+			})
+	}
+
+	/// The message of the key with "{name}" replaced by the arguments.
+	/// The arguments are inserted as is, even if they contain the braces
+	pub fn format(self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+		let mut res = String::new();
+		let mut rest = self.text(key);
+		while let Some(start) = rest.find('{') {
+			res += &rest[..start];
+			let after = &rest[start + 1..];
+			let arg = after.split_once('}').and_then(|(name, tail)| {
+				let (_, value) = args.iter().find(|(n, _)| *n == name)?;
+				Some((value, tail))
+			});
+			match arg {
+				Some((value, tail)) => {
+					res += &value.to_string();
+					rest = tail;
+				}
+				None => {
+					res.push('{');
+					rest = after;
+				}
+			}
+		}
+		res + rest
+	}
+
+	pub fn kind(self, kind: &ActivityKind) -> &'static str {
+		self.text(&format!("kind.{}", kind.name()))
+	}
+}
+
+impl std::str::FromStr for Lang {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Lang::ALL
+			.into_iter()
+			.find(|lang| lang.code() == s)
+			.ok_or(())
+	}
+}
+
+const EN: &[(&str, &str)] = &[
+	("language.name", "English"),
+	(
+		"help",
+		"This is a simple bot for sbis build/deploy progress notification. List of supported commands:
+	/help: prints help message.
+	/subscribe: sends a notification when the build/deploy process completes.
+	/history: shows the recently completed actions.
+	/status: shows the running actions.
+	/clean [age]: deletes the messages in this chat older than the age (e.g. 2h), all of them without the age.
+	/stats: shows the message deletion statistics.
+	/quiet [HH:MM-HH:MM timezone [kind=mode ...] | off]: shows, sets or removes your quiet hours.
+	During them the notifications are sent silently, held for a digest or dropped: kind=silent|digest|drop,
+	the kinds are build, deploy, update_to_revision and update_module_manager.
+	/language [en|ru|auto]: shows or sets the language of the bot, auto follows Telegram.
+	",
+	),
+	("unknown_command", "Unknown command: {command}. \n{help}"),
+	("not_allowed", "You are not allowed to use this bot"),
+	("started", "Bot has started"),
+	("stopping", "Bot is stopping"),
+	(
+		"stopping_watched",
+		"Bot is stopping, watching the following actions resumes after the restart:",
+	),
+	("kind.build", "Build"),
+	("kind.deploy", "Deploy"),
+	("kind.update_to_revision", "Update to revisions"),
+	("kind.update_module_manager", "Update with module manager"),
+	("path", ", path = `\"{path}\"`"),
+	("usage", "{children} child processes, CPU {cpu}%, memory {memory}"),
+	(
+		"resource_usage",
+		"peak memory {memory}, average CPU {cpu}%, CPU time {cpu_time}",
+	),
+	("estimated", "{value} (estimated)"),
+	("used", "Used: {usage}"),
+	("current_action", "Current action: {label}{kind} ({usage})"),
+	("several_actions", "There are several running actions"),
+	(
+		"will_notify",
+		"When action have been completed you will be notified",
+	),
+	("no_current_action", "There is no current action"),
+	("new_action", "{label}New action: {kind}\nPath: {path}"),
+	("completed", "{label}{kind} completed{path}"),
+	(
+		"overtime",
+		"{label}{kind}{path} runs for {elapsed}, longer than the expected {limit}",
+	),
+	(
+		"stall",
+		"{label}{kind}{path} seems to hang: no CPU and I/O for {idle}",
+	),
+	("button.keep", "Keep waiting"),
+	("button.stop", "Stop"),
+	("not_subscribed", "You are not subscribed to this action"),
+	("keep_waiting", "OK, waiting for the action"),
+	("already_completed", "The action has already completed"),
+	("asked_to_stop", "Asked {count} processes of the action to stop"),
+	("agent_not_connected", "The agent {host} is not connected"),
+	("agent_reconnected", "The agent {host} has reconnected"),
+	(
+		"agent_lost",
+		"Lost the connection to the agent {host}, its actions are considered running until it reconnects",
+	),
+	("no_history", "No actions have been completed yet"),
+	("history", "Recently completed actions:"),
+	("running_actions", "Running actions:"),
+	("host_disconnected", " (disconnected)"),
+	("host_idle", ": no running actions"),
+	("notifications", "{count} notifications:"),
+	("daily", "Daily digest: {count} actions completed"),
+	("daily_none", "Daily digest: no actions have completed"),
+	("daily_running", ", {count} running now"),
+	("quiet_digest", "During the quiet hours:"),
+	("quiet_hours", "Quiet hours: {quiet}"),
+	("no_quiet_hours", "No quiet hours are set"),
+	("quiet_removed", "The quiet hours are removed"),
+	(
+		"quiet.missing_args",
+		"The time range and the timezone are required",
+	),
+	("quiet.invalid_time", "Invalid time: {value}"),
+	("quiet.invalid_range", "Invalid time range: {value}"),
+	("quiet.empty", "The quiet hours are empty"),
+	(
+		"quiet.unknown_timezone",
+		"Unknown timezone: {value}, use the names like Europe/Moscow or UTC",
+	),
+	("quiet.expected_kind_mode", "Expected kind=mode: {value}"),
+	("quiet.unknown_kind", "Unknown action kind: {value}"),
+	("quiet.unknown_mode", "Unknown mode: {value}"),
+	("language", "Language: {language}"),
+	(
+		"language_auto",
+		"Language: {language}, as in Telegram",
+	),
+	(
+		"unknown_language",
+		"Unknown language: {value}, the supported ones are {languages} and auto",
+	),
+	("config_reloaded", "Configuration reloaded:"),
+	("config_unchanged", "Configuration reloaded, no changes"),
+	(
+		"config_reload_failed",
+		"Configuration reload failed: {error}\nThe current settings are kept",
+	),
+	("clean.nothing", "There are no messages to delete"),
+	("clean.deleted", "Deleted {count} messages"),
+	(
+		"clean.replaced",
+		", replaced {count} too old ones with a placeholder",
+	),
+	("clean.failed", ", failed to delete {count}"),
+	("stats", "Tracked messages: {count}\nSince the start:"),
+	("stats.deleted", "deleted: {count}"),
+	("stats.not_found", "already deleted: {count}"),
+	("stats.replaced", "replaced with a placeholder: {count}"),
+	("deleted_placeholder", "[deleted]"),
+	("stats.no_rights", "no rights to delete: {count}"),
+	("stats.retried", "to retry later: {count}"),
+	("stats.given_up", "given up: {count}"),
+];
+
+const RU: &[(&str, &str)] = &[
+	("language.name", "русский"),
+	(
+		"help",
+		"Это простой бот для уведомлений о ходе сборки и развёртывания sbis. Поддерживаемые команды:
+	/help: показывает эту справку.
+	/subscribe: присылает уведомление, когда сборка или развёртывание завершится.
+	/history: показывает недавно завершённые действия.
+	/status: показывает запущенные действия.
+	/clean [age]: удаляет сообщения в этом чате старше указанного возраста (например, 2h), без возраста — все.
+	/stats: показывает статистику удаления сообщений.
+	/quiet [HH:MM-HH:MM timezone [kind=mode ...] | off]: показывает, задаёт или отменяет ваши тихие часы.
+	В это время уведомления приходят без звука, копятся в сводку или отбрасываются: kind=silent|digest|drop,
+	виды действий: build, deploy, update_to_revision и update_module_manager.
+	/language [en|ru|auto]: показывает или задаёт язык бота, auto — как в Telegram.
+	",
+	),
+	("unknown_command", "Неизвестная команда: {command}. \n{help}"),
+	("not_allowed", "Вам не разрешено пользоваться этим ботом"),
+	("started", "Бот запущен"),
+	("stopping", "Бот останавливается"),
+	(
+		"stopping_watched",
+		"Бот останавливается, отслеживание следующих действий продолжится после перезапуска:",
+	),
+	("kind.build", "Сборка"),
+	("kind.deploy", "Развёртывание"),
+	("kind.update_to_revision", "Обновление до ревизий"),
+	("kind.update_module_manager", "Обновление менеджером модулей"),
+	("path", ", путь = `\"{path}\"`"),
+	(
+		"usage",
+		"дочерних процессов: {children}, CPU {cpu}%, память {memory}",
+	),
+	(
+		"resource_usage",
+		"пиковая память {memory}, средняя загрузка CPU {cpu}%, время CPU {cpu_time}",
+	),
+	("estimated", "{value} (оценка)"),
+	("used", "Использовано: {usage}"),
+	("current_action", "Текущее действие: {label}{kind} ({usage})"),
+	("several_actions", "Запущено несколько действий"),
+	(
+		"will_notify",
+		"Когда действие завершится, вы получите уведомление",
+	),
+	("no_current_action", "Сейчас нет запущенных действий"),
+	("new_action", "{label}Новое действие: {kind}\nПуть: {path}"),
+	("completed", "{label}{kind}: завершено{path}"),
+	(
+		"overtime",
+		"{label}{kind}{path} выполняется уже {elapsed}, дольше ожидаемых {limit}",
+	),
+	(
+		"stall",
+		"{label}{kind}{path}, похоже, зависло: нет загрузки CPU и ввода-вывода уже {idle}",
+	),
+	("button.keep", "Ждать дальше"),
+	("button.stop", "Остановить"),
+	("not_subscribed", "Вы не подписаны на это действие"),
+	("keep_waiting", "Хорошо, ждём завершения действия"),
+	("already_completed", "Действие уже завершилось"),
+	(
+		"asked_to_stop",
+		"Запрошена остановка процессов действия: {count}",
+	),
+	("agent_not_connected", "Агент {host} не подключён"),
+	("agent_reconnected", "Агент {host} снова подключился"),
+	(
+		"agent_lost",
+		"Потеряна связь с агентом {host}, его действия считаются запущенными, пока он не подключится снова",
+	),
+	("no_history", "Завершённых действий пока нет"),
+	("history", "Недавно завершённые действия:"),
+	("running_actions", "Запущенные действия:"),
+	("host_disconnected", " (нет связи)"),
+	("host_idle", ": нет запущенных действий"),
+	("notifications", "Уведомлений: {count}"),
+	("daily", "Сводка за день: завершено действий: {count}"),
+	("daily_none", "Сводка за день: завершённых действий нет"),
+	("daily_running", ", выполняется сейчас: {count}"),
+	("quiet_digest", "За время тихих часов:"),
+	("quiet_hours", "Тихие часы: {quiet}"),
+	("no_quiet_hours", "Тихие часы не заданы"),
+	("quiet_removed", "Тихие часы отменены"),
+	(
+		"quiet.missing_args",
+		"Нужно указать диапазон времени и часовой пояс",
+	),
+	("quiet.invalid_time", "Неверное время: {value}"),
+	("quiet.invalid_range", "Неверный диапазон времени: {value}"),
+	("quiet.empty", "Тихие часы пусты"),
+	(
+		"quiet.unknown_timezone",
+		"Неизвестный часовой пояс: {value}, используйте названия вроде Europe/Moscow или UTC",
+	),
+	("quiet.expected_kind_mode", "Ожидается kind=mode: {value}"),
+	("quiet.unknown_kind", "Неизвестный вид действия: {value}"),
+	("quiet.unknown_mode", "Неизвестный режим: {value}"),
+	("language", "Язык: {language}"),
+	("language_auto", "Язык: {language}, как в Telegram"),
+	(
+		"unknown_language",
+		"Неизвестный язык: {value}, поддерживаются {languages} и auto",
+	),
+	("config_reloaded", "Конфигурация перечитана:"),
+	("config_unchanged", "Конфигурация перечитана, изменений нет"),
+	(
+		"config_reload_failed",
+		"Не удалось перечитать конфигурацию: {error}\nПрежние настройки сохранены",
+	),
+	("clean.nothing", "Нет сообщений для удаления"),
+	("clean.deleted", "Удалено сообщений: {count}"),
+	(
+		"clean.replaced",
+		", заменено заглушкой слишком старых: {count}",
+	),
+	("clean.failed", ", не удалось удалить: {count}"),
+	("stats", "Отслеживается сообщений: {count}\nС момента запуска:"),
+	("stats.deleted", "удалено: {count}"),
+	("stats.not_found", "уже удалено: {count}"),
+	("stats.replaced", "заменено заглушкой: {count}"),
+	("deleted_placeholder", "[удалено]"),
+	("stats.no_rights", "нет прав на удаление: {count}"),
+	("stats.retried", "будет повторено позже: {count}"),
+	("stats.given_up", "попытки прекращены: {count}"),
+];
+
+#[cfg(test)]
+mod test {
+	use std::collections::BTreeSet;
+
+	use super::*;
+
+	/// The names of the arguments used by the message
+	fn placeholders(text: &str) -> BTreeSet<&str> {
+		text.split('{')
+			.skip(1)
+			.filter_map(|s| s.split_once('}'))
+			.map(|(name, _)| name)
+			.collect()
+	}
+
+	#[test]
+	fn test_catalogs() {
+		let keys = |lang: Lang| -> Vec<&str> { lang.catalog().iter().map(|(k, _)| *k).collect() };
+		let en_keys = keys(Lang::En);
+		assert_eq!(
+			en_keys.iter().collect::<BTreeSet<_>>().len(),
+			en_keys.len(),
+			"Duplicate keys"
+		);
+		for lang in Lang::ALL {
+			let mut lang_keys = keys(lang);
+			lang_keys.sort_unstable();
+			let mut expected = en_keys.clone();
+			expected.sort_unstable();
+			assert_eq!(lang_keys, expected, "{:?}", lang);
+
+			for (key, text) in lang.catalog() {
+				assert!(!text.is_empty(), "{:?} {}", lang, key);
+				assert_eq!(
+					placeholders(text),
+					placeholders(Lang::En.text(key)),
+					"{:?} {}",
+					lang,
+					key
+				);
+			}
+			for kind in ActivityKind::ALL {
+				assert_ne!(lang.kind(&kind), "", "{:?} {:?}", lang, kind);
+			}
+		}
+	}
+
+	#[test]
+	fn test_format() {
+		assert_eq!(
+			Lang::Ru.format("agent_reconnected", &[("host", &"b1")]),
+			"Агент b1 снова подключился"
+		);
+		assert_eq!(
+			Lang::En.format("asked_to_stop", &[("count", &3)]),
+			"Asked 3 processes of the action to stop"
+		);
+		assert_eq!(Lang::Ru.kind(&ActivityKind::Build), "Сборка");
+	}
+
+	#[test]
+	fn test_from_code() {
+		assert_eq!(Lang::from_code("ru"), Some(Lang::Ru));
+		assert_eq!(Lang::from_code("en-US"), Some(Lang::En));
+		assert_eq!(Lang::from_code("RU_ru"), Some(Lang::Ru));
+		assert_eq!(Lang::from_code("de"), None);
+		assert_eq!(Lang::from_code(""), None);
+	}
+}
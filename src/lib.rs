@@ -0,0 +1,20 @@
+pub mod activity;
+pub mod agent;
+pub mod bot;
+pub mod cleaner;
+pub mod cli;
+pub mod cmdline;
+pub mod config;
+pub mod deletion;
+pub mod duration;
+pub mod history;
+pub mod i18n;
+pub mod logging;
+pub mod monitor;
+pub mod msg_storage;
+pub mod notifier;
+pub mod preferences;
+pub mod storage;
+pub mod subscriptions;
+pub mod telegram;
+pub mod watcher;
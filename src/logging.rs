@@ -0,0 +1,202 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogRotation {
+	Never,
+	Hourly,
+	Daily,
+}
+
+impl std::str::FromStr for LogRotation {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"never" => Ok(LogRotation::Never),
+			"hourly" => Ok(LogRotation::Hourly),
+			"daily" => Ok(LogRotation::Daily),
+			_ => Err(()),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+	Stderr,
+	/// Stderr without timestamps and with the syslog priority prefixes, which journald understands
+	Journald,
+	/// Rotating log file. A relative path is resolved against the data directory
+	File {
+		path: PathBuf,
+		rotation: LogRotation,
+	},
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+	/// Filter in the `RUST_LOG` syntax, e.g. "info" or "sbis_build_status=debug"
+	pub level: String,
+	pub output: LogOutput,
+}
+
+impl Default for LogConfig {
+	fn default() -> Self {
+		Self {
+			level: "info".to_owned(),
+			output: LogOutput::Stderr,
+		}
+	}
+}
+
+pub fn is_valid_filter(level: &str) -> bool {
+	EnvFilter::try_new(level).is_ok()
+}
+
+/// Event format for journald: "<6>span{field=1}: message field=2"
+struct JournaldFormat;
+
+fn syslog_priority(level: &Level) -> u8 {
+	match *level {
+		Level::ERROR => 3,
+		Level::WARN => 4,
+		Level::INFO => 6,
+		_ => 7,
+	}
+}
+
+impl<S, N> FormatEvent<S, N> for JournaldFormat
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	N: for<'a> FormatFields<'a> + 'static,
+{
+	fn format_event(
+		&self,
+		ctx: &FmtContext<'_, S, N>,
+		mut writer: Writer<'_>,
+		event: &Event<'_>,
+	) -> std::fmt::Result {
+		write!(writer, "<{}>", syslog_priority(event.metadata().level()))?;
+		if let Some(scope) = ctx.event_scope() {
+			for span in scope.from_root() {
+				write!(writer, "{}", span.name())?;
+				if let Some(fields) = span.extensions().get::<FormattedFields<N>>() {
+					if !fields.is_empty() {
+						write!(writer, "{{{}}}", fields)?;
+					}
+				}
+				write!(writer, ": ")?;
+			}
+		}
+		ctx.field_format().format_fields(writer.by_ref(), event)?;
+		writeln!(writer)
+	}
+}
+
+/// Installs the global logger. The returned guard flushes the log file when dropped, so it must live until the exit
+pub fn init(config: &LogConfig, data_dir: &Path) -> Option<WorkerGuard> {
+	let filter = EnvFilter::try_new(&config.level).unwrap_or_else(|_| EnvFilter::new("info"));
+	let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+	match &config.output {
+		LogOutput::Stderr => {
+			builder.with_writer(std::io::stderr).init();
+			None
+		}
+		LogOutput::Journald => {
+			builder
+				.with_ansi(false)
+				.event_format(JournaldFormat)
+				.with_writer(std::io::stderr)
+				.init();
+			None
+		}
+		LogOutput::File { path, rotation } => {
+			let path = data_dir.join(path);
+			let dir = path.parent().unwrap_or(data_dir);
+			let file_name = path.file_name().unwrap_or("bot.log".as_ref());
+			let appender = match rotation {
+				LogRotation::Never => tracing_appender::rolling::never(dir, file_name),
+				LogRotation::Hourly => tracing_appender::rolling::hourly(dir, file_name),
+				LogRotation::Daily => tracing_appender::rolling::daily(dir, file_name),
+			};
+			let (writer, guard) = tracing_appender::non_blocking(appender);
+			builder.with_ansi(false).with_writer(writer).init();
+			Some(guard)
+		}
+	}
+}
+
+/// Formats the error with all its sources: "error: source: source of source".
+/// Sources already included into the error text are skipped
+pub fn error_chain(e: &dyn std::error::Error) -> String {
+	let mut res = e.to_string();
+	let mut source = e.source();
+	while let Some(e) = source {
+		let text = e.to_string();
+		if !res.contains(&text) {
+			write!(res, ": {}", text).unwrap();
+		}
+		source = e.source();
+	}
+	res
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+
+	#[derive(Clone, Default)]
+	struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+	impl std::io::Write for Buffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_journald_format() {
+		let buffer = Buffer::default();
+		let writer = buffer.clone();
+		let subscriber = tracing_subscriber::fmt()
+			.with_ansi(false)
+			.event_format(JournaldFormat)
+			.with_writer(move || writer.clone())
+			.finish();
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = tracing::info_span!("send", chat_id = 42);
+			let _enter = span.enter();
+			tracing::warn!(error = "timeout", "Cannot send the message");
+			tracing::info!("Sent");
+		});
+
+		let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+		assert_eq!(
+			output,
+			"<4>send{chat_id=42}: Cannot send the message error=\"timeout\"\n<6>send{chat_id=42}: Sent\n"
+		);
+	}
+
+	#[test]
+	fn test_log_config() {
+		assert!(is_valid_filter("info"));
+		assert!(is_valid_filter("sbis_build_status=debug,teloxide=warn"));
+		assert!(!is_valid_filter("sbis_build_status=loud"));
+		assert_eq!("daily".parse(), Ok(LogRotation::Daily));
+		assert!("weekly".parse::<LogRotation>().is_err());
+	}
+}
@@ -1,309 +1,397 @@
 use activity::ProcessDescription;
+use clap::Parser;
 use futures::FutureExt;
 use futures::{pin_mut, select, StreamExt};
-use std::collections::{HashMap, HashSet};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
 use teloxide::requests::Requester;
-use teloxide::types::{ChatId, MediaKind, MessageId, MessageKind, UpdateKind, User, UserId};
-use teloxide::update_listeners::AsUpdateStream;
-
-mod activity;
-mod config;
-mod msg_storage;
-
-#[derive(PartialEq, Eq)]
-enum Request {
-	Help,
-	Subscribe,
-	UnknownRequest(String),
-}
-
-impl From<&str> for Request {
-	fn from(command: &str) -> Request {
-		let mut vs: Vec<_> = command
-			.split_ascii_whitespace()
-			.filter_map(|s| {
-				if s.len() == 0 {
-					None
-				} else {
-					Some(s.to_ascii_lowercase())
-				}
-			})
-			.collect();
-
-		if vs.is_empty() {
-			return Request::UnknownRequest(command.to_string());
-		}
-
-		vs[0] = vs[0].chars().skip_while(|c| *c == '/').collect();
-
-		if vs[0] == "help" {
-			return Request::Help;
-		}
-		if vs[0] == "subscribe" {
-			return Request::Subscribe;
-		}
-		Request::UnknownRequest(command.to_string())
+use teloxide::types::UpdateKind;
+use teloxide::update_listeners::{AsUpdateStream, UpdateListener};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, warn};
+
+use sbis_build_status::agent::{Agent, AgentServer};
+use sbis_build_status::bot::{format_usage, BotData, Links};
+use sbis_build_status::cleaner::Cleaner;
+use sbis_build_status::i18n::Lang;
+use sbis_build_status::monitor::Monitor;
+use sbis_build_status::msg_storage::MessageStorage;
+use sbis_build_status::notifier::{self, Outbox, SharedMessages};
+use sbis_build_status::preferences::Languages;
+use sbis_build_status::{activity, cli, config, logging, storage};
+
+/// SIGHUP forces the configuration reload
+#[cfg(unix)]
+struct ReloadSignal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl ReloadSignal {
+	fn new() -> Self {
+		use tokio::signal::unix::{signal, SignalKind};
+		Self(signal(SignalKind::hangup()).unwrap())
 	}
-}
 
-fn get_string_help() -> String {
-	"This is a simple bot for sbis build/deploy progress notification. List of supported commands:
-	/help: prints help message.
-	/subscribe: sends a notification when the build/deploy process completes.
-	"
-	.to_string()
+	async fn recv(&mut self) {
+		self.0.recv().await;
+	}
 }
 
-type UserActions = HashMap<sysinfo::Pid, (activity::ActivityKind, Option<String>)>;
-type AllActions = HashMap<UserId, UserActions>;
+/// There is no SIGHUP on Windows, so only the config file modifications are watched there
+#[cfg(not(unix))]
+struct ReloadSignal;
 
-struct BotData {
-	owner_id: UserId,
+#[cfg(not(unix))]
+impl ReloadSignal {
+	fn new() -> Self {
+		Self
+	}
 
-	api_new: teloxide::Bot,
-	subscribers: AllActions,
+	async fn recv(&mut self) {
+		std::future::pending::<()>().await
+	}
+}
 
-	msg_storage: msg_storage::MessageStorage<(ChatId, i32)>,
-	auto_subscribe: bool,
+/// SIGINT or SIGTERM stops the bot
+#[cfg(unix)]
+struct ShutdownSignal {
+	sigint: tokio::signal::unix::Signal,
+	sigterm: tokio::signal::unix::Signal,
 }
 
-impl BotData {
-	fn subscribe(&mut self, chat_id: UserId) -> Option<String> {
-		let act_list = activity::get_activity_list();
-		if let Some(elem) = act_list.first() {
-			// There is at least one element
-			let mut msg = if act_list.len() == 1 {
-				format!("Current action: {}", elem.activity_kind())
-			} else {
-				"There are several running actions".to_owned()
-			};
-			msg += "\n";
-			msg += "When action have been completed you will be notified";
-
-			let h: std::collections::HashMap<_, _> = act_list
-				.into_iter()
-				.map(|a| {
-					(
-						*a.pid(),
-						(
-							a.activity_kind().clone(),
-							a.description().map(|x| x.to_owned()),
-						),
-					)
-				})
-				.collect();
-
-			self.subscribers.insert(chat_id, h);
-
-			Some(msg)
-		} else {
-			None
+#[cfg(unix)]
+impl ShutdownSignal {
+	fn new() -> Self {
+		use tokio::signal::unix::{signal, SignalKind};
+		Self {
+			sigint: signal(SignalKind::interrupt()).unwrap(),
+			sigterm: signal(SignalKind::terminate()).unwrap(),
 		}
 	}
 
-	async fn process_message(&mut self, msg: &str, chat: &User) {
-		let request_type = Request::from(msg);
-		let s = match request_type {
-			Request::Help => (chat, get_string_help()),
-
-			Request::Subscribe => {
-				let s = self
-					.subscribe(chat.id)
-					.or(Some("There is no current action".to_owned()))
-					.unwrap();
-				(chat, s)
-			}
+	async fn recv(&mut self) {
+		let sigint = self.sigint.recv();
+		let sigterm = self.sigterm.recv();
+		pin_mut!(sigint, sigterm);
+		futures::future::select(sigint, sigterm).await;
+	}
+}
 
-			Request::UnknownRequest(_) => (
-				chat,
-				format!("Unknown command: {}. \n{}", msg, get_string_help()),
-			),
-		};
-		let u = s.0.id.0 as u64;
-		self.send_message(ChatId(u as i64), s.1).await
+/// Ctrl+C stops the bot
+#[cfg(not(unix))]
+struct ShutdownSignal;
+
+#[cfg(not(unix))]
+impl ShutdownSignal {
+	fn new() -> Self {
+		Self
 	}
 
-	async fn process_check_timer(&mut self) {
-		self.process_auto_subscribe_timer().await;
-
-		let current_actions = activity::get_activity_list();
-		let pid_list_new = current_actions.iter().map(|a| a.pid()).collect();
-
-		let mut msg_list = Vec::new();
-
-		for (chat, actions) in self.subscribers.iter_mut() {
-			let pid_list_old: std::collections::HashSet<_> = actions.keys().collect();
-			assert_ne!(pid_list_old.len(), 0);
-
-			// List of completed actions
-			let completed_list: Vec<_> = pid_list_old
-				.difference(&pid_list_new)
-				.map(|a| **a)
-				.collect();
-			for pid in completed_list {
-				if let Some(act) = actions.get(&pid) {
-					let mut msg = format!("{} completed", act.0);
-					if let Some(s) = &act.1 {
-						msg += ", path = `\"";
-						msg += &s;
-						msg += "\"`";
-					};
-					let chat_id = chat.0;
-					msg_list.push((chat_id, msg));
-				}
-				actions.remove(&pid);
-			}
-		}
-		for (chat, msg) in msg_list {
-			self.send_message(ChatId(chat as i64), msg).await;
-		}
-		self.subscribers.retain(|_, actions| actions.len() != 0);
+	async fn recv(&mut self) {
+		tokio::signal::ctrl_c().await.ok();
 	}
+}
 
-	async fn process_auto_subscribe_timer(&mut self) {
-		if !self.auto_subscribe {
-			return;
+fn check_config(config_path: &std::path::Path) -> ExitCode {
+	match config::read_config(config_path) {
+		Ok(_) => {
+			println!("{}: OK", config_path.display());
+			ExitCode::SUCCESS
 		}
-
-		let current_subscribers = self
-			.subscribers
-			.get(&self.owner_id)
-			.unwrap_or(&HashMap::new())
-			.clone();
-
-		for action in activity::get_activity_list() {
-			if !current_subscribers.contains_key(action.pid()) {
-				self.send_message(
-					ChatId(self.owner_id.0 as i64),
-					format!(
-						r#"New action: {}
-Path: {}"#,
-						action.activity_kind(),
-						action.description().unwrap_or("")
-					),
-				)
-				.await;
-			}
+		Err(e) => {
+			eprintln!("{}: {}", config_path.display(), e);
+			ExitCode::FAILURE
 		}
+	}
+}
 
-		self.subscribe(self.owner_id);
+fn list_activities() -> ExitCode {
+	let mut tracker = activity::ActivityTracker::new();
+	// The CPU usage is measured between two refreshes
+	tracker.refresh();
+	std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+	for action in tracker.refresh() {
+		println!(
+			"{}\t{}\t{}\t{}",
+			action.id(),
+			action.activity_kind(),
+			action.description().unwrap_or(""),
+			format_usage(&action, Lang::En)
+		);
 	}
+	ExitCode::SUCCESS
+}
 
-	async fn delete_old_messages(&mut self) {
-		let old_msg = self
-			.msg_storage
-			.get_old_messages(&std::time::Duration::from_secs(60 * 60 * 24));
-		let mut deleted_msg = HashSet::new();
-		let mut err_messages = HashSet::new();
-		let mut is_first_iter = true;
-		for (chat_id, msg_id) in old_msg.iter() {
-			if !is_first_iter {
-				tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
-			} else {
-				is_first_iter = false;
-			}
-			let res = self
-				.api_new
-				.delete_message(*chat_id, MessageId(*msg_id))
-				.await;
-			if res.is_ok() {
-				deleted_msg.insert((chat_id.clone(), msg_id.clone()));
-			} else {
-				err_messages.insert((chat_id.clone(), msg_id.clone()));
-			}
+/// Reports the activities to the bot until SIGINT/SIGTERM (Ctrl+C on Windows)
+fn run_agent(config_path: &std::path::Path, data_dir: &std::path::Path) -> ExitCode {
+	let config = match config::read_agent_config(config_path) {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("{}: {}", config_path.display(), e);
+			return ExitCode::FAILURE;
 		}
-		if !err_messages.is_empty() {
-			// Get a list of very old messages - older than 10 days
-			let old_msg = self
-				.msg_storage
-				.get_old_messages(&std::time::Duration::from_secs(60 * 60 * 24 * 10));
-			for v in old_msg.iter() {
-				if err_messages.contains(v) {
-					deleted_msg.insert(v.clone());
-				}
-			}
+	};
+	let _log_guard = logging::init(&config.log, data_dir);
+	info!(
+		config = %config_path.display(),
+		server = config.server,
+		host = config.host,
+		"Starting the agent"
+	);
+
+	let runtime = tokio::runtime::Builder::new_multi_thread()
+		.enable_time()
+		.enable_io()
+		.build()
+		.unwrap();
+	runtime.block_on(async {
+		let agent = Agent::start(config, activity::ActivityTracker::new());
+		let mut shutdown_signal = ShutdownSignal::new();
+		let run = agent.run().fuse();
+		let shutdown = shutdown_signal.recv().fuse();
+		pin_mut!(run, shutdown);
+		select! {
+			_ = run => {},
+			_ = shutdown => {},
 		}
-		self.msg_storage
-			.remove_messages(deleted_msg.into_iter().collect());
-	}
+	});
+	info!("The agent has stopped");
+	ExitCode::SUCCESS
+}
 
-	async fn send_message<M: ToString + Send>(&mut self, chat_id: ChatId, s: M) {
-		if let Ok(msg) = self
-			.api_new
-			.send_message(chat_id.clone(), s.to_string())
-			.await
-		{
-			let msg_id = msg.id;
-			self.msg_storage.add_message((chat_id, msg_id.0));
-		}
+fn main() -> ExitCode {
+	let cli = cli::Cli::parse();
+	let config_path = cli.config_path();
+	match cli.command {
+		Some(cli::Command::CheckConfig) => return check_config(&config_path),
+		Some(cli::Command::ListActivities) => return list_activities(),
+		Some(cli::Command::Agent) => return run_agent(&config_path, &cli.data_dir()),
+		None => {}
 	}
-}
 
-fn main() {
-	let runtime = tokio::runtime::Builder::new_current_thread()
+	let config = match config::read_config(&config_path) {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("{}: {}", config_path.display(), e);
+			return ExitCode::FAILURE;
+		}
+	};
+	let data_dir = cli.data_dir();
+	let _log_guard = logging::init(&config.log, &data_dir);
+	info!(
+		config = %config_path.display(),
+		data_dir = %data_dir.display(),
+		owner_id = %config.owner_id,
+		auto_subscribe = config.auto_subscribe,
+		"Starting the bot"
+	);
+
+	let runtime = tokio::runtime::Builder::new_multi_thread()
 		.enable_time()
 		.enable_io()
 		.build()
 		.unwrap();
 
+	let storage = match storage::open(&config.storage, &data_dir) {
+		Ok(storage) => storage,
+		Err(e) => {
+			tracing::error!(error = %e, "Cannot open the storage");
+			return ExitCode::FAILURE;
+		}
+	};
+
+	// A single check doesn't wait for the agents
+	let agent_server = match config.agents.as_ref().filter(|_| !cli.once) {
+		Some(agents_config) => {
+			let (events_tx, events) = mpsc::unbounded_channel();
+			match runtime.block_on(AgentServer::bind(agents_config, events_tx)) {
+				Ok((server, agents)) => {
+					info!(listen = %agents_config.listen, "Waiting for the agents");
+					Some((server, agents, events))
+				}
+				Err(e) => {
+					tracing::error!(listen = %agents_config.listen, error = %e, "Cannot listen for the agents");
+					return ExitCode::FAILURE;
+				}
+			}
+		}
+		None => None,
+	};
+
 	runtime.block_on(async {
-		let config = config::read_config();
-
-		let subscribers = HashMap::new();
-		let api2 = teloxide::Bot::new(config.token);
-
-		let mut api2_updates_stream =
-			teloxide::update_listeners::polling_default(api2.clone()).await;
-		let mut api2_updates_stream_2 = api2_updates_stream.as_stream();
-
-		let mut check_timer = tokio::time::interval(std::time::Duration::from_secs(10));
-		// Clear the chat from old messages every 4 hours
-		let mut delete_msg_timer =
-			tokio::time::interval(std::time::Duration::from_secs(60 * 60 * 4));
-
-		let mut bot_data = BotData {
-			api_new: api2,
-			subscribers,
-			owner_id: config.owner_id,
-			msg_storage: msg_storage::MessageStorage::new(),
-			auto_subscribe: config.auto_subscribe,
+		let api2 = teloxide::Bot::new(config.token.clone());
+		let msg_storage: SharedMessages =
+			Arc::new(Mutex::new(MessageStorage::new(storage.clone())));
+		let (config_tx, config_rx) = watch::channel(config.clone());
+
+		// The tasks communicate over the channels, so the Telegram commands are handled
+		// while the processes are scanned or the old messages are deleted
+		let (outbox, outbox_queue) = Outbox::channel();
+		let notifier = tokio::spawn(notifier::run(
+			api2.clone(),
+			msg_storage.clone(),
+			outbox_queue,
+		));
+
+		let (snapshots_tx, mut snapshots) = mpsc::unbounded_channel();
+		let (monitor, monitor_handle) = Monitor::new(
+			activity::ActivityTracker::new(),
+			config_rx.clone(),
+			snapshots_tx,
+		);
+		let monitor = tokio::spawn(monitor.run());
+
+		let (agents, mut agent_events) = match agent_server {
+			Some((server, agents, events)) => {
+				tokio::spawn(server.run());
+				(Some(agents), Some(events))
+			}
+			None => (None, None),
 		};
 
-		let chat_id_new = ChatId(bot_data.owner_id.0 as i64);
-		bot_data.send_message(chat_id_new, "Bot has started").await;
+		let (languages_tx, languages_rx) = watch::channel(Languages::new());
+		let mut cleaner = Cleaner::new(
+			api2.clone(),
+			msg_storage.clone(),
+			config_rx,
+			languages_rx,
+			outbox.clone(),
+		);
+		let (cleaner_tx, cleaner_requests) = mpsc::unbounded_channel();
+
+		let mut bot_data = BotData::new(
+			config,
+			config::ConfigWatcher::new(config_path),
+			storage,
+			msg_storage.clone(),
+			Links {
+				outbox: outbox.clone(),
+				monitor: monitor_handle,
+				cleaner: cleaner_tx,
+				agents,
+				config: config_tx,
+				languages: languages_tx,
+			},
+		);
+
+		if cli.once {
+			if let Some(snapshot) = snapshots.recv().await {
+				bot_data.process_check(snapshot);
+			}
+			cleaner.delete_old_messages().await;
+			// The notifier sends the queued messages and stops
+			drop((bot_data, cleaner, outbox));
+			notifier.await.ok();
+			monitor.await.ok();
+			msg_storage.lock().unwrap().flush();
+			return;
+		}
+		let cleaner = tokio::spawn(cleaner.run(cleaner_requests));
+
+		let mut updates = teloxide::update_listeners::polling_default(api2.clone()).await;
+		let polling_stop_token = updates.stop_token();
+		let (messages_tx, mut messages) = mpsc::unbounded_channel();
+		let poller = tokio::spawn(async move {
+			let stream = updates.as_stream();
+			pin_mut!(stream);
+			while let Some(update) = stream.next().await {
+				match update {
+					Ok(update) => {
+						if let UpdateKind::CallbackQuery(query) = &update.kind {
+							// Stops the progress indicator on the button, the bot replies with a message
+							if let Err(e) = api2.answer_callback_query(query.id.clone()).await {
+								warn!(error = %logging::error_chain(&e), "Cannot answer the button");
+							}
+						}
+						if let UpdateKind::Message(_) | UpdateKind::CallbackQuery(_) = update.kind {
+							if let Err(e) = messages_tx.send(update.kind) {
+								debug!(update = ?e.0, "Update ignored during the shutdown");
+							}
+						}
+					}
+					Err(e) => warn!(error = %logging::error_chain(&e), "Polling error"),
+				}
+			}
+		});
+
+		let mut reload_signal = ReloadSignal::new();
+		let mut shutdown_signal = ShutdownSignal::new();
+		bot_data.notify_startup();
 
 		loop {
-			let check_tick = check_timer.tick().fuse();
-			let delete_msg_tick = delete_msg_timer.tick().fuse();
-			let msg = api2_updates_stream_2.next().fuse();
+			let msg = messages.recv().fuse();
+			let snapshot = snapshots.recv().fuse();
+			let agent_event = async {
+				match &mut agent_events {
+					Some(events) => events.recv().await,
+					None => std::future::pending().await,
+				}
+			}
+			.fuse();
+			let reload = reload_signal.recv().fuse();
+			let shutdown = shutdown_signal.recv().fuse();
 
-			pin_mut!(check_tick, msg, delete_msg_tick);
+			pin_mut!(msg, snapshot, agent_event, reload, shutdown);
 
 			select! {
-				msg = msg => {
-					if let Some(Ok(msg)) = msg {
-						if let UpdateKind::Message(message) = msg.kind
-						{
-							let chat_id = message.chat.id;
-							let msg_id = message.id.0;
-
-							bot_data.msg_storage.add_message((chat_id, msg_id));
-							if let MessageKind::Common ( msg_common ) = message.kind {
-
-								if let MediaKind::Text(media_text) = msg_common.media_kind{
-									let user = msg_common.from.unwrap();
-									bot_data.process_message(&media_text.text, &user).await
-								}
-							}
+				msg = msg => match msg {
+					Some(UpdateKind::Message(message)) => bot_data.process_update_message(message).await,
+					Some(UpdateKind::CallbackQuery(query)) => {
+						if let Some(data) = &query.data {
+							bot_data.process_button(data, &query.from).await
 						}
 					}
+					Some(_) => {}
+					None => {
+						warn!("The update stream has been closed");
+						break;
+					}
+				},
+
+				snapshot = snapshot => match snapshot {
+					Some(snapshot) => bot_data.process_check(snapshot),
+					None => {
+						warn!("The monitor has stopped");
+						break;
+					}
 				},
 
-				_ = delete_msg_tick =>
-					bot_data.delete_old_messages().await,
+				event = agent_event => match event {
+					Some(event) => bot_data.process_agent_event(event),
+					None => {
+						warn!("The agent server has stopped");
+						break;
+					}
+				},
 
-				_ = check_tick => bot_data.process_check_timer().await,
+				_ = reload => bot_data.reload_config(),
+
+				_ = shutdown => break,
 			}
 		}
+
+		info!("Stopping the bot");
+		polling_stop_token.stop();
+		bot_data.notify_shutdown();
+		let shutdown_timeout = bot_data.config.shutdown_timeout;
+		// The tasks stop when their channels are closed. The cleaner may be in the middle
+		// of a long deletion, the messages it hasn't forgotten are deleted after the restart
+		cleaner.abort();
+		drop((bot_data, outbox, messages));
+		let shutdown = async {
+			// The polling confirms the received updates before the stream ends
+			poller.await.ok();
+			notifier.await.ok();
+			monitor.await.ok();
+		};
+		if tokio::time::timeout(shutdown_timeout, shutdown)
+			.await
+			.is_err()
+		{
+			warn!("The shutdown timed out");
+		}
+		msg_storage.lock().unwrap().flush();
+		info!("The bot has stopped");
 	});
+
+	ExitCode::SUCCESS
 }
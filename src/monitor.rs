@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{pin_mut, select, FutureExt};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{debug, info};
+
+use crate::activity::{ActivityTracker, ProcessDescriptionWithPid, ProcessId};
+use crate::config::{AgentConfig, Config};
+use crate::watcher::{ProcessWatcher, EVENT_DELAY};
+
+/// The settings of the monitor, both the bot and the agent have them
+pub trait ScanSettings: Send + Sync + 'static {
+	fn check_interval(&self) -> Duration;
+	fn watch_processes(&self) -> bool;
+}
+
+impl ScanSettings for Config {
+	fn check_interval(&self) -> Duration {
+		self.check_interval
+	}
+	fn watch_processes(&self) -> bool {
+		self.watch_processes
+	}
+}
+
+impl ScanSettings for AgentConfig {
+	fn check_interval(&self) -> Duration {
+		self.check_interval
+	}
+	fn watch_processes(&self) -> bool {
+		self.watch_processes
+	}
+}
+
+/// The activities found by one scan of the processes
+#[derive(Clone)]
+pub struct Snapshot {
+	/// The scans are numbered in order: a snapshot delivered late is older than the one with a greater number
+	pub seq: u64,
+	pub activities: Arc<[ProcessDescriptionWithPid]>,
+}
+
+/// What the bot asks the monitor for
+enum Request {
+	/// Scan right away
+	Scan(oneshot::Sender<Snapshot>),
+	/// Terminate the processes of the activity
+	Stop(ProcessId, oneshot::Sender<usize>),
+}
+
+/// Talks to the monitor task
+#[derive(Clone)]
+pub struct MonitorHandle {
+	requests: mpsc::UnboundedSender<Request>,
+	subscribed: Arc<watch::Sender<HashSet<ProcessId>>>,
+}
+
+impl MonitorHandle {
+	/// Scans the processes right away. None if the monitor has stopped
+	pub async fn scan(&self) -> Option<Snapshot> {
+		let (tx, rx) = oneshot::channel();
+		self.requests.send(Request::Scan(tx)).ok()?;
+		rx.await.ok()
+	}
+
+	/// Asks the processes of the activity to terminate. Returns how many of them have been asked,
+	/// None if the monitor has stopped
+	pub async fn stop(&self, root: ProcessId) -> Option<usize> {
+		let (tx, rx) = oneshot::channel();
+		self.requests.send(Request::Stop(root, tx)).ok()?;
+		rx.await.ok()
+	}
+
+	/// Sets the subscribed activities, the exits of their processes are watched
+	pub fn watch(&self, roots: HashSet<ProcessId>) {
+		self.subscribed.send_if_modified(|subscribed| {
+			let is_modified = *subscribed != roots;
+			*subscribed = roots;
+			is_modified
+		});
+	}
+}
+
+/// Why the monitor has woken up
+enum Wake {
+	/// Scan and send the snapshot to the bot
+	Check,
+	Request(Request),
+	/// The watched processes or the settings may have changed
+	Update,
+	/// The bot has stopped
+	Stop,
+}
+
+/// Scans the processes by the check timer, the process events and the requests.
+/// The snapshots of the checks go to the bot over the channel
+pub struct Monitor<C> {
+	tracker: Arc<Mutex<ActivityTracker>>,
+	config: watch::Receiver<C>,
+	requests: mpsc::UnboundedReceiver<Request>,
+	subscribed: watch::Receiver<HashSet<ProcessId>>,
+	snapshots: mpsc::UnboundedSender<Snapshot>,
+	seq: u64,
+}
+
+impl<C: ScanSettings> Monitor<C> {
+	pub fn new(
+		tracker: ActivityTracker,
+		config: watch::Receiver<C>,
+		snapshots: mpsc::UnboundedSender<Snapshot>,
+	) -> (Self, MonitorHandle) {
+		let (requests_tx, requests) = mpsc::unbounded_channel();
+		let (subscribed_tx, subscribed) = watch::channel(HashSet::new());
+		let monitor = Self {
+			tracker: Arc::new(Mutex::new(tracker)),
+			config,
+			requests,
+			subscribed,
+			snapshots,
+			seq: 0,
+		};
+		let handle = MonitorHandle {
+			requests: requests_tx,
+			subscribed: Arc::new(subscribed_tx),
+		};
+		(monitor, handle)
+	}
+
+	/// The scan is slow with thousands of processes, it runs in the blocking pool
+	async fn scan(&mut self) -> Snapshot {
+		self.seq += 1;
+		let tracker = self.tracker.clone();
+		let activities = tokio::task::spawn_blocking(move || tracker.lock().unwrap().refresh())
+			.await
+			.unwrap();
+		Snapshot {
+			seq: self.seq,
+			activities: activities.into(),
+		}
+	}
+
+	async fn stop(&self, root: ProcessId) -> usize {
+		let tracker = self.tracker.clone();
+		tokio::task::spawn_blocking(move || tracker.lock().unwrap().stop(&root))
+			.await
+			.unwrap()
+	}
+
+	/// The processes whose exit may complete a subscribed activity
+	fn watched_processes(&self) -> Vec<ProcessId> {
+		let tracker = self.tracker.lock().unwrap();
+		self.subscribed
+			.borrow()
+			.iter()
+			.flat_map(|root| tracker.tree(root))
+			.copied()
+			.collect()
+	}
+
+	/// Stops when the bot drops the handle or the snapshot channel
+	pub async fn run(mut self) {
+		let mut check_timer = tokio::time::interval(self.config.borrow().check_interval());
+		let mut watcher = ProcessWatcher::new(self.config.borrow().watch_processes());
+		// The check caused by the process events
+		let mut event_check: Option<tokio::time::Instant> = None;
+		loop {
+			// The settings may be changed by the config reload
+			let (check_interval, watch_processes) = {
+				let config = self.config.borrow();
+				(config.check_interval(), config.watch_processes())
+			};
+			if check_timer.period() != check_interval {
+				check_timer = tokio::time::interval_at(
+					tokio::time::Instant::now() + check_interval,
+					check_interval,
+				);
+			}
+			if watcher.is_enabled() != watch_processes {
+				watcher = ProcessWatcher::new(watch_processes);
+			}
+			watcher.watch(self.watched_processes());
+
+			let wake = {
+				let check_tick = check_timer.tick().fuse();
+				let request = self.requests.recv().fuse();
+				let process_event = watcher.changed().fuse();
+				let event_check_tick = async move {
+					match event_check {
+						Some(deadline) => tokio::time::sleep_until(deadline).await,
+						None => std::future::pending().await,
+					}
+				}
+				.fuse();
+				let subscribed_changed = self.subscribed.changed().fuse();
+				let config_changed = self.config.changed().fuse();
+				pin_mut!(
+					check_tick,
+					request,
+					process_event,
+					event_check_tick,
+					subscribed_changed,
+					config_changed
+				);
+				select! {
+					_ = check_tick => Wake::Check,
+					_ = event_check_tick => {
+						event_check = None;
+						Wake::Check
+					},
+					_ = process_event => {
+						event_check.get_or_insert(tokio::time::Instant::now() + EVENT_DELAY);
+						Wake::Update
+					},
+					request = request => request.map_or(Wake::Stop, Wake::Request),
+					changed = subscribed_changed => changed.map_or(Wake::Stop, |_| Wake::Update),
+					changed = config_changed => changed.map_or(Wake::Stop, |_| Wake::Update),
+				}
+			};
+
+			match wake {
+				Wake::Check => {
+					let snapshot = self.scan().await;
+					if self.snapshots.send(snapshot).is_err() {
+						break;
+					}
+				}
+				Wake::Request(Request::Scan(reply)) => {
+					let snapshot = self.scan().await;
+					reply.send(snapshot).ok();
+				}
+				Wake::Request(Request::Stop(root, reply)) => {
+					let count = self.stop(root).await;
+					info!(%root, count, "Activity stopped");
+					reply.send(count).ok();
+				}
+				Wake::Update => {}
+				Wake::Stop => break,
+			}
+		}
+		debug!("The monitor has stopped");
+	}
+}
@@ -1,52 +1,222 @@
-use std::io::{Read, Write};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
 use std::str::FromStr;
 
-pub struct MessageStorage<T: Eq + Ord + serde::Serialize + for<'a> serde::Deserialize<'a>> {
-	msg_list: Vec<(T, chrono::DateTime<chrono::Utc>)>,
+use crate::storage::SharedStorage;
+
+/// Key of the message list in the storage
+const STORAGE_KEY: &str = "message";
+
+/// Version of the storage file format. Version 0 is a plain array of messages without the header,
+/// version 1 has no message categories
+const SCHEMA_VERSION: u64 = 2;
+
+/// Kind of the message, it defines how long the message is kept
+#[derive(
+	Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageCategory {
+	/// A message from the user
+	Command,
+	/// Reply to the user command
+	Reply,
+	/// Reply to the /help command
+	Help,
+	/// "Bot has started" and the similar messages
+	Startup,
+	/// Notification about the activities
+	Notification,
+	/// Report about a failure
+	Failure,
+	/// A message pinned in the chat, it is never deleted
+	Pinned,
+}
+
+impl MessageCategory {
+	pub const ALL: [MessageCategory; 7] = [
+		MessageCategory::Command,
+		MessageCategory::Reply,
+		MessageCategory::Help,
+		MessageCategory::Startup,
+		MessageCategory::Notification,
+		MessageCategory::Failure,
+		MessageCategory::Pinned,
+	];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			MessageCategory::Command => "command",
+			MessageCategory::Reply => "reply",
+			MessageCategory::Help => "help",
+			MessageCategory::Startup => "startup",
+			MessageCategory::Notification => "notification",
+			MessageCategory::Failure => "failure",
+			MessageCategory::Pinned => "pinned",
+		}
+	}
+}
+
+impl std::str::FromStr for MessageCategory {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		MessageCategory::ALL
+			.into_iter()
+			.find(|c| c.name() == s)
+			.ok_or(())
+	}
+}
+
+type MessageList<T> = Vec<(T, chrono::DateTime<chrono::Utc>, MessageCategory)>;
+
+#[derive(serde::Serialize)]
+struct StorageFile<'a, T> {
+	version: u64,
+	messages: Vec<(&'a T, String, MessageCategory)>,
+}
+
+/// Requirements for the message identifier kept in the storage
+pub trait MessageKey:
+	Clone + Eq + Ord + Hash + serde::Serialize + for<'a> serde::Deserialize<'a>
+{
+}
+
+impl<T: Clone + Eq + Ord + Hash + serde::Serialize + for<'a> serde::Deserialize<'a>> MessageKey
+	for T
+{
+}
+
+/// The list of sent messages which should be deleted later.
+/// The changes are written to the storage by `flush`, so several changes are saved at once
+pub struct MessageStorage<T: MessageKey> {
+	/// Messages ordered by the sending time, the oldest first
+	by_date: BTreeSet<(chrono::DateTime<chrono::Utc>, T)>,
+	/// Sending time and category of each message, also used to skip duplicates
+	entries: HashMap<T, (chrono::DateTime<chrono::Utc>, MessageCategory)>,
+	storage: Option<SharedStorage>,
+	/// There are changes not written to the storage yet
+	is_dirty: bool,
 }
 
-impl<T: Clone + Eq + Ord + serde::Serialize + for<'a> serde::Deserialize<'a>> MessageStorage<T> {
+impl<T: MessageKey> MessageStorage<T> {
 	fn new_without_file() -> Self {
 		Self {
-			msg_list: Vec::new(),
+			by_date: BTreeSet::new(),
+			entries: HashMap::new(),
+			storage: None,
+			is_dirty: false,
 		}
 	}
 
-	pub fn new() -> Self {
-		match std::fs::File::open(MessageStorage::<T>::get_file_path()) {
-			Ok(mut f) => MessageStorage::new_from_file(&mut f),
-			Err(_) => MessageStorage::new_without_file(),
+	/// Loads the messages from the storage.
+	/// If the data is damaged, the valid entries are recovered from it and from the backup
+	pub fn new(storage: SharedStorage) -> Self {
+		let mut res = MessageStorage::new_without_file();
+		match storage.load(STORAGE_KEY) {
+			Ok(data) => {
+				let parsed = data.as_deref().map(parse_messages::<T>);
+				match parsed {
+					Some(Some((msg_list, true))) => res.insert_all(msg_list),
+					parsed => {
+						if let Some(parsed) = parsed {
+							let recovered =
+								parsed.as_ref().map_or(0, |(msg_list, _)| msg_list.len());
+							tracing::warn!(recovered, "The message storage is damaged");
+							if let Some((msg_list, _)) = parsed {
+								res.insert_all(msg_list);
+							}
+						}
+						// The backup may contain the entries lost in the damaged part of the data
+						let backup = storage
+							.load_backup(STORAGE_KEY)
+							.ok()
+							.flatten()
+							.and_then(|data| parse_messages::<T>(&data));
+						if let Some((backup_list, _)) = backup {
+							res.insert_all(backup_list);
+						}
+					}
+				}
+			}
+			Err(e) => tracing::error!(error = %e, "Cannot read the message storage"),
 		}
+		res.storage = Some(storage);
+		res
 	}
 
+	#[cfg(test)]
 	fn new_from_file(f: &mut std::fs::File) -> Self {
+		use std::io::Read;
 		let mut data = Vec::new();
 		f.read_to_end(&mut data).unwrap();
-		if let Ok(msg_list) = serde_json::from_slice::<Vec<(T, String)>>(&data) {
-			Self {
-				msg_list: msg_list
-					.into_iter()
-					.filter_map(|(id, date_str)| {
-						match chrono::DateTime::<chrono::Utc>::from_str(&date_str) {
-							Ok(date) => Some((id, date)),
-							Err(_) => None,
-						}
-					})
-					.collect(),
-			}
-		} else {
-			Self {
-				msg_list: Vec::new(),
-			}
+		let mut storage = MessageStorage::new_without_file();
+		if let Some((msg_list, _)) = parse_messages(&data) {
+			storage.insert_all(msg_list);
 		}
+		storage
+	}
+
+	/// Adds the message unless it is already stored. Returns true if the message has been added
+	fn insert(
+		&mut self,
+		msg_id: T,
+		date: chrono::DateTime<chrono::Utc>,
+		category: MessageCategory,
+	) -> bool {
+		if self.entries.contains_key(&msg_id) {
+			return false;
+		}
+		self.entries.insert(msg_id.clone(), (date, category));
+		self.by_date.insert((date, msg_id));
+		true
+	}
+
+	fn insert_all(&mut self, msg_list: MessageList<T>) {
+		for (id, date, category) in msg_list {
+			self.insert(id, date, category);
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
 	}
 
 	fn get_old_messages_impl(&self, msg_age: &chrono::Duration) -> Vec<T> {
-		let too_old = chrono::Utc::now() - *msg_age;
-		self.msg_list
+		// The age beyond the date range is older than every message
+		let Some(too_old) = chrono::Utc::now().checked_sub_signed(*msg_age) else {
+			return Vec::new();
+		};
+		self.by_date
+			.iter()
+			.take_while(|(date, _)| *date < too_old)
+			.map(|(_, id)| id.clone())
+			.collect()
+	}
+
+	pub fn get_old_messages(&self, msg_age: &std::time::Duration) -> Vec<T> {
+		match chrono::Duration::from_std(*msg_age) {
+			Ok(msg_age) => self.get_old_messages_impl(&msg_age),
+			Err(_) => Vec::new(),
+		}
+	}
+
+	/// Returns the messages older than their own maximum age. `max_age` returns None for the messages which must be kept forever
+	pub fn get_expired_messages<F: Fn(&T, MessageCategory) -> Option<std::time::Duration>>(
+		&self,
+		max_age: F,
+	) -> Vec<T> {
+		let now = chrono::Utc::now();
+		self.by_date
 			.iter()
-			.filter_map(|(id, date)| {
-				if *date < too_old {
+			.filter_map(|(date, id)| {
+				let category = self.entries[id].1;
+				let age = chrono::Duration::from_std(max_age(id, category)?).ok()?;
+				if *date < now.checked_sub_signed(age)? {
 					Some(id.clone())
 				} else {
 					None
@@ -55,76 +225,176 @@ impl<T: Clone + Eq + Ord + serde::Serialize + for<'a> serde::Deserialize<'a>> Me
 			.collect()
 	}
 
-	pub fn get_old_messages(&self, msg_age: &std::time::Duration) -> Vec<T> {
-		self.get_old_messages_impl(&chrono::Duration::from_std(*msg_age).unwrap())
+	pub fn add_message(&mut self, msg_id: T, category: MessageCategory) {
+		if self.insert(msg_id, chrono::Utc::now(), category) {
+			self.is_dirty = true;
+		}
 	}
 
-	pub fn add_message(&mut self, msg_id: T) {
-		for (id, _) in self.msg_list.iter() {
-			if *id == msg_id {
-				return;
+	pub fn category(&self, msg_id: &T) -> Option<MessageCategory> {
+		self.entries.get(msg_id).map(|(_, category)| *category)
+	}
+
+	/// When the message has been sent
+	pub fn date(&self, msg_id: &T) -> Option<chrono::DateTime<chrono::Utc>> {
+		self.entries.get(msg_id).map(|(date, _)| *date)
+	}
+
+	/// Changes the category of a stored message. Returns false if there is no such message
+	pub fn set_category(&mut self, msg_id: &T, category: MessageCategory) -> bool {
+		match self.entries.get_mut(msg_id) {
+			Some(entry) => {
+				if entry.1 != category {
+					entry.1 = category;
+					self.is_dirty = true;
+				}
+				true
 			}
+			None => false,
 		}
-		self.msg_list.push((msg_id, chrono::Utc::now()));
-		self.write_messages().ok();
 	}
 
 	pub fn remove_messages(&mut self, msg_list: Vec<T>) {
-		let mut msg_list = msg_list;
-		msg_list.sort();
-
-		self.msg_list
-			.retain(|(id, _)| msg_list.binary_search(id).is_err());
+		for id in msg_list {
+			if let Some((date, _)) = self.entries.remove(&id) {
+				self.by_date.remove(&(date, id));
+				self.is_dirty = true;
+			}
+		}
+	}
 
-		self.write_messages().ok();
+	/// Writes the changes to the file if there are any. Errors are logged, the changes are kept to be written later
+	pub fn flush(&mut self) {
+		if !self.is_dirty {
+			return;
+		}
+		match self.write_messages() {
+			Ok(()) => self.is_dirty = false,
+			Err(e) => tracing::error!(error = %e, "Cannot write the message storage"),
+		}
 	}
 
 	fn write_messages(&self) -> Result<(), std::io::Error> {
-		let mut path = std::env::current_exe().unwrap();
-		path.pop();
-		path.push("message.json");
-		let mut f = std::fs::OpenOptions::new()
-			.write(true)
-			.create(true)
-			.truncate(true)
-			.open(MessageStorage::<T>::get_file_path())?;
+		match &self.storage {
+			Some(storage) => storage.save(STORAGE_KEY, &self.serialize()),
+			None => Ok(()),
+		}
+	}
+
+	#[cfg(test)]
+	fn write_messages_to_file(&self, f: &mut std::fs::File) -> Result<(), std::io::Error> {
+		use std::io::Write;
+		f.write_all(&self.serialize())
+	}
+
+	fn serialize(&self) -> Vec<u8> {
+		let file = StorageFile {
+			version: SCHEMA_VERSION,
+			messages: self
+				.by_date
+				.iter()
+				.map(|(date, id)| (id, date.to_string(), self.entries[id].1))
+				.collect(),
+		};
 
-		self.write_messages_to_file(&mut f)
+		serde_json::to_vec(&file).unwrap()
 	}
+}
+
+/// The messages stored before the categories appeared were deleted as replies
+const LEGACY_CATEGORY: MessageCategory = MessageCategory::Reply;
 
-	fn get_file_path() -> std::path::PathBuf {
-		let mut path = std::env::current_exe().unwrap();
-		path.pop();
-		path.push("message.json");
+fn parse_entry<T: for<'a> serde::Deserialize<'a>>(
+	value: serde_json::Value,
+) -> Option<(T, chrono::DateTime<chrono::Utc>, MessageCategory)> {
+	let is_legacy = value.as_array().is_some_and(|a| a.len() == 2);
+	let (id, date_str, category) = if is_legacy {
+		let (id, date_str) = serde_json::from_value::<(T, String)>(value).ok()?;
+		(id, date_str, LEGACY_CATEGORY)
+	} else {
+		serde_json::from_value::<(T, String, MessageCategory)>(value).ok()?
+	};
+	let date = chrono::DateTime::<chrono::Utc>::from_str(&date_str).ok()?;
+	Some((id, date, category))
+}
 
-		path
+/// Reads the array elements one by one until the end of the array or the first broken element
+fn parse_array_prefix(data: &[u8]) -> Vec<serde_json::Value> {
+	let mut res = Vec::new();
+	let mut pos = 0;
+	loop {
+		while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b',') {
+			pos += 1;
+		}
+		if pos >= data.len() || data[pos] == b']' {
+			break;
+		}
+		let mut stream =
+			serde_json::Deserializer::from_slice(&data[pos..]).into_iter::<serde_json::Value>();
+		match stream.next() {
+			Some(Ok(value)) => {
+				res.push(value);
+				pos += stream.byte_offset();
+			}
+			_ => break,
+		}
 	}
+	res
+}
 
-	fn write_messages_to_file(&self, f: &mut std::fs::File) -> Result<(), std::io::Error> {
-		let v: Vec<_> = self
-			.msg_list
-			.iter()
-			.map(|(id, date)| (id, date.to_string()))
-			.collect();
+/// Parses the storage file of any version. The second element of the result is false if the file is damaged
+/// and only a part of the messages has been recovered. Returns None if the file can't be recognized at all
+fn parse_messages<T: for<'a> serde::Deserialize<'a>>(
+	data: &[u8],
+) -> Option<(MessageList<T>, bool)> {
+	let (entries, mut is_valid) = match serde_json::from_slice::<serde_json::Value>(data) {
+		Ok(serde_json::Value::Array(entries)) => (entries, true),
+		Ok(serde_json::Value::Object(mut file)) => {
+			let version = file.get("version").and_then(|v| v.as_u64());
+			match (version, file.remove("messages")) {
+				(Some(v), Some(serde_json::Value::Array(entries))) if v <= SCHEMA_VERSION => {
+					(entries, true)
+				}
+				_ => return None,
+			}
+		}
+		Ok(_) => return None,
+		Err(_) => {
+			// The file is truncated or garbled, take the entries before the damaged place
+			let find = |data: &[u8], pattern: &[u8]| {
+				data.windows(pattern.len()).position(|w| w == pattern)
+			};
+			let start = match find(data, b"\"messages\"") {
+				Some(pos) => pos + find(&data[pos..], b"[")? + 1,
+				None => find(data, b"[")? + 1,
+			};
+			(parse_array_prefix(&data[start..]), false)
+		}
+	};
 
-		let json = serde_json::to_string(&v).unwrap();
-		f.write(json.as_bytes()).map(|_| {})
+	let count = entries.len();
+	let msg_list: Vec<_> = entries.into_iter().filter_map(parse_entry).collect();
+	if msg_list.len() != count {
+		is_valid = false;
 	}
+	Some((msg_list, is_valid))
 }
 
 #[cfg(test)]
 mod test {
 
 	use super::*;
+	use crate::storage::{FileStorage, MemoryStorage};
 	use std::io::{Seek, SeekFrom};
+	use std::sync::Arc;
 
 	#[test]
 	fn test_1() {
 		let mut msg = MessageStorage::new_without_file();
-		msg.add_message(1);
-		msg.add_message(2);
+		msg.add_message(1, MessageCategory::Reply);
+		msg.add_message(2, MessageCategory::Reply);
 		std::thread::sleep(std::time::Duration::from_secs(1));
-		msg.add_message(3);
+		msg.add_message(3, MessageCategory::Reply);
 		assert_eq!(
 			msg.get_old_messages_impl(&chrono::Duration::try_milliseconds(500).unwrap()),
 			[1, 2]
@@ -136,20 +406,49 @@ mod test {
 		);
 	}
 
+	#[test]
+	fn test_duplicates() {
+		let mut msg = MessageStorage::new_without_file();
+		msg.add_message(1, MessageCategory::Reply);
+		msg.is_dirty = false;
+		msg.add_message(1, MessageCategory::Reply);
+		assert!(!msg.is_dirty);
+		assert_eq!(msg.len(), 1);
+
+		msg.remove_messages(vec![2]);
+		assert!(!msg.is_dirty);
+		msg.remove_messages(vec![1, 1]);
+		assert!(msg.is_dirty);
+		assert!(msg.is_empty());
+	}
+
 	#[test]
 	fn test_2() {
 		let mut storage_file = tempfile::tempfile().unwrap();
 		{
 			let cur_dt = chrono::Utc::now();
 
-			let mut new_msg_list = Vec::new();
-			new_msg_list.push((1, cur_dt));
-			new_msg_list.push((2, cur_dt - chrono::Duration::try_days(1).unwrap()));
-			new_msg_list.push((3, cur_dt - chrono::Duration::try_days(2).unwrap()));
-			new_msg_list.push((4, cur_dt - chrono::Duration::try_days(3).unwrap()));
+			let new_msg_list = vec![
+				(1, cur_dt, MessageCategory::Reply),
+				(
+					2,
+					cur_dt - chrono::Duration::try_days(1).unwrap(),
+					MessageCategory::Reply,
+				),
+				(
+					3,
+					cur_dt - chrono::Duration::try_days(2).unwrap(),
+					MessageCategory::Reply,
+				),
+				(
+					4,
+					cur_dt - chrono::Duration::try_days(3).unwrap(),
+					MessageCategory::Reply,
+				),
+			];
 
 			let mut msg = MessageStorage::new_from_file(&mut storage_file);
-			msg.msg_list = new_msg_list;
+			msg.insert_all(new_msg_list);
 			msg.write_messages_to_file(&mut storage_file).unwrap();
 		}
 		storage_file.seek(SeekFrom::Start(0)).unwrap();
@@ -157,10 +456,173 @@ mod test {
 		{
 			let msg = MessageStorage::<i32>::new_from_file(&mut storage_file);
 			let msgs = msg.get_old_messages_impl(&chrono::Duration::try_hours(36).unwrap());
-			assert_eq!(msgs, [3, 4]);
+			assert_eq!(msgs, [4, 3]);
 
 			let msgs = msg.get_old_messages_impl(&chrono::Duration::try_hours(12).unwrap());
-			assert_eq!(msgs, [2, 3, 4]);
+			assert_eq!(msgs, [4, 3, 2]);
+		}
+	}
+
+	#[test]
+	fn test_expired() {
+		let mut msg = MessageStorage::new_without_file();
+		let cur_dt = chrono::Utc::now();
+		msg.insert_all(vec![
+			(
+				1,
+				cur_dt - chrono::Duration::try_hours(1).unwrap(),
+				MessageCategory::Reply,
+			),
+			(
+				2,
+				cur_dt - chrono::Duration::try_hours(3).unwrap(),
+				MessageCategory::Reply,
+			),
+			(
+				11,
+				cur_dt - chrono::Duration::try_hours(1).unwrap(),
+				MessageCategory::Reply,
+			),
+			(
+				12,
+				cur_dt - chrono::Duration::try_days(300).unwrap(),
+				MessageCategory::Reply,
+			),
+		]);
+		let two_hours = std::time::Duration::from_secs(2 * 3600);
+		let expired =
+			msg.get_expired_messages(|id, _| if *id < 10 { Some(two_hours) } else { None });
+		assert_eq!(expired, [2]);
+
+		assert!(msg.set_category(&2, MessageCategory::Pinned));
+		assert!(!msg.set_category(&3, MessageCategory::Pinned));
+		assert_eq!(msg.category(&2), Some(MessageCategory::Pinned));
+		assert_eq!(msg.category(&3), None);
+		assert!(msg.date(&2).unwrap() < cur_dt);
+		let expired = msg.get_expired_messages(|_, category| match category {
+			MessageCategory::Pinned => None,
+			_ => Some(two_hours),
+		});
+		assert_eq!(expired, [12]);
+
+		// The ages beyond the date range don't panic, no message is that old
+		let huge = std::time::Duration::from_secs(100_000_000_000_000);
+		assert!(chrono::Duration::from_std(huge).is_ok());
+		for age in [huge, std::time::Duration::MAX] {
+			assert!(msg.get_old_messages(&age).is_empty());
+			assert!(msg.get_expired_messages(|_, _| Some(age)).is_empty());
 		}
 	}
+
+	#[test]
+	fn test_file_storage() {
+		let data_dir = tempfile::tempdir().unwrap();
+		let storage: SharedStorage = Arc::new(FileStorage::new(data_dir.path().to_path_buf()));
+		{
+			let mut msg = MessageStorage::new(storage.clone());
+			msg.add_message(1, MessageCategory::Reply);
+			msg.add_message(2, MessageCategory::Reply);
+			assert!(!data_dir.path().join("message.json").exists());
+			msg.flush();
+		}
+		assert!(data_dir.path().join("message.json").exists());
+
+		let msg = MessageStorage::<i32>::new(storage);
+		assert_eq!(
+			msg.get_old_messages_impl(&chrono::Duration::try_days(-1).unwrap()),
+			[1, 2]
+		);
+	}
+
+	#[test]
+	fn test_flush() {
+		let storage: SharedStorage = Arc::new(MemoryStorage::default());
+		let mut msg = MessageStorage::new(storage.clone());
+		msg.add_message(1, MessageCategory::Reply);
+		msg.flush();
+		assert!(storage.load_backup(STORAGE_KEY).unwrap().is_none());
+		msg.add_message(2, MessageCategory::Reply);
+		msg.flush();
+
+		let data = storage.load(STORAGE_KEY).unwrap().unwrap();
+		assert!(data.starts_with(b"{\"version\":2,\"messages\":[[1,"));
+		let backup = storage.load_backup(STORAGE_KEY).unwrap().unwrap();
+		let (backup, is_valid) = parse_messages::<i32>(&backup).unwrap();
+		assert!(is_valid);
+		assert_eq!(backup.len(), 1);
+	}
+
+	#[test]
+	fn test_legacy_format() {
+		let data = br#"[[1, "2024-01-01 00:00:00 UTC"], [2, "2024-01-02 00:00:00 UTC"]]"#;
+		let (msg_list, is_valid) = parse_messages::<i32>(data).unwrap();
+		assert!(is_valid);
+		assert_eq!(msg_list.iter().map(|x| x.0).collect::<Vec<_>>(), [1, 2]);
+	}
+
+	#[test]
+	fn test_categories_format() {
+		let data = br#"{"version":2,"messages":[[1,"2024-01-01 00:00:00 UTC","failure"],[2,"2024-01-02 00:00:00 UTC","pinned"],[3,"2024-01-02 00:00:00 UTC","unknown"]]}"#;
+		let (msg_list, is_valid) = parse_messages::<i32>(data).unwrap();
+		assert!(!is_valid);
+		let categories: Vec<_> = msg_list.iter().map(|x| (x.0, x.2)).collect();
+		assert_eq!(
+			categories,
+			[(1, MessageCategory::Failure), (2, MessageCategory::Pinned)]
+		);
+
+		let data = br#"{"version":1,"messages":[[1,"2024-01-01 00:00:00 UTC"]]}"#;
+		let (msg_list, is_valid) = parse_messages::<i32>(data).unwrap();
+		assert!(is_valid);
+		assert_eq!(msg_list[0].2, LEGACY_CATEGORY);
+
+		for category in MessageCategory::ALL {
+			assert_eq!(category.name().parse(), Ok(category));
+		}
+	}
+
+	#[test]
+	fn test_recovery() {
+		let full = br#"{"version":1,"messages":[[1,"2024-01-01 00:00:00 UTC"],[2,"wrong date"],[3,"2024-01-03 00:00:00 UTC"],[4,"2024-01-04 00:00:00 UTC"]]}"#;
+		let (msg_list, is_valid) = parse_messages::<i32>(full).unwrap();
+		assert!(!is_valid);
+		assert_eq!(msg_list.iter().map(|x| x.0).collect::<Vec<_>>(), [1, 3, 4]);
+
+		// Truncated in the middle of the last entry
+		let (msg_list, is_valid) = parse_messages::<i32>(&full[..full.len() - 12]).unwrap();
+		assert!(!is_valid);
+		assert_eq!(msg_list.iter().map(|x| x.0).collect::<Vec<_>>(), [1, 3]);
+
+		// Truncated legacy file
+		let legacy = br#"[[1, "2024-01-01 00:00:00 UTC"], [2, "2024-01-0"#;
+		let (msg_list, _) = parse_messages::<i32>(legacy).unwrap();
+		assert_eq!(msg_list.iter().map(|x| x.0).collect::<Vec<_>>(), [1]);
+
+		assert!(parse_messages::<i32>(b"garbage").is_none());
+		assert!(parse_messages::<i32>(br#"{"version":100,"messages":[]}"#).is_none());
+	}
+
+	#[test]
+	fn test_recovery_from_backup() {
+		let data_dir = tempfile::tempdir().unwrap();
+		let path = data_dir.path().join("message.json");
+		std::fs::write(
+			path.with_extension("json.bak"),
+			br#"{"version":1,"messages":[[1,"2024-01-01 00:00:00 UTC"],[2,"2024-01-02 00:00:00 UTC"]]}"#,
+		)
+		.unwrap();
+		let storage: SharedStorage = Arc::new(FileStorage::new(data_dir.path().to_path_buf()));
+
+		// The crash between the renames leaves only the backup
+		let msg = MessageStorage::<i32>::new(storage.clone());
+		assert_eq!(msg.len(), 2);
+
+		std::fs::write(
+			&path,
+			br#"{"version":1,"messages":[[2,"2024-01-02 00:00:00 UTC"],[3,"2024-01-03 00:"#,
+		)
+		.unwrap();
+		let msg = MessageStorage::<i32>::new(storage);
+		assert_eq!(msg.get_old_messages_impl(&chrono::Duration::zero()), [1, 2]);
+	}
 }
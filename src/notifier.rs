@@ -0,0 +1,105 @@
+use std::sync::{Arc, Mutex};
+
+use teloxide::types::ChatId;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::logging;
+use crate::msg_storage::{MessageCategory, MessageStorage};
+use crate::telegram::{Button, Sender};
+
+/// The messages of the bot and the users to delete later, shared by the tasks
+pub type SharedMessages = Arc<Mutex<MessageStorage<(ChatId, i32)>>>;
+
+/// A message waiting to be sent
+#[derive(Debug)]
+pub struct Outgoing {
+	pub chat_id: ChatId,
+	pub text: String,
+	pub category: MessageCategory,
+	pub buttons: Vec<Button>,
+	/// Sent without the sound
+	pub silent: bool,
+}
+
+/// Queues the messages for the notifier task, so the sender doesn't wait for Telegram
+#[derive(Clone)]
+pub struct Outbox(mpsc::UnboundedSender<Outgoing>);
+
+impl Outbox {
+	pub fn channel() -> (Self, mpsc::UnboundedReceiver<Outgoing>) {
+		let (tx, rx) = mpsc::unbounded_channel();
+		(Self(tx), rx)
+	}
+
+	pub fn send<M: ToString>(&self, chat_id: ChatId, text: M, category: MessageCategory) {
+		self.send_with_buttons(chat_id, text, category, Vec::new());
+	}
+
+	pub fn send_with_buttons<M: ToString>(
+		&self,
+		chat_id: ChatId,
+		text: M,
+		category: MessageCategory,
+		buttons: Vec<Button>,
+	) {
+		self.push(Outgoing {
+			chat_id,
+			text: text.to_string(),
+			category,
+			buttons,
+			silent: false,
+		});
+	}
+
+	/// Sends the message without the sound, used during the quiet hours
+	pub fn send_silently<M: ToString>(
+		&self,
+		chat_id: ChatId,
+		text: M,
+		category: MessageCategory,
+		buttons: Vec<Button>,
+	) {
+		self.push(Outgoing {
+			chat_id,
+			text: text.to_string(),
+			category,
+			buttons,
+			silent: true,
+		});
+	}
+
+	fn push(&self, message: Outgoing) {
+		if let Err(e) = self.0.send(message) {
+			warn!(chat_id = %e.0.chat_id, "The notifier has stopped, the message is lost");
+		}
+	}
+}
+
+/// Sends the queued messages in order and remembers them for the deletion.
+/// Stops when all the outboxes are dropped and the queue is empty
+pub async fn run<S: Sender>(
+	api: S,
+	messages: SharedMessages,
+	mut queue: mpsc::UnboundedReceiver<Outgoing>,
+) {
+	while let Some(message) = queue.recv().await {
+		let chat_id = message.chat_id;
+		match api
+			.send_message(chat_id, message.text, message.buttons, message.silent)
+			.await
+		{
+			Ok(msg_id) => {
+				debug!(%chat_id, msg_id = msg_id.0, "Message sent");
+				messages
+					.lock()
+					.unwrap()
+					.add_message((chat_id, msg_id.0), message.category);
+			}
+			Err(e) => {
+				warn!(%chat_id, error = %logging::error_chain(&e), "Cannot send the message")
+			}
+		}
+	}
+	debug!("The notifier has stopped");
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use teloxide::types::UserId;
+
+use crate::activity::ActivityKind;
+use crate::i18n::Lang;
+use crate::storage::Storage;
+
+/// Key of the user preferences in the storage
+const STORAGE_KEY: &str = "preferences";
+
+/// What happens to the notifications about the activities during the quiet hours
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuietMode {
+	/// Sent without the sound
+	#[default]
+	Silent,
+	/// Held and sent as one message when the quiet hours end
+	Digest,
+	/// Not sent at all
+	Drop,
+}
+
+impl QuietMode {
+	pub const ALL: [QuietMode; 3] = [QuietMode::Silent, QuietMode::Digest, QuietMode::Drop];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			QuietMode::Silent => "silent",
+			QuietMode::Digest => "digest",
+			QuietMode::Drop => "drop",
+		}
+	}
+}
+
+impl std::str::FromStr for QuietMode {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		QuietMode::ALL.into_iter().find(|m| m.name() == s).ok_or(())
+	}
+}
+
+/// Why the arguments of /quiet are rejected, the values are the wrong arguments
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QuietHoursError {
+	MissingArgs,
+	InvalidTime(String),
+	InvalidRange(String),
+	Empty,
+	UnknownTimezone(String),
+	ExpectedKindMode(String),
+	UnknownKind(String),
+	UnknownMode(String),
+}
+
+impl QuietHoursError {
+	/// The message shown to the user
+	pub fn describe(&self, lang: Lang) -> String {
+		let (key, value) = match self {
+			QuietHoursError::MissingArgs => ("quiet.missing_args", ""),
+			QuietHoursError::InvalidTime(value) => ("quiet.invalid_time", value.as_str()),
+			QuietHoursError::InvalidRange(value) => ("quiet.invalid_range", value.as_str()),
+			QuietHoursError::Empty => ("quiet.empty", ""),
+			QuietHoursError::UnknownTimezone(value) => ("quiet.unknown_timezone", value.as_str()),
+			QuietHoursError::ExpectedKindMode(value) => {
+				("quiet.expected_kind_mode", value.as_str())
+			}
+			QuietHoursError::UnknownKind(value) => ("quiet.unknown_kind", value.as_str()),
+			QuietHoursError::UnknownMode(value) => ("quiet.unknown_mode", value.as_str()),
+		};
+		lang.format(key, &[("value", &value)])
+	}
+}
+
+/// The daily time range when the user doesn't want to be disturbed
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct QuietHours {
+	pub start: NaiveTime,
+	/// Excluded. The range spans midnight if the end is before the start
+	pub end: NaiveTime,
+	pub timezone: Tz,
+	/// The kinds without a mode are silent
+	#[serde(default)]
+	pub modes: HashMap<ActivityKind, QuietMode>,
+}
+
+impl QuietHours {
+	/// Parses the arguments of the command: "23:00-08:00 Europe/Moscow [kind=mode ...]"
+	pub fn parse(args: &[String]) -> Result<Self, QuietHoursError> {
+		let [range, timezone, modes @ ..] = args else {
+			return Err(QuietHoursError::MissingArgs);
+		};
+		let parse_time = |s: &str| {
+			NaiveTime::parse_from_str(s, "%H:%M")
+				.map_err(|_| QuietHoursError::InvalidTime(s.to_owned()))
+		};
+		let (start, end) = range
+			.split_once('-')
+			.ok_or_else(|| QuietHoursError::InvalidRange(range.clone()))?;
+		let (start, end) = (parse_time(start)?, parse_time(end)?);
+		if start == end {
+			return Err(QuietHoursError::Empty);
+		}
+		let timezone = timezone
+			.parse()
+			.map_err(|_| QuietHoursError::UnknownTimezone(timezone.clone()))?;
+
+		let mut res = Self {
+			start,
+			end,
+			timezone,
+			modes: HashMap::new(),
+		};
+		for s in modes {
+			let (kind, mode) = s
+				.split_once('=')
+				.ok_or_else(|| QuietHoursError::ExpectedKindMode(s.clone()))?;
+			let kind = kind
+				.to_ascii_lowercase()
+				.parse()
+				.map_err(|_| QuietHoursError::UnknownKind(kind.to_owned()))?;
+			let mode = mode
+				.to_ascii_lowercase()
+				.parse()
+				.map_err(|_| QuietHoursError::UnknownMode(mode.to_owned()))?;
+			res.modes.insert(kind, mode);
+		}
+		Ok(res)
+	}
+
+	pub fn contains(&self, now: DateTime<Utc>) -> bool {
+		let time = now.with_timezone(&self.timezone).time();
+		if self.start < self.end {
+			self.start <= time && time < self.end
+		} else {
+			time >= self.start || time < self.end
+		}
+	}
+
+	pub fn mode(&self, kind: &ActivityKind) -> QuietMode {
+		self.modes.get(kind).copied().unwrap_or_default()
+	}
+}
+
+/// Written the way it is set by the command
+impl std::fmt::Display for QuietHours {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{}-{} {}",
+			self.start.format("%H:%M"),
+			self.end.format("%H:%M"),
+			self.timezone
+		)?;
+		for kind in ActivityKind::ALL {
+			if let Some(mode) = self.modes.get(&kind) {
+				write!(f, " {}={}", kind.name(), mode.name())?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// The settings chosen by the user with the commands
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Preferences {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub quiet_hours: Option<QuietHours>,
+	/// Chosen by /language, wins over the language of Telegram
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub language: Option<Lang>,
+	/// The language of the Telegram client of the user as of the last message
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub telegram_language: Option<Lang>,
+}
+
+impl Preferences {
+	/// English if the user hasn't chosen the language and Telegram has no translated one
+	pub fn lang(&self) -> Lang {
+		self.language.or(self.telegram_language).unwrap_or_default()
+	}
+
+	/// The quiet hours if they are active now
+	pub fn quiet_now(&self, now: DateTime<Utc>) -> Option<&QuietHours> {
+		self.quiet_hours.as_ref().filter(|q| q.contains(now))
+	}
+}
+
+pub type AllPreferences = HashMap<UserId, Preferences>;
+
+/// The languages of the users, shared with the tasks writing to the chats on their own
+pub type Languages = HashMap<UserId, Lang>;
+
+pub fn languages(preferences: &AllPreferences) -> Languages {
+	preferences.iter().map(|(id, p)| (*id, p.lang())).collect()
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredPreferences {
+	user_id: u64,
+	#[serde(flatten)]
+	preferences: Preferences,
+}
+
+pub fn load(storage: &dyn Storage) -> AllPreferences {
+	let data = match storage.load(STORAGE_KEY) {
+		Ok(Some(data)) => data,
+		Ok(None) => return AllPreferences::new(),
+		Err(e) => {
+			tracing::error!(error = %e, "Cannot read the preferences");
+			return AllPreferences::new();
+		}
+	};
+	let stored: Vec<StoredPreferences> = match serde_json::from_slice(&data) {
+		Ok(stored) => stored,
+		Err(e) => {
+			tracing::warn!(error = %e, "The preferences are damaged");
+			return AllPreferences::new();
+		}
+	};
+	stored
+		.into_iter()
+		.map(|s| (UserId(s.user_id), s.preferences))
+		.collect()
+}
+
+/// The users with the default preferences are not written
+pub fn save(storage: &dyn Storage, preferences: &AllPreferences) {
+	let stored: Vec<_> = preferences
+		.iter()
+		.filter(|(_, p)| **p != Preferences::default())
+		.map(|(user_id, p)| StoredPreferences {
+			user_id: user_id.0,
+			preferences: p.clone(),
+		})
+		.collect();
+	let data = serde_json::to_vec(&stored).unwrap();
+	if let Err(e) = storage.save(STORAGE_KEY, &data) {
+		tracing::error!(error = %e, "Cannot write the preferences");
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::storage::MemoryStorage;
+
+	fn args(s: &str) -> Vec<String> {
+		s.split_whitespace().map(|s| s.to_owned()).collect()
+	}
+
+	fn utc(s: &str) -> DateTime<Utc> {
+		DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+	}
+
+	#[test]
+	fn test_quiet_hours() {
+		let quiet = QuietHours::parse(&args(
+			"23:00-08:00 Europe/Moscow update_to_revision=drop Build=Digest",
+		))
+		.unwrap();
+		assert_eq!(
+			quiet.to_string(),
+			"23:00-08:00 Europe/Moscow build=digest update_to_revision=drop"
+		);
+		assert_eq!(quiet.mode(&ActivityKind::Build), QuietMode::Digest);
+		assert_eq!(quiet.mode(&ActivityKind::UpdateToRevision), QuietMode::Drop);
+		assert_eq!(quiet.mode(&ActivityKind::Deploy), QuietMode::Silent);
+
+		// Moscow is UTC+3
+		assert!(quiet.contains(utc("2024-06-01T00:00:00Z")));
+		assert!(quiet.contains(utc("2024-06-01T20:00:00Z")));
+		assert!(quiet.contains(utc("2024-06-01T04:59:00Z")));
+		assert!(!quiet.contains(utc("2024-06-01T05:00:00Z")));
+		assert!(!quiet.contains(utc("2024-06-01T19:59:00Z")));
+
+		let day = QuietHours::parse(&args("12:00-13:30 UTC")).unwrap();
+		assert!(day.contains(utc("2024-06-01T12:00:00Z")));
+		assert!(day.contains(utc("2024-06-01T13:29:00Z")));
+		assert!(!day.contains(utc("2024-06-01T13:30:00Z")));
+		assert!(!day.contains(utc("2024-06-01T11:59:00Z")));
+
+		for (invalid, error) in [
+			("", QuietHoursError::MissingArgs),
+			("23:00-08:00", QuietHoursError::MissingArgs),
+			(
+				"23:00 UTC",
+				QuietHoursError::InvalidRange("23:00".to_owned()),
+			),
+			(
+				"25:00-08:00 UTC",
+				QuietHoursError::InvalidTime("25:00".to_owned()),
+			),
+			("08:00-08:00 UTC", QuietHoursError::Empty),
+			(
+				"23:00-08:00 Mars/Olympus",
+				QuietHoursError::UnknownTimezone("Mars/Olympus".to_owned()),
+			),
+			(
+				"23:00-08:00 UTC build",
+				QuietHoursError::ExpectedKindMode("build".to_owned()),
+			),
+			(
+				"23:00-08:00 UTC compile=drop",
+				QuietHoursError::UnknownKind("compile".to_owned()),
+			),
+			(
+				"23:00-08:00 UTC build=loud",
+				QuietHoursError::UnknownMode("loud".to_owned()),
+			),
+		] {
+			assert_eq!(QuietHours::parse(&args(invalid)), Err(error), "{}", invalid);
+		}
+		assert_eq!(
+			QuietHoursError::UnknownMode("loud".to_owned()).describe(Lang::Ru),
+			"Неизвестный режим: loud"
+		);
+	}
+
+	#[test]
+	fn test_preferences() {
+		let storage = MemoryStorage::default();
+		assert!(load(&storage).is_empty());
+
+		let mut preferences = AllPreferences::new();
+		preferences.insert(
+			UserId(1),
+			Preferences {
+				quiet_hours: Some(QuietHours::parse(&args("22:00-07:00 UTC deploy=drop")).unwrap()),
+				language: None,
+				telegram_language: Some(Lang::Ru),
+			},
+		);
+		preferences.insert(UserId(2), Preferences::default());
+		save(&storage, &preferences);
+
+		preferences.remove(&UserId(2));
+		assert_eq!(load(&storage), preferences);
+	}
+}
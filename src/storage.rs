@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Persistent state of the bot: each key holds one document (JSON in practice).
+/// The previous version of every document is kept as a backup to recover from damaged data
+pub trait Storage: Send + Sync {
+	/// Returns None if nothing has been saved under the key yet
+	fn load(&self, key: &str) -> std::io::Result<Option<Vec<u8>>>;
+	fn load_backup(&self, key: &str) -> std::io::Result<Option<Vec<u8>>>;
+	fn save(&self, key: &str, data: &[u8]) -> std::io::Result<()>;
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum StorageConfig {
+	/// `<key>.json` files in the data directory
+	#[default]
+	File,
+	/// Nothing survives a restart, useful for tests and experiments
+	Memory,
+	/// SQLite database. A relative path is resolved against the data directory
+	Sqlite { path: PathBuf },
+}
+
+pub fn open(config: &StorageConfig, data_dir: &Path) -> std::io::Result<SharedStorage> {
+	match config {
+		StorageConfig::File => Ok(Arc::new(FileStorage::new(data_dir.to_path_buf()))),
+		StorageConfig::Memory => Ok(Arc::new(MemoryStorage::default())),
+		#[cfg(feature = "sqlite")]
+		StorageConfig::Sqlite { path } => Ok(Arc::new(SqliteStorage::open(&data_dir.join(path))?)),
+		#[cfg(not(feature = "sqlite"))]
+		StorageConfig::Sqlite { .. } => Err(std::io::Error::new(
+			std::io::ErrorKind::Unsupported,
+			"the bot is built without the sqlite feature",
+		)),
+	}
+}
+
+/// A document and its previous version
+type Versions = (Vec<u8>, Option<Vec<u8>>);
+
+#[derive(Default)]
+pub struct MemoryStorage {
+	documents: Mutex<HashMap<String, Versions>>,
+}
+
+impl Storage for MemoryStorage {
+	fn load(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+		let documents = self.documents.lock().unwrap();
+		Ok(documents.get(key).map(|(data, _)| data.clone()))
+	}
+
+	fn load_backup(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+		let documents = self.documents.lock().unwrap();
+		Ok(documents.get(key).and_then(|(_, backup)| backup.clone()))
+	}
+
+	fn save(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+		let mut documents = self.documents.lock().unwrap();
+		let backup = documents.remove(key).map(|(data, _)| data);
+		documents.insert(key.to_owned(), (data.to_vec(), backup));
+		Ok(())
+	}
+}
+
+/// The documents are `<key>.json` files, the backups are `<key>.json.bak`
+pub struct FileStorage {
+	dir: PathBuf,
+}
+
+impl FileStorage {
+	pub fn new(dir: PathBuf) -> Self {
+		Self { dir }
+	}
+
+	fn path(&self, key: &str) -> PathBuf {
+		self.dir.join(format!("{}.json", key))
+	}
+}
+
+fn read_if_exists(path: &Path) -> std::io::Result<Option<Vec<u8>>> {
+	match std::fs::read(path) {
+		Ok(data) => Ok(Some(data)),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+	path.with_extension("json.bak")
+}
+
+/// Makes the renames in the directory durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+	match path.parent() {
+		Some(dir) => std::fs::File::open(dir)?.sync_all(),
+		None => Ok(()),
+	}
+}
+
+/// Directories can't be opened as files on Windows, the rename is durable there anyway
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> std::io::Result<()> {
+	Ok(())
+}
+
+impl Storage for FileStorage {
+	fn load(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+		read_if_exists(&self.path(key))
+	}
+
+	fn load_backup(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+		read_if_exists(&backup_path(&self.path(key)))
+	}
+
+	/// The file is never overwritten in place: the data goes to a temporary file which replaces the document.
+	/// The previous version of the document becomes the backup
+	fn save(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+		let path = self.path(key);
+		let tmp_path = path.with_extension("json.tmp");
+		{
+			let mut f = std::fs::File::create(&tmp_path)?;
+			f.write_all(data)?;
+			f.sync_all()?;
+		}
+		if path.exists() {
+			std::fs::rename(&path, backup_path(&path))?;
+		}
+		std::fs::rename(&tmp_path, &path)?;
+		sync_dir(&path)
+	}
+}
+
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+	connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(e: rusqlite::Error) -> std::io::Error {
+	std::io::Error::other(e)
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+	pub fn open(path: &Path) -> std::io::Result<Self> {
+		let connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+		connection
+			.execute_batch(
+				"PRAGMA journal_mode = WAL;
+				CREATE TABLE IF NOT EXISTS documents (
+					key TEXT PRIMARY KEY NOT NULL,
+					data BLOB NOT NULL,
+					backup BLOB
+				);",
+			)
+			.map_err(sqlite_error)?;
+		Ok(Self {
+			connection: Mutex::new(connection),
+		})
+	}
+
+	fn load_column(&self, key: &str, column: &str) -> std::io::Result<Option<Vec<u8>>> {
+		use rusqlite::OptionalExtension;
+		let connection = self.connection.lock().unwrap();
+		connection
+			.query_row(
+				&format!("SELECT {} FROM documents WHERE key = ?1", column),
+				[key],
+				|row| row.get::<_, Option<Vec<u8>>>(0),
+			)
+			.optional()
+			.map(Option::flatten)
+			.map_err(sqlite_error)
+	}
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+	fn load(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+		self.load_column(key, "data")
+	}
+
+	fn load_backup(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+		self.load_column(key, "backup")
+	}
+
+	fn save(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+		let connection = self.connection.lock().unwrap();
+		connection
+			.execute(
+				"INSERT INTO documents (key, data) VALUES (?1, ?2)
+				ON CONFLICT (key) DO UPDATE SET backup = data, data = excluded.data",
+				rusqlite::params![key, data],
+			)
+			.map(|_| ())
+			.map_err(sqlite_error)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn check_storage(storage: &dyn Storage) {
+		assert_eq!(storage.load("a").unwrap(), None);
+		assert_eq!(storage.load_backup("a").unwrap(), None);
+
+		storage.save("a", b"1").unwrap();
+		assert_eq!(storage.load("a").unwrap().unwrap(), b"1");
+		assert_eq!(storage.load_backup("a").unwrap(), None);
+
+		storage.save("a", b"2").unwrap();
+		storage.save("b", b"3").unwrap();
+		assert_eq!(storage.load("a").unwrap().unwrap(), b"2");
+		assert_eq!(storage.load_backup("a").unwrap().unwrap(), b"1");
+		assert_eq!(storage.load("b").unwrap().unwrap(), b"3");
+	}
+
+	#[test]
+	fn test_memory_storage() {
+		check_storage(&MemoryStorage::default());
+	}
+
+	#[test]
+	fn test_file_storage() {
+		let dir = tempfile::tempdir().unwrap();
+		let storage = FileStorage::new(dir.path().to_path_buf());
+		check_storage(&storage);
+		assert!(dir.path().join("a.json").exists());
+		assert!(dir.path().join("a.json.bak").exists());
+		assert!(!dir.path().join("a.json.tmp").exists());
+	}
+
+	#[cfg(feature = "sqlite")]
+	#[test]
+	fn test_sqlite_storage() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.sqlite");
+		check_storage(&SqliteStorage::open(&path).unwrap());
+
+		// The data survives reopening
+		let storage = SqliteStorage::open(&path).unwrap();
+		assert_eq!(storage.load("a").unwrap().unwrap(), b"2");
+	}
+}
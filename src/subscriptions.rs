@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use teloxide::types::UserId;
+
+use crate::activity::{ActivityId, ActivityKind, ProcessId};
+use crate::storage::Storage;
+
+/// Key of the subscriptions in the storage
+const STORAGE_KEY: &str = "subscriptions";
+
+pub type UserActions = HashMap<ActivityId, (ActivityKind, Option<String>)>;
+pub type AllActions = HashMap<UserId, UserActions>;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredSubscription {
+	user_id: u64,
+	pid: u32,
+	start_time: u64,
+	/// The label of the agent, empty for this machine
+	#[serde(default, skip_serializing_if = "String::is_empty")]
+	host: String,
+	kind: ActivityKind,
+	description: Option<String>,
+}
+
+/// Restores the subscriptions saved before the restart
+pub fn load(storage: &dyn Storage) -> AllActions {
+	let data = match storage.load(STORAGE_KEY) {
+		Ok(Some(data)) => data,
+		Ok(None) => return AllActions::new(),
+		Err(e) => {
+			tracing::error!(error = %e, "Cannot read the subscriptions");
+			return AllActions::new();
+		}
+	};
+	let stored: Vec<StoredSubscription> = match serde_json::from_slice(&data) {
+		Ok(stored) => stored,
+		Err(e) => {
+			tracing::warn!(error = %e, "The subscriptions are damaged");
+			return AllActions::new();
+		}
+	};
+
+	let mut res = AllActions::new();
+	for s in stored {
+		res.entry(UserId(s.user_id)).or_default().insert(
+			ActivityId {
+				host: s.host,
+				root: ProcessId {
+					pid: sysinfo::Pid::from_u32(s.pid),
+					start_time: s.start_time,
+				},
+			},
+			(s.kind, s.description),
+		);
+	}
+	res
+}
+
+pub fn save(storage: &dyn Storage, subscribers: &AllActions) {
+	let stored: Vec<_> = subscribers
+		.iter()
+		.flat_map(|(user_id, actions)| {
+			actions
+				.iter()
+				.map(|(id, (kind, description))| StoredSubscription {
+					user_id: user_id.0,
+					pid: id.root.pid.as_u32(),
+					start_time: id.root.start_time,
+					host: id.host.clone(),
+					kind: kind.clone(),
+					description: description.clone(),
+				})
+		})
+		.collect();
+	let data = serde_json::to_vec(&stored).unwrap();
+	if let Err(e) = storage.save(STORAGE_KEY, &data) {
+		tracing::error!(error = %e, "Cannot write the subscriptions");
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::storage::MemoryStorage;
+
+	#[test]
+	fn test_subscriptions() {
+		let storage = MemoryStorage::default();
+		assert!(load(&storage).is_empty());
+
+		let mut subscribers = AllActions::new();
+		subscribers.entry(UserId(1)).or_default().insert(
+			id(100, 1000),
+			(ActivityKind::Build, Some("C:/build".to_owned())),
+		);
+		subscribers
+			.entry(UserId(2))
+			.or_default()
+			.insert(id(200, 2000), (ActivityKind::Deploy, None));
+		subscribers.entry(UserId(2)).or_default().insert(
+			ActivityId {
+				host: "build1".to_owned(),
+				root: pid(200, 2000),
+			},
+			(ActivityKind::Build, None),
+		);
+		save(&storage, &subscribers);
+
+		assert_eq!(load(&storage), subscribers);
+	}
+
+	fn pid(pid: u32, start_time: u64) -> ProcessId {
+		ProcessId {
+			pid: sysinfo::Pid::from_u32(pid),
+			start_time,
+		}
+	}
+
+	fn id(number: u32, start_time: u64) -> ActivityId {
+		ActivityId::local(pid(number, start_time))
+	}
+}
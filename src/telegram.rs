@@ -0,0 +1,81 @@
+use std::future::Future;
+
+use teloxide::payloads::SendMessageSetters;
+use teloxide::requests::Requester;
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MessageId};
+use teloxide::RequestError;
+
+/// A button under the message. Its data comes back to the bot when the button is pressed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Button {
+	pub text: String,
+	pub data: String,
+}
+
+/// The Telegram methods used by the bot. The tests replace them with a fake
+pub trait Sender: Send + Sync {
+	/// Returns the identifier of the sent message. The buttons are shown in one row,
+	/// the silent message comes without the sound
+	fn send_message(
+		&self,
+		chat_id: ChatId,
+		text: String,
+		buttons: Vec<Button>,
+		silent: bool,
+	) -> impl Future<Output = Result<MessageId, RequestError>> + Send;
+
+	fn delete_message(
+		&self,
+		chat_id: ChatId,
+		msg_id: MessageId,
+	) -> impl Future<Output = Result<(), RequestError>> + Send;
+
+	fn edit_message_text(
+		&self,
+		chat_id: ChatId,
+		msg_id: MessageId,
+		text: String,
+	) -> impl Future<Output = Result<(), RequestError>> + Send;
+}
+
+impl Sender for teloxide::Bot {
+	fn send_message(
+		&self,
+		chat_id: ChatId,
+		text: String,
+		buttons: Vec<Button>,
+		silent: bool,
+	) -> impl Future<Output = Result<MessageId, RequestError>> + Send {
+		let mut request = Requester::send_message(self, chat_id, text);
+		if silent {
+			request = request.disable_notification(true);
+		}
+		if !buttons.is_empty() {
+			let row: Vec<_> = buttons
+				.into_iter()
+				.map(|b| InlineKeyboardButton::callback(b.text, b.data))
+				.collect();
+			request = request.reply_markup(InlineKeyboardMarkup::new([row]));
+		}
+		async move { request.await.map(|msg| msg.id) }
+	}
+
+	fn delete_message(
+		&self,
+		chat_id: ChatId,
+		msg_id: MessageId,
+	) -> impl Future<Output = Result<(), RequestError>> + Send {
+		let request = Requester::delete_message(self, chat_id, msg_id);
+		async move { request.await.map(|_| ()) }
+	}
+
+	fn edit_message_text(
+		&self,
+		chat_id: ChatId,
+		msg_id: MessageId,
+		text: String,
+	) -> impl Future<Output = Result<(), RequestError>> + Send {
+		let request = Requester::edit_message_text(self, chat_id, msg_id, text);
+		async move { request.await.map(|_| ()) }
+	}
+}
@@ -0,0 +1,417 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::activity::ProcessId;
+
+/// Pause between a process event and the check, the events of one burst are handled by one check
+pub const EVENT_DELAY: Duration = Duration::from_secs(1);
+
+/// How the process events are received
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchMode {
+	/// The netlink process connector reports every start and exit, it requires CAP_NET_ADMIN
+	Connector,
+	/// A pidfd per watched process reports only the exits of the subscribed activities
+	Pidfd,
+	/// Nothing is watched, the activities are found by the check timer only
+	Polling,
+}
+
+/// Wakes the bot up when the processes change instead of waiting for the next check.
+/// It is available on Linux only, elsewhere the bot relies on the check timer
+pub struct ProcessWatcher {
+	enabled: bool,
+	/// Processes whose exit may complete a subscribed activity
+	watched: HashSet<ProcessId>,
+	#[cfg(target_os = "linux")]
+	source: Option<linux::EventSource>,
+}
+
+impl ProcessWatcher {
+	/// Tries the process connector, then pidfd. Must be called inside the Tokio runtime
+	pub fn new(enabled: bool) -> Self {
+		let mut res = Self {
+			enabled,
+			watched: HashSet::new(),
+			#[cfg(target_os = "linux")]
+			source: None,
+		};
+		#[cfg(target_os = "linux")]
+		if enabled {
+			res.source = linux::EventSource::open();
+		}
+		tracing::info!(mode = ?res.mode(), "Process watcher started");
+		res
+	}
+
+	/// Whether the watcher is allowed by the configuration, it may still fall back to polling
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	pub fn mode(&self) -> WatchMode {
+		#[cfg(target_os = "linux")]
+		match self.source {
+			Some(linux::EventSource::Connector(_)) => return WatchMode::Connector,
+			Some(linux::EventSource::Pidfd { .. }) => return WatchMode::Pidfd,
+			None => {}
+		}
+		WatchMode::Polling
+	}
+
+	/// Replaces the processes whose exits are reported
+	pub fn watch(&mut self, processes: impl IntoIterator<Item = ProcessId>) {
+		self.watched = processes.into_iter().collect();
+		#[cfg(target_os = "linux")]
+		if let Some(source) = &mut self.source {
+			source.watch(&self.watched);
+		}
+	}
+
+	/// Resolves when a process has started an activity or a watched process has exited. Never resolves in the polling mode.
+	/// The future may be dropped at any time, no events are lost then
+	pub async fn changed(&mut self) {
+		#[cfg(target_os = "linux")]
+		if let Some(source) = &mut self.source {
+			let Err(e) = source.changed(&self.watched).await else {
+				return;
+			};
+			let mode = self.mode();
+			tracing::warn!(error = %e, ?mode, "The process events are unavailable");
+			// The connector may be refused after the subscription, pidfd is tried then
+			self.source = match mode {
+				WatchMode::Connector => linux::EventSource::open_pidfd(),
+				_ => None,
+			};
+			if let Some(source) = &mut self.source {
+				source.watch(&self.watched);
+			}
+			tracing::info!(mode = ?self.mode(), "Process watcher restarted");
+			// The events could be lost, a check finds the changes
+			return;
+		}
+		std::future::pending::<()>().await
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+	use std::collections::{HashMap, HashSet};
+	use std::io;
+	use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+	use tokio::io::unix::AsyncFd;
+
+	use crate::activity::{self, ProcessId};
+
+	/// The number of the processes watched by pidfd, the exits of the rest are found by the check timer
+	const MAX_PIDFDS: usize = 256;
+
+	// linux/connector.h and linux/cn_proc.h
+	const CN_IDX_PROC: u32 = 1;
+	const CN_VAL_PROC: u32 = 1;
+	const PROC_CN_MCAST_LISTEN: u32 = 1;
+	const PROC_EVENT_NONE: u32 = 0;
+	const PROC_EVENT_EXEC: u32 = 0x2;
+	const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+	const NLMSG_HDR_LEN: usize = 16;
+	const CN_MSG_LEN: usize = 20;
+	/// The event data follows `what`, `cpu` and `timestamp_ns` of struct proc_event
+	const EVENT_DATA_OFFSET: usize = NLMSG_HDR_LEN + CN_MSG_LEN + 16;
+
+	#[derive(Debug, PartialEq, Eq)]
+	pub(super) enum ProcEvent {
+		/// The reply to the subscription, non-zero is an errno
+		Ack(u32),
+		/// A process has started a new program
+		Exec(u32),
+		/// A process (not a thread) has exited
+		Exit(u32),
+		/// The socket buffer has overflowed
+		Lost,
+	}
+
+	fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+		let bytes = buf.get(offset..offset + 4)?;
+		Some(u32::from_ne_bytes(bytes.try_into().unwrap()))
+	}
+
+	/// Parses the netlink messages received from the process connector, the other events are skipped
+	pub(super) fn parse_events(mut buf: &[u8]) -> Vec<ProcEvent> {
+		let mut res = Vec::new();
+		while let Some(len) = read_u32(buf, 0) {
+			let len = len as usize;
+			if len < NLMSG_HDR_LEN || len > buf.len() {
+				break;
+			}
+			let msg = &buf[..len];
+			let data = |i: usize| read_u32(msg, EVENT_DATA_OFFSET + 4 * i);
+			let event = match read_u32(msg, NLMSG_HDR_LEN + CN_MSG_LEN) {
+				Some(PROC_EVENT_NONE) => data(0).map(ProcEvent::Ack),
+				Some(PROC_EVENT_EXEC) => data(0).map(ProcEvent::Exec),
+				// The exits of the threads are skipped: the PID differs from the thread group ID
+				Some(PROC_EVENT_EXIT) => match (data(0), data(1)) {
+					(Some(pid), Some(tgid)) if pid == tgid => Some(ProcEvent::Exit(pid)),
+					_ => None,
+				},
+				_ => None,
+			};
+			res.extend(event);
+			// The messages are aligned to 4 bytes
+			buf = buf.get((len + 3) & !3..).unwrap_or_default();
+		}
+		res
+	}
+
+	fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+		if res < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(res)
+		}
+	}
+
+	/// Subscribes to the events of the process connector
+	fn open_connector() -> io::Result<AsyncFd<OwnedFd>> {
+		let fd = check(unsafe {
+			libc::socket(
+				libc::AF_NETLINK,
+				libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+				libc::NETLINK_CONNECTOR,
+			)
+		})?;
+		let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+		let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+		addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+		addr.nl_groups = CN_IDX_PROC;
+		check(unsafe {
+			libc::bind(
+				fd.as_raw_fd(),
+				&addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+				std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+			)
+		})?;
+
+		// struct nlmsghdr, struct cn_msg and enum proc_cn_mcast_op
+		let len = NLMSG_HDR_LEN + CN_MSG_LEN + 4;
+		let mut msg = Vec::with_capacity(len);
+		msg.extend((len as u32).to_ne_bytes());
+		msg.extend((libc::NLMSG_DONE as u16).to_ne_bytes());
+		msg.extend(0u16.to_ne_bytes());
+		msg.extend(0u32.to_ne_bytes());
+		msg.extend(std::process::id().to_ne_bytes());
+		msg.extend(CN_IDX_PROC.to_ne_bytes());
+		msg.extend(CN_VAL_PROC.to_ne_bytes());
+		msg.extend([0; 8]);
+		msg.extend(4u16.to_ne_bytes());
+		msg.extend(0u16.to_ne_bytes());
+		msg.extend(PROC_CN_MCAST_LISTEN.to_ne_bytes());
+		let sent =
+			unsafe { libc::send(fd.as_raw_fd(), msg.as_ptr() as *const libc::c_void, len, 0) };
+		if sent < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		AsyncFd::new(fd)
+	}
+
+	fn pidfd_open(pid: u32) -> io::Result<OwnedFd> {
+		let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+	}
+
+	pub(super) enum EventSource {
+		Connector(AsyncFd<OwnedFd>),
+		Pidfd {
+			fds: HashMap<ProcessId, AsyncFd<OwnedFd>>,
+			/// A watched process has exited before its pidfd was opened
+			missed: bool,
+		},
+	}
+
+	impl EventSource {
+		pub fn open() -> Option<Self> {
+			match open_connector() {
+				Ok(socket) => return Some(Self::Connector(socket)),
+				Err(e) => tracing::debug!(error = %e, "The process connector is unavailable"),
+			}
+			Self::open_pidfd()
+		}
+
+		pub fn open_pidfd() -> Option<Self> {
+			// Linux 5.3 is required
+			match pidfd_open(std::process::id()) {
+				Ok(_) => Some(Self::Pidfd {
+					fds: HashMap::new(),
+					missed: false,
+				}),
+				Err(e) => {
+					tracing::debug!(error = %e, "pidfd is unavailable");
+					None
+				}
+			}
+		}
+
+		pub fn watch(&mut self, watched: &HashSet<ProcessId>) {
+			let Self::Pidfd { fds, missed } = self else {
+				return;
+			};
+			fds.retain(|id, _| watched.contains(id));
+			for id in watched {
+				if fds.len() >= MAX_PIDFDS {
+					break;
+				}
+				if fds.contains_key(id) {
+					continue;
+				}
+				match pidfd_open(id.pid.as_u32()).and_then(AsyncFd::new) {
+					Ok(fd) => {
+						fds.insert(*id, fd);
+					}
+					Err(e) if e.raw_os_error() == Some(libc::ESRCH) => *missed = true,
+					Err(e) => tracing::debug!(%id, error = %e, "Cannot watch the process"),
+				}
+			}
+		}
+
+		pub async fn changed(&mut self, watched: &HashSet<ProcessId>) -> io::Result<()> {
+			match self {
+				Self::Connector(socket) => {
+					let watched: HashSet<u32> = watched.iter().map(|id| id.pid.as_u32()).collect();
+					let mut buf = [0u8; 4096];
+					loop {
+						let mut guard = socket.readable().await?;
+						let received = guard.try_io(|socket| {
+							let n = unsafe {
+								libc::recv(
+									socket.as_raw_fd(),
+									buf.as_mut_ptr() as *mut libc::c_void,
+									buf.len(),
+									0,
+								)
+							};
+							if n < 0 {
+								Err(io::Error::last_os_error())
+							} else {
+								Ok(n as usize)
+							}
+						});
+						let events = match received {
+							Ok(Ok(n)) => parse_events(&buf[..n]),
+							Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+								vec![ProcEvent::Lost]
+							}
+							Ok(Err(e)) => return Err(e),
+							Err(_would_block) => continue,
+						};
+						for event in events {
+							match event {
+								ProcEvent::Ack(0) => {}
+								ProcEvent::Ack(errno) => {
+									return Err(io::Error::from_raw_os_error(errno as i32))
+								}
+								ProcEvent::Lost => return Ok(()),
+								// The compilers of a build start thousands of programs, the scan waits for an activity
+								ProcEvent::Exec(pid) if activity::is_activity_process(pid) => {
+									return Ok(())
+								}
+								ProcEvent::Exec(_) => {}
+								ProcEvent::Exit(pid) if watched.contains(&pid) => return Ok(()),
+								ProcEvent::Exit(_) => {}
+							}
+						}
+					}
+				}
+				Self::Pidfd { fds, missed } => {
+					if std::mem::take(missed) {
+						return Ok(());
+					}
+					if fds.is_empty() {
+						std::future::pending::<()>().await;
+					}
+					let exits = fds.iter().map(|(id, fd)| {
+						Box::pin(async move {
+							fd.readable().await?.retain_ready();
+							Ok::<_, io::Error>(*id)
+						})
+					});
+					let (exited, _, _) = futures::future::select_all(exits).await;
+					// The pidfd of an exited process stays readable, it is not needed anymore
+					fds.remove(&exited?);
+					Ok(())
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_parse_events() {
+		use linux::{parse_events, ProcEvent};
+
+		// struct nlmsghdr, struct cn_msg, what, cpu, timestamp_ns and the event data
+		let message = |what: u32, data: &[u32]| {
+			let mut msg = vec![0u8; 52];
+			let len = (msg.len() + 4 * data.len()) as u32;
+			msg[..4].copy_from_slice(&len.to_ne_bytes());
+			msg[36..40].copy_from_slice(&what.to_ne_bytes());
+			for x in data {
+				msg.extend(x.to_ne_bytes());
+			}
+			msg
+		};
+		let mut buf = message(0, &[0]);
+		buf.extend(message(0x2, &[10, 10]));
+		// A thread and a process exit
+		buf.extend(message(0x8000_0000, &[11, 10, 0, 17, 1, 1]));
+		buf.extend(message(0x8000_0000, &[10, 10, 0, 17, 1, 1]));
+		// Fork
+		buf.extend(message(0x1, &[1, 1, 10, 10]));
+		assert_eq!(
+			parse_events(&buf),
+			[ProcEvent::Ack(0), ProcEvent::Exec(10), ProcEvent::Exit(10)]
+		);
+		// A truncated message is skipped
+		assert_eq!(parse_events(&buf[..40]), []);
+	}
+
+	#[test]
+	fn test_watch_exit() {
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+		let _runtime_guard = runtime.enter();
+		let mut watcher = ProcessWatcher::new(true);
+		if watcher.mode() == WatchMode::Polling {
+			return;
+		}
+		#[cfg(target_os = "linux")]
+		{
+			// The connector reports the other processes as well, only the exits are checked here
+			watcher.source = linux::EventSource::open_pidfd();
+		}
+		let mut child = std::process::Command::new("sleep")
+			.arg("0.2")
+			.spawn()
+			.unwrap();
+		watcher.watch([ProcessId {
+			pid: sysinfo::Pid::from_u32(child.id()),
+			start_time: 0,
+		}]);
+		let changed = runtime.block_on(async {
+			tokio::time::timeout(Duration::from_secs(10), watcher.changed()).await
+		});
+		assert!(changed.is_ok());
+		child.wait().unwrap();
+	}
+}
@@ -0,0 +1,932 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use sbis_build_status::activity::{ActivityTracker, ProcessInfo, ScriptedSource};
+use sbis_build_status::agent::{Agent, AgentEvent, AgentServer};
+use sbis_build_status::bot::{BotData, Links};
+use sbis_build_status::cleaner::{Cleaner, CleanerRequest};
+use sbis_build_status::config::{read_config, AgentConfig, Config, ConfigWatcher};
+use sbis_build_status::i18n::Lang;
+use sbis_build_status::logging::LogConfig;
+use sbis_build_status::monitor::{Monitor, MonitorHandle, Snapshot};
+use sbis_build_status::msg_storage::{MessageCategory, MessageStorage};
+use sbis_build_status::notifier::{self, Outbox, Outgoing, SharedMessages};
+use sbis_build_status::preferences::Languages;
+use sbis_build_status::storage::{MemoryStorage, SharedStorage, Storage};
+use sbis_build_status::subscriptions;
+use sbis_build_status::telegram::{Button, Sender};
+use teloxide::types::{ChatId, MessageId, User, UserId};
+use teloxide::{ApiError, RequestError};
+use tokio::sync::{mpsc, watch};
+
+/// Records the sent, deleted and edited messages instead of sending them
+#[derive(Clone, Default)]
+struct FakeSender {
+	sent: Arc<Mutex<Vec<(ChatId, String)>>>,
+	/// Every attempt, including the failed ones
+	deleted: Arc<Mutex<Vec<(ChatId, MessageId)>>>,
+	edited: Arc<Mutex<Vec<(MessageId, String)>>>,
+	/// The errors returned by the next deletions of the message, the rest succeed
+	delete_errors: Arc<Mutex<HashMap<i32, VecDeque<RequestError>>>>,
+	last_id: Arc<AtomicI32>,
+}
+
+impl FakeSender {
+	/// Waits for the messages to be sent
+	async fn wait_sent(&self, count: usize) -> Vec<(ChatId, String)> {
+		for _ in 0..100 {
+			if self.sent.lock().unwrap().len() >= count {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		self.sent.lock().unwrap().clone()
+	}
+}
+
+impl Sender for FakeSender {
+	fn send_message(
+		&self,
+		chat_id: ChatId,
+		text: String,
+		_buttons: Vec<Button>,
+		_silent: bool,
+	) -> impl Future<Output = Result<MessageId, RequestError>> + Send {
+		self.sent.lock().unwrap().push((chat_id, text));
+		let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+		async move { Ok(MessageId(id)) }
+	}
+
+	async fn delete_message(&self, chat_id: ChatId, msg_id: MessageId) -> Result<(), RequestError> {
+		self.deleted.lock().unwrap().push((chat_id, msg_id));
+		let error = self
+			.delete_errors
+			.lock()
+			.unwrap()
+			.get_mut(&msg_id.0)
+			.and_then(|errors| errors.pop_front());
+		error.map_or(Ok(()), Err)
+	}
+
+	async fn edit_message_text(
+		&self,
+		_chat_id: ChatId,
+		msg_id: MessageId,
+		text: String,
+	) -> Result<(), RequestError> {
+		self.edited.lock().unwrap().push((msg_id, text));
+		Ok(())
+	}
+}
+
+const OWNER: UserId = UserId(1);
+
+fn runtime() -> tokio::runtime::Runtime {
+	tokio::runtime::Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.unwrap()
+}
+
+/// The checks are made by the tests, not by the timer
+fn test_config(auto_subscribe: bool, extra: &str) -> (Config, tempfile::NamedTempFile) {
+	let mut config_file = tempfile::NamedTempFile::new().unwrap();
+	write!(
+		config_file,
+		"token = t\nowner_id = {}\nauto_subscribe = {}\ncheck_interval = 1h\nwatch_processes = false\ndelete_pause = 0s\n{}\n",
+		OWNER, auto_subscribe, extra
+	)
+	.unwrap();
+	config_file.flush().unwrap();
+	(read_config(config_file.path()).unwrap(), config_file)
+}
+
+/// The bot with the monitor of the scripted processes. The messages stay in the outbox
+struct TestBot {
+	runtime: tokio::runtime::Runtime,
+	bot: BotData,
+	monitor: MonitorHandle,
+	outbox: mpsc::UnboundedReceiver<Outgoing>,
+	processes: ScriptedSource,
+	/// Where the agents connect, if the config has the [agents] section
+	agents_addr: Option<std::net::SocketAddr>,
+	agent_events: Option<mpsc::UnboundedReceiver<AgentEvent>>,
+	_snapshots: mpsc::UnboundedReceiver<Snapshot>,
+	cleaner: mpsc::UnboundedReceiver<CleanerRequest>,
+	/// The languages published for the cleaner
+	languages: watch::Receiver<Languages>,
+	_config_file: tempfile::NamedTempFile,
+	storage: SharedStorage,
+}
+
+impl TestBot {
+	fn start(auto_subscribe: bool) -> Self {
+		Self::with_config(auto_subscribe, "stall_timeout = never")
+	}
+
+	fn with_config(auto_subscribe: bool, extra: &str) -> Self {
+		let (config, config_file) = test_config(auto_subscribe, extra);
+		let runtime = runtime();
+		let storage: SharedStorage = Arc::new(MemoryStorage::default());
+		let processes = ScriptedSource::default();
+		let (config_tx, config_rx) = watch::channel(config.clone());
+		let (snapshots_tx, snapshots) = mpsc::unbounded_channel();
+		let (monitor, monitor_handle) = Monitor::new(
+			ActivityTracker::with_source(Box::new(processes.clone())),
+			config_rx,
+			snapshots_tx,
+		);
+		runtime.spawn(monitor.run());
+		let (outbox_tx, outbox) = Outbox::channel();
+		let (cleaner_tx, cleaner) = mpsc::unbounded_channel();
+		let (languages_tx, languages) = watch::channel(Languages::new());
+		let (agents, agents_addr, agent_events) = match &config.agents {
+			Some(agents_config) => {
+				let (events_tx, events) = mpsc::unbounded_channel();
+				let (server, agents) = runtime
+					.block_on(AgentServer::bind(agents_config, events_tx))
+					.unwrap();
+				let addr = server.local_addr().unwrap();
+				runtime.spawn(server.run());
+				(Some(agents), Some(addr), Some(events))
+			}
+			None => (None, None, None),
+		};
+		let bot = BotData::new(
+			config,
+			ConfigWatcher::new(config_file.path().to_path_buf()),
+			storage.clone(),
+			Arc::new(Mutex::new(MessageStorage::new(storage.clone()))),
+			Links {
+				outbox: outbox_tx,
+				monitor: monitor_handle.clone(),
+				cleaner: cleaner_tx,
+				agents,
+				config: config_tx,
+				languages: languages_tx,
+			},
+		);
+		Self {
+			runtime,
+			bot,
+			monitor: monitor_handle,
+			outbox,
+			processes,
+			agents_addr,
+			agent_events,
+			_snapshots: snapshots,
+			cleaner,
+			languages,
+			_config_file: config_file,
+			storage,
+		}
+	}
+
+	fn message(&mut self, text: &str, user_id: UserId) {
+		self.message_from(text, &user(user_id));
+	}
+
+	fn message_from(&mut self, text: &str, user: &User) {
+		let chat_id = ChatId(user.id.0 as i64);
+		self.runtime
+			.block_on(self.bot.process_message(text, user, chat_id));
+	}
+
+	fn press(&mut self, data: &str, user_id: UserId) {
+		self.runtime
+			.block_on(self.bot.process_button(data, &user(user_id)));
+	}
+
+	fn check(&mut self) {
+		let snapshot = self.runtime.block_on(self.monitor.scan()).unwrap();
+		self.bot.process_check(snapshot);
+	}
+
+	/// Connects the agent watching the scripted processes
+	fn start_agent(&self, host: &str, processes: &ScriptedSource) -> tokio::task::JoinHandle<()> {
+		let config = AgentConfig {
+			server: self.agents_addr.unwrap().to_string(),
+			token: "secret".to_owned(),
+			host: host.to_owned(),
+			check_interval: Duration::from_millis(100),
+			watch_processes: false,
+			log: LogConfig::default(),
+		};
+		let _guard = self.runtime.enter();
+		let tracker = ActivityTracker::with_source(Box::new(processes.clone()));
+		self.runtime.spawn(Agent::start(config, tracker).run())
+	}
+
+	/// Passes the agent events to the bot up to the expected one
+	fn agent_events(&mut self, until: impl Fn(&AgentEvent) -> bool) {
+		let events = self.agent_events.as_mut().unwrap();
+		loop {
+			let event = self
+				.runtime
+				.block_on(async {
+					tokio::time::timeout(Duration::from_secs(5), events.recv()).await
+				})
+				.unwrap()
+				.unwrap();
+			let is_last = until(&event);
+			self.bot.process_agent_event(event);
+			if is_last {
+				break;
+			}
+		}
+	}
+
+	/// Waits for the report of the agent with the number of the activities
+	fn agent_report(&mut self, host: &str, count: usize) {
+		self.agent_events(|event| {
+			matches!(event, AgentEvent::Report { host: h, activities } if h == host && activities.len() == count)
+		});
+	}
+
+	/// Returns the messages sent since the previous call
+	fn take_outgoing(&mut self) -> Vec<Outgoing> {
+		let mut res = Vec::new();
+		while let Ok(message) = self.outbox.try_recv() {
+			res.push(message);
+		}
+		res
+	}
+
+	fn take(&mut self) -> Vec<(ChatId, String)> {
+		self.take_outgoing()
+			.into_iter()
+			.map(|message| (message.chat_id, message.text))
+			.collect()
+	}
+}
+
+fn process(pid: u32, start_time: u64, name: &str, cmd: &[&str]) -> ProcessInfo {
+	ProcessInfo {
+		pid: sysinfo::Pid::from_u32(pid),
+		start_time,
+		parent: Some(sysinfo::Pid::from_u32(1)),
+		name: name.to_owned(),
+		cmd: cmd.iter().map(|s| s.to_string()).collect(),
+		cwd: None,
+		cpu_usage: 0.0,
+		memory: 0,
+		disk_io: 0,
+		cpu_time: Some(Duration::ZERO),
+	}
+}
+
+fn build(pid: u32, start_time: u64, path: &str) -> ProcessInfo {
+	process(
+		pid,
+		start_time,
+		"qtcreator_ctrlc_stub",
+		&["stub", "--build", path],
+	)
+}
+
+fn user(id: UserId) -> User {
+	User {
+		id,
+		is_bot: false,
+		first_name: "User".to_owned(),
+		last_name: None,
+		username: None,
+		language_code: None,
+		is_premium: false,
+		added_to_attachment_menu: false,
+	}
+}
+
+fn texts(messages: &[(ChatId, String)]) -> Vec<&str> {
+	messages.iter().map(|(_, text)| text.as_str()).collect()
+}
+
+#[test]
+fn test_subscribe_and_complete() {
+	let mut t = TestBot::start(false);
+	let init = process(1, 1, "init", &[]);
+	t.processes
+		.set(vec![init.clone(), build(100, 10, "/work/build")]);
+
+	let subscriber = UserId(2);
+	t.message("/subscribe", subscriber);
+	let sent = t.take();
+	assert_eq!(sent.len(), 1);
+	assert_eq!(sent[0].0, ChatId(2));
+	assert!(
+		sent[0].1.starts_with("Current action: Build"),
+		"{}",
+		sent[0].1
+	);
+
+	// Nothing has changed
+	t.check();
+	assert!(t.take().is_empty());
+
+	t.processes.set(vec![init]);
+	t.check();
+	let sent = t.take();
+	assert_eq!(
+		sent,
+		[(
+			ChatId(2),
+			"Build completed, path = `\"/work/build\"`\nUsed: peak memory 0 MiB, average CPU 0%, CPU time 0s"
+				.to_owned()
+		)]
+	);
+
+	// The subscription is over
+	t.check();
+	assert!(t.take().is_empty());
+
+	t.message("/history", subscriber);
+	let sent = t.take();
+	assert!(
+		sent[0].1.contains("Build, path = `\"/work/build\"`")
+			&& sent[0].1.contains("peak memory 0 MiB"),
+		"{}",
+		sent[0].1
+	);
+}
+
+#[test]
+fn test_nothing_to_subscribe() {
+	let mut t = TestBot::start(false);
+	t.processes.set(vec![process(1, 1, "init", &[])]);
+	t.message("/subscribe", OWNER);
+	assert_eq!(texts(&t.take()), ["There is no current action"]);
+}
+
+#[test]
+fn test_shutdown() {
+	let mut t = TestBot::start(false);
+	t.processes
+		.set(vec![process(1, 1, "init", &[]), build(100, 10, "/a")]);
+	t.message("/subscribe", UserId(2));
+	t.take();
+
+	t.bot.notify_shutdown();
+	let sent = t.take();
+	assert_eq!(sent.len(), 2);
+	assert!(sent.contains(&(
+		ChatId(2),
+		"Bot is stopping, watching the following actions resumes after the restart:\nBuild, path = `\"/a\"`"
+			.to_owned()
+	)));
+	assert!(sent.contains(&(ChatId(1), "Bot is stopping".to_owned())));
+	// Restored by the next start
+	let saved = subscriptions::load(&*t.storage);
+	assert_eq!(saved[&UserId(2)].len(), 1);
+}
+
+#[test]
+fn test_reload_config() {
+	let mut t = TestBot::start(false);
+	// SIGHUP is answered even if nothing has changed
+	t.bot.reload_config();
+	assert_eq!(
+		t.take(),
+		[(ChatId(1), "Configuration reloaded, no changes".to_owned())]
+	);
+}
+
+#[test]
+fn test_clean_age_limit() {
+	let mut t = TestBot::start(false);
+	for age in ["200000000000000000s", "400d"] {
+		t.message(&format!("/clean {}", age), OWNER);
+		let sent = t.take();
+		assert!(sent[0].1.starts_with("Unknown command: "), "{}", sent[0].1);
+	}
+}
+
+#[test]
+fn test_clean_group() {
+	let mut t = TestBot::start(false);
+	let group = ChatId(-100);
+	t.runtime
+		.block_on(t.bot.process_message("/clean 1h", &user(OWNER), group));
+	match t.cleaner.try_recv() {
+		Ok(CleanerRequest::Clean { chat_id, age, .. }) => {
+			assert_eq!(chat_id, group);
+			assert_eq!(age, Some(Duration::from_secs(3600)));
+		}
+		request => panic!("{:?}", request),
+	}
+	assert!(t.take().is_empty());
+}
+
+#[test]
+fn test_auto_subscribe_with_pid_reuse() {
+	let mut t = TestBot::start(true);
+	let init = process(1, 1, "init", &[]);
+	t.processes.set(vec![init.clone(), build(100, 10, "/a")]);
+	t.check();
+	assert_eq!(texts(&t.take()), ["New action: Build\nPath: /a"]);
+
+	// The build has exited and another one has got its PID between the checks
+	t.processes.set(vec![init, build(100, 20, "/b")]);
+	t.check();
+	let sent = t.take();
+	assert!(sent.iter().all(|(chat, _)| *chat == ChatId(1)));
+	assert_eq!(
+		texts(&sent),
+		[
+			"New action: Build\nPath: /b",
+			"Build completed, path = `\"/a\"`\nUsed: peak memory 0 MiB, average CPU 0%, CPU time 0s"
+		]
+	);
+}
+
+#[test]
+fn test_outdated_snapshot() {
+	let mut t = TestBot::start(false);
+	let init = process(1, 1, "init", &[]);
+	t.processes.set(vec![init.clone()]);
+	// Scanned by the timer before the build has started, handled after the subscription
+	let outdated = t.runtime.block_on(t.monitor.scan()).unwrap();
+
+	t.processes.set(vec![init, build(100, 10, "/a")]);
+	t.message("/subscribe", OWNER);
+	t.take();
+	t.bot.process_check(outdated);
+	assert!(t.take().is_empty());
+}
+
+#[test]
+fn test_clean() {
+	let (config, _config_file) = test_config(false, "");
+	let runtime = runtime();
+	let sender = FakeSender::default();
+	let messages: SharedMessages = Arc::new(Mutex::new(MessageStorage::new(Arc::new(
+		MemoryStorage::default(),
+	))));
+	let (outbox, queue) = Outbox::channel();
+	runtime.spawn(notifier::run(sender.clone(), messages.clone(), queue));
+	let (_config_tx, config_rx) = watch::channel(config);
+	let (cleaner_tx, requests) = mpsc::unbounded_channel();
+	let (_languages_tx, languages_rx) = watch::channel(Languages::new());
+	let cleaner = Cleaner::new(
+		sender.clone(),
+		messages.clone(),
+		config_rx,
+		languages_rx,
+		outbox.clone(),
+	);
+	runtime.spawn(cleaner.run(requests));
+
+	runtime.block_on(async {
+		outbox.send(ChatId(5), "a", MessageCategory::Reply);
+		outbox.send(ChatId(5), "b", MessageCategory::Notification);
+		outbox.send(ChatId(6), "c", MessageCategory::Reply);
+		assert_eq!(sender.wait_sent(3).await.len(), 3);
+
+		cleaner_tx
+			.send(CleanerRequest::Clean {
+				chat_id: ChatId(5),
+				age: None,
+				lang: Lang::En,
+			})
+			.unwrap();
+		let sent = sender.wait_sent(4).await;
+		assert_eq!(sent[3], (ChatId(5), "Deleted 2 messages".to_owned()));
+		let mut deleted = sender.deleted.lock().unwrap().clone();
+		deleted.sort_by_key(|(_, id)| id.0);
+		assert_eq!(
+			deleted,
+			[(ChatId(5), MessageId(1)), (ChatId(5), MessageId(2))]
+		);
+
+		cleaner_tx
+			.send(CleanerRequest::Stats {
+				chat_id: ChatId(6),
+				lang: Lang::En,
+			})
+			.unwrap();
+		let sent = sender.wait_sent(5).await;
+		assert_eq!(sent[4].0, ChatId(6));
+		// The message to the chat 6 and the reply to the /clean
+		assert!(
+			sent[4].1.starts_with("Tracked messages: 2\n"),
+			"{}",
+			sent[4].1
+		);
+		assert!(sent[4].1.contains("\ndeleted: 2\n"), "{}", sent[4].1);
+	});
+}
+
+#[test]
+fn test_clean_failures() {
+	let (config, _config_file) = test_config(false, "undeletable_ttl = 4d");
+	let runtime = runtime();
+	let sender = FakeSender::default();
+	// The reply gets an id after the stored messages
+	sender.last_id.store(100, Ordering::Relaxed);
+	let now = chrono::Utc::now();
+	let hour = Duration::from_secs(3600);
+	let ages = [hour * 120, hour * 72, hour * 2, hour, hour / 2];
+	let entries: Vec<_> = (1..)
+		.zip(ages)
+		.map(|(id, age)| ((5, id), (now - age).to_string(), MessageCategory::Reply))
+		.collect();
+	let storage = MemoryStorage::default();
+	let data = serde_json::json!({ "version": 2, "messages": entries });
+	storage
+		.save("message", data.to_string().as_bytes())
+		.unwrap();
+	let messages: SharedMessages = Arc::new(Mutex::new(MessageStorage::new(Arc::new(storage))));
+	{
+		let mut errors = sender.delete_errors.lock().unwrap();
+		let io_error = RequestError::Io(std::io::ErrorKind::ConnectionReset.into());
+		errors.insert(1, VecDeque::from([io_error]));
+		errors.insert(
+			2,
+			VecDeque::from([RequestError::Api(ApiError::MessageCantBeDeleted)]),
+		);
+		let short_wait = RequestError::RetryAfter(Duration::from_millis(100));
+		errors.insert(3, VecDeque::from([short_wait]));
+		errors.insert(4, VecDeque::from([RequestError::RetryAfter(hour)]));
+	}
+	let (outbox, queue) = Outbox::channel();
+	runtime.spawn(notifier::run(sender.clone(), messages.clone(), queue));
+	let (_config_tx, config_rx) = watch::channel(config);
+	let (cleaner_tx, requests) = mpsc::unbounded_channel();
+	// The placeholder is written in the language of the chat, not of the /clean reply
+	let (_languages_tx, languages_rx) = watch::channel(Languages::from([(UserId(5), Lang::Ru)]));
+	let cleaner = Cleaner::new(
+		sender.clone(),
+		messages.clone(),
+		config_rx,
+		languages_rx,
+		outbox,
+	);
+	runtime.spawn(cleaner.run(requests));
+
+	runtime.block_on(async {
+		cleaner_tx
+			.send(CleanerRequest::Clean {
+				chat_id: ChatId(5),
+				age: None,
+				lang: Lang::En,
+			})
+			.unwrap();
+		let sent = sender.wait_sent(1).await;
+		// The message older than undeletable_ttl is given up, the ones after the long flood wait
+		// are left for the next pass
+		assert_eq!(
+			sent[0].1,
+			"Deleted 1 messages, replaced 1 too old ones with a placeholder, failed to delete 3"
+		);
+	});
+	let deleted: Vec<_> = sender
+		.deleted
+		.lock()
+		.unwrap()
+		.iter()
+		.map(|(_, id)| id.0)
+		.collect();
+	// The message is retried after the short flood wait
+	assert_eq!(deleted, [1, 2, 3, 3, 4]);
+	assert_eq!(
+		*sender.edited.lock().unwrap(),
+		[(MessageId(2), "[удалено]".to_owned())]
+	);
+	let mut left = messages.lock().unwrap().get_old_messages(&Duration::ZERO);
+	left.sort();
+	assert_eq!(left, [(ChatId(5), 4), (ChatId(5), 5), (ChatId(5), 101)]);
+}
+
+#[test]
+fn test_stall() {
+	let mut t = TestBot::with_config(false, "stall_timeout = 1s");
+	let init = process(1, 1, "init", &[]);
+	t.processes.set(vec![init.clone(), build(100, 10, "/a")]);
+	t.message("/subscribe", OWNER);
+	t.take();
+	t.check();
+	assert!(t.take().is_empty());
+
+	let wait = || std::thread::sleep(Duration::from_millis(1100));
+	wait();
+	t.check();
+	let sent = t.take_outgoing();
+	assert_eq!(sent.len(), 1);
+	assert!(
+		sent[0]
+			.text
+			.starts_with("Build, path = `\"/a\"` seems to hang: no CPU and I/O for"),
+		"{}",
+		sent[0].text
+	);
+	assert_eq!(sent[0].category, MessageCategory::Failure);
+	let buttons: Vec<_> = sent[0].buttons.iter().map(|b| b.data.as_str()).collect();
+	assert_eq!(buttons, ["keep:100:10", "stop:100:10"]);
+
+	// The alert is not repeated until the owner chooses to keep waiting
+	t.check();
+	assert!(t.take().is_empty());
+	t.press("keep:100:10", OWNER);
+	assert_eq!(texts(&t.take()), ["OK, waiting for the action"]);
+	wait();
+	t.check();
+	assert_eq!(t.take().len(), 1);
+
+	// Only the subscribers may stop the action
+	t.press("stop:100:10", UserId(2));
+	assert_eq!(texts(&t.take()), ["You are not subscribed to this action"]);
+	t.press("stop:100:10", OWNER);
+	assert_eq!(
+		texts(&t.take()),
+		["Asked 1 processes of the action to stop"]
+	);
+	t.check();
+	let sent = t.take();
+	assert!(
+		sent[0]
+			.1
+			.starts_with("Build completed, path = `\"/a\"`\nUsed: peak memory"),
+		"{}",
+		sent[0].1
+	);
+}
+
+#[test]
+fn test_max_duration() {
+	let mut t = TestBot::with_config(
+		false,
+		"stall_timeout = never\n[max_duration]\nbuild = 1s\nrepeat = 1s\npath = 1h /b",
+	);
+	let init = process(1, 1, "init", &[]);
+	t.processes.set(vec![
+		init.clone(),
+		build(100, 10, "/a"),
+		build(200, 20, "/b"),
+	]);
+	t.message("/subscribe", OWNER);
+	t.take();
+	t.check();
+	assert!(t.take().is_empty());
+
+	let wait = || std::thread::sleep(Duration::from_millis(1100));
+	wait();
+	t.check();
+	let sent = t.take_outgoing();
+	assert_eq!(sent.len(), 1);
+	assert!(
+		sent[0].text.starts_with("Build, path = `\"/a\"` runs for")
+			&& sent[0].text.ends_with("longer than the expected 1s"),
+		"{}",
+		sent[0].text
+	);
+	assert_eq!(sent[0].category, MessageCategory::Failure);
+
+	// The warning is repeated after the interval only
+	t.check();
+	assert!(t.take().is_empty());
+	wait();
+	t.check();
+	assert_eq!(t.take().len(), 1);
+}
+
+#[test]
+fn test_agents() {
+	let mut t = TestBot::with_config(
+		true,
+		"stall_timeout = never\n[agents]\nlisten = 127.0.0.1:0\ntoken = secret\nhost = main",
+	);
+	let init = process(1, 1, "init", &[]);
+	t.processes.set(vec![init.clone(), build(100, 10, "/a")]);
+	t.check();
+	assert_eq!(texts(&t.take()), ["[main] New action: Build\nPath: /a"]);
+
+	// The same PID on another machine is another activity
+	let b1 = ScriptedSource::default();
+	b1.set(vec![init.clone(), build(100, 10, "/b1")]);
+	t.start_agent("b1", &b1);
+	t.agent_report("b1", 1);
+	assert_eq!(texts(&t.take()), ["[b1] New action: Build\nPath: /b1"]);
+
+	let b2 = ScriptedSource::default();
+	b2.set(vec![init.clone(), build(200, 20, "/b2")]);
+	let b2_agent = t.start_agent("b2", &b2);
+	t.agent_report("b2", 1);
+	assert_eq!(texts(&t.take()), ["[b2] New action: Build\nPath: /b2"]);
+
+	t.message("/status", OWNER);
+	let sent = t.take();
+	let status: Vec<_> = sent[0].1.lines().collect();
+	assert_eq!(status.len(), 6, "{}", sent[0].1);
+	assert_eq!(status[0], "main:");
+	assert!(status[1].starts_with("  Build, path = `\"/a\"`: 0 child processes"));
+	assert_eq!(status[2], "b1:");
+	assert!(status[3].starts_with("  Build, path = `\"/b1\"`"));
+	assert_eq!(status[4], "b2:");
+
+	// The activity is stopped by its agent
+	t.press("stop:100:10:b1", OWNER);
+	assert_eq!(
+		texts(&t.take()),
+		["Asked 1 processes of the action to stop"]
+	);
+	t.agent_report("b1", 0);
+	let sent = t.take();
+	assert_eq!(sent.len(), 1);
+	assert!(
+		sent[0]
+			.1
+			.starts_with("[b1] Build completed, path = `\"/b1\"`\nUsed:"),
+		"{}",
+		sent[0].1
+	);
+
+	// The activities of a lost agent are not completed
+	b2_agent.abort();
+	t.agent_events(|event| matches!(event, AgentEvent::Disconnected { host } if host == "b2"));
+	let sent = t.take();
+	assert_eq!(sent.len(), 1);
+	assert!(sent[0].1.starts_with("Lost the connection to the agent b2"));
+	t.check();
+	assert!(t.take().is_empty());
+	t.message("/status", OWNER);
+	assert!(t.take()[0].1.contains("\nb2 (disconnected):\n  Build"));
+
+	t.start_agent("b2", &b2);
+	t.agent_report("b2", 1);
+	assert_eq!(texts(&t.take()), ["The agent b2 has reconnected"]);
+}
+
+#[test]
+fn test_quiet_hours() {
+	let mut t = TestBot::start(false);
+	let init = process(1, 1, "init", &[]);
+	t.processes.set(vec![init.clone(), build(100, 10, "/a")]);
+
+	// The quiet hours are now
+	let now = chrono::Utc::now();
+	let range = format!(
+		"{}-{}",
+		(now - chrono::Duration::hours(1)).format("%H:%M"),
+		(now + chrono::Duration::hours(1)).format("%H:%M")
+	);
+	let (silent, digest, dropped) = (UserId(2), UserId(3), UserId(4));
+	for (user_id, modes) in [
+		(silent, ""),
+		(digest, " build=digest"),
+		(dropped, " Build=DROP deploy=digest"),
+	] {
+		t.message(&format!("/quiet {} UTC{}", range, modes), user_id);
+		t.message("/subscribe", user_id);
+	}
+	let sent = t.take();
+	assert_eq!(sent.len(), 6);
+	assert_eq!(sent[0].1, format!("Quiet hours: {} UTC", range));
+	assert_eq!(
+		sent[4].1,
+		format!("Quiet hours: {} UTC build=drop deploy=digest", range)
+	);
+
+	t.processes.set(vec![init]);
+	t.check();
+	let sent = t.take_outgoing();
+	assert_eq!(sent.len(), 1);
+	assert_eq!(sent[0].chat_id, ChatId(2));
+	assert!(sent[0].text.starts_with("Build completed"));
+	assert!(sent[0].silent);
+
+	// The digest is sent when the quiet hours end
+	t.message("/quiet", digest);
+	assert_eq!(
+		texts(&t.take()),
+		[format!("Quiet hours: {} UTC build=digest", range)]
+	);
+	t.message("/quiet off", digest);
+	let sent = t.take_outgoing();
+	assert_eq!(sent.len(), 2);
+	assert_eq!(sent[0].text, "The quiet hours are removed");
+	assert!(
+		sent[1]
+			.text
+			.starts_with("During the quiet hours:\n\nBuild completed, path = `\"/a\"`"),
+		"{}",
+		sent[1].text
+	);
+	assert!(!sent[1].silent);
+	t.message("/quiet", digest);
+	assert_eq!(texts(&t.take()), ["No quiet hours are set"]);
+
+	t.message("/quiet 23:00-08:00 Mars/Olympus", dropped);
+	assert!(t.take()[0].1.starts_with("Unknown timezone: Mars/Olympus"));
+	t.check();
+	assert!(t.take().is_empty());
+}
+
+#[test]
+fn test_digest_window() {
+	let mut t = TestBot::with_config(false, "stall_timeout = 1s\n[digest]\nwindow = 0s");
+	let init = process(1, 1, "init", &[]);
+	let stands = [
+		build(100, 10, "/a"),
+		build(200, 20, "/b"),
+		build(300, 30, "/c"),
+	];
+	let mut processes = vec![init.clone()];
+	processes.extend(stands.iter().cloned());
+	t.processes.set(processes);
+	t.message("/subscribe", UserId(2));
+	t.take();
+
+	// The completions found by one check come in one message
+	t.processes.set(vec![init.clone(), stands[2].clone()]);
+	t.check();
+	let sent = t.take();
+	assert_eq!(sent.len(), 1);
+	let parts: Vec<_> = sent[0].1.split("\n\n").collect();
+	assert_eq!(parts.len(), 3, "{}", sent[0].1);
+	assert_eq!(parts[0], "2 notifications:");
+	assert!(parts[1..]
+		.iter()
+		.any(|p| p.starts_with("Build completed, path = `\"/a\"`")));
+	assert!(parts[1..]
+		.iter()
+		.any(|p| p.starts_with("Build completed, path = `\"/b\"`")));
+
+	// The alerts with the buttons are sent apart
+	std::thread::sleep(Duration::from_millis(1100));
+	t.check();
+	let sent = t.take_outgoing();
+	assert_eq!(sent.len(), 1);
+	assert!(sent[0].text.contains("seems to hang"));
+	assert_eq!(sent[0].buttons.len(), 2);
+
+	// A single notification has no header
+	t.processes.set(vec![init]);
+	t.check();
+	let sent = t.take();
+	assert_eq!(sent.len(), 1);
+	assert!(sent[0].1.starts_with("Build completed, path = `\"/c\"`"));
+}
+
+#[test]
+fn test_language() {
+	let mut t = TestBot::start(false);
+	let init = process(1, 1, "init", &[]);
+	t.processes.set(vec![init.clone(), build(100, 10, "/a")]);
+
+	// The language of Telegram is used by default
+	let russian = User {
+		language_code: Some("ru-RU".to_owned()),
+		..user(UserId(2))
+	};
+	t.message_from("/subscribe", &russian);
+	t.message_from("/foo", &russian);
+	let sent = t.take();
+	assert!(
+		sent[0].1.starts_with("Текущее действие: Сборка ("),
+		"{}",
+		sent[0].1
+	);
+	assert!(sent[1].1.starts_with("Неизвестная команда: /foo. \n"));
+	t.message_from("/language", &russian);
+	assert_eq!(texts(&t.take()), ["Язык: русский, как в Telegram"]);
+	assert_eq!(t.languages.borrow().get(&UserId(2)), Some(&Lang::Ru));
+
+	t.processes.set(vec![init]);
+	t.check();
+	assert_eq!(
+		t.take(),
+		[(ChatId(2), "Сборка: завершено, путь = `\"/a\"`".to_owned())]
+	);
+
+	// The chosen language wins over Telegram
+	t.message_from("/language EN", &russian);
+	t.message_from("/subscribe", &russian);
+	assert_eq!(
+		texts(&t.take()),
+		["Language: English", "There is no current action"]
+	);
+	t.message_from("/language de", &russian);
+	assert_eq!(
+		texts(&t.take()),
+		["Unknown language: de, the supported ones are en, ru and auto"]
+	);
+	t.message_from("/language auto", &russian);
+	assert_eq!(texts(&t.take()), ["Язык: русский, как в Telegram"]);
+
+	// Telegram switched to a language without the translation
+	let german = User {
+		language_code: Some("de".to_owned()),
+		..user(UserId(2))
+	};
+	t.message_from("/subscribe", &german);
+	assert_eq!(texts(&t.take()), ["There is no current action"]);
+	t.message("/language ru", OWNER);
+	t.message("/status", OWNER);
+	assert_eq!(
+		texts(&t.take()),
+		["Язык: русский", "Сейчас нет запущенных действий"]
+	);
+}